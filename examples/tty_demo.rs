@@ -1,6 +1,6 @@
+use pachyterm::expect::{Expect, Pattern};
 use pachyterm::tty::{TtyEngine, PtyConfig, TerminalMode};
 use std::time::Duration;
-use tokio::time::sleep;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -11,10 +11,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Demo 1: Basic PTY creation and management
     println!("1. Creating PTY session...");
-    let mut config = PtyConfig::default();
-    config.shell = "/bin/bash".to_string();
-    config.rows = 24;
-    config.cols = 80;
+    let config = PtyConfig {
+        shell: "/bin/bash".to_string(),
+        rows: 24,
+        cols: 80,
+        ..PtyConfig::default()
+    };
     
     let pty_id = engine.create_pty(config).await?;
     println!("   PTY {} created successfully", pty_id);
@@ -22,26 +24,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Demo 2: Terminal I/O
     println!("\n2. Testing terminal I/O...");
-    let command = b"echo 'Hello from Pachyterm TTY Engine!'\n";
-    let bytes_written = engine.write_to_pty(pty_id, command).await?;
-    println!("   Wrote {} bytes to PTY", bytes_written);
-    
-    // Give the command time to execute
-    sleep(Duration::from_millis(100)).await;
-    
-    // Read the output
-    let mut buffer = [0u8; 1024];
-    match engine.read_from_pty(pty_id, &mut buffer).await {
-        Ok(bytes_read) => {
-            if bytes_read > 0 {
-                let output = String::from_utf8_lossy(&buffer[..bytes_read]);
-                println!("   Read {} bytes: {}", bytes_read, output.trim());
-            } else {
-                println!("   No output available yet");
-            }
-        }
-        Err(e) => println!("   Read error: {}", e),
-    }
+    let mut session = Expect::new(&engine, pty_id)?;
+    session.send_line("echo \"Hello from $(echo Pachyterm) TTY Engine!\"").await?;
+    println!("   Sent a command");
+
+    // Wait for the output rather than sleeping and hoping it arrived
+    let found = session.expect(Pattern::regex(r"Hello from (\w+) TTY Engine!")?, Duration::from_secs(5)).await?;
+    println!("   Read: {} (captured {:?})", found.text(), found.get(1).unwrap_or_default());
     
     // Demo 3: Terminal modes
     println!("\n3. Testing terminal modes...");
@@ -0,0 +1,128 @@
+use pachyterm::capture::{self, CaptureFormat, CaptureOptions};
+use pachyterm::tty::{TtyEngine, TtyError};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "\
+Usage: pachyterm run [OPTIONS] [--] COMMAND [ARGS...]
+
+Runs COMMAND in a terminal session, passing stdin through, and writes its
+output once it exits. Exits with the command's exit code.
+
+Options:
+  --rows N          Terminal height (default 24)
+  --cols N          Terminal width (default 80)
+  --format FORMAT   screen: text of the final screen (default)
+                    transcript: everything written, escape sequences included
+                    plain: the transcript with escape sequences stripped
+  -o, --output FILE Write the output to FILE instead of stdout
+  --no-stdin        Don't pass stdin to the command
+  -h, --help        Show this help";
+
+struct RunArgs {
+    options: CaptureOptions,
+    output: Option<PathBuf>,
+    forward_stdin: bool,
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+/// Parses the arguments after `run`; `None` asks for help
+fn parse_run_args(mut args: impl Iterator<Item = String>) -> Result<Option<RunArgs>, String> {
+    let mut run = RunArgs { options: CaptureOptions::default(), output: None, forward_stdin: true };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--rows" | "--cols" => {
+                let size = value(&mut args, &arg)?;
+                let size = size.parse().ok().filter(|&size| size > 0).ok_or_else(|| format!("invalid {}: {}", arg, size))?;
+                if arg == "--rows" {
+                    run.options.rows = size;
+                } else {
+                    run.options.cols = size;
+                }
+            }
+            "--format" => {
+                let name = value(&mut args, &arg)?;
+                run.options.format = CaptureFormat::parse(&name).ok_or_else(|| format!("unknown format: {}", name))?;
+            }
+            "-o" | "--output" => run.output = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--no-stdin" => run.forward_stdin = false,
+            "--" => run.options.command.extend(args.by_ref()),
+            flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+            _ => {
+                run.options.command.push(arg);
+                run.options.command.extend(args.by_ref());
+            }
+        }
+    }
+    if run.options.command.is_empty() {
+        return Err("no command given".to_string());
+    }
+    Ok(Some(run))
+}
+
+/// Exit code for a command that couldn't be started, as shells use
+fn launch_failure_code(error: &TtyError) -> i32 {
+    match error {
+        TtyError::Exec { errno, .. } if *errno == libc::ENOENT => 127,
+        TtyError::Exec { .. } => 126,
+        _ => 1,
+    }
+}
+
+async fn run(args: RunArgs) -> i32 {
+    let engine = TtyEngine::new();
+    let stdin = args.forward_stdin.then(tokio::io::stdin);
+    let capture = match capture::capture(&engine, &args.options, stdin).await {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("pachyterm: {}", e);
+            return launch_failure_code(&e);
+        }
+    };
+
+    let written = match &args.output {
+        Some(path) => std::fs::write(path, &capture.output),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&capture.output).and_then(|_| stdout.flush())
+        }
+    };
+    if let Err(e) = written {
+        eprintln!("pachyterm: failed to write output: {}", e);
+        return 1;
+    }
+    capture.exit_code()
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let code = match args.next().as_deref() {
+        Some("run") => match parse_run_args(args) {
+            Ok(Some(run_args)) => run(run_args).await,
+            Ok(None) => {
+                println!("{}", USAGE);
+                0
+            }
+            Err(message) => {
+                eprintln!("pachyterm: {}\n\n{}", message, USAGE);
+                2
+            }
+        },
+        Some("-h" | "--help" | "help") => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    };
+    // Exit right away: a read of stdin may still be blocked on a thread
+    process::exit(code);
+}
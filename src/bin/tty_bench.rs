@@ -28,7 +28,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Test 2: I/O Throughput
     println!("\n=== I/O Throughput Performance ===");
-    let pty_id = engine.create_pty(config.clone()).await?;
+    // Output is read after all the writes
+    let pty_id = engine.create_pty(PtyConfig { read_from_start: true, ..config.clone() }).await?;
     
     // Write throughput test
     let test_data = b"echo 'performance test data'\n";
@@ -51,12 +52,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start = Instant::now();
     for _ in 0..100 {
         let mut buffer = [0u8; 1024];
-        match engine.read_from_pty(pty_id, &mut buffer).await {
-            Ok(bytes_read) => total_read += bytes_read,
-            Err(_) => break,
-        }
-        if total_read == 0 {
-            break;
+        // Reads wait for output, so stop once the shell has gone quiet
+        match tokio::time::timeout(Duration::from_millis(100), engine.read_from_pty(pty_id, &mut buffer)).await {
+            Ok(Ok(bytes_read)) if bytes_read > 0 => total_read += bytes_read,
+            _ => break,
         }
     }
     let read_duration = start.elapsed();
@@ -0,0 +1,284 @@
+// Headless capture of one command's terminal output, behind `pachyterm run`
+use crate::tty::{ExitStatus, PtyConfig, SessionEvent, TtyEngine, TtyError};
+use crate::vt::{Parser, Perform};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+/// How long output is still collected after the command exits, from
+/// background jobs still holding the terminal
+pub const DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// End-of-file character, sent once the input runs out as `script` does
+const VEOF: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// Text of the final screen, without colors
+    Screen,
+    /// Everything the command wrote, escape sequences included
+    Transcript,
+    /// The transcript with escape sequences stripped
+    Plain,
+}
+
+impl CaptureFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "screen" => Some(CaptureFormat::Screen),
+            "transcript" | "ansi" => Some(CaptureFormat::Transcript),
+            "plain" => Some(CaptureFormat::Plain),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Program and arguments; bare names are looked up in `$PATH`
+    pub command: Vec<String>,
+    pub rows: u16,
+    pub cols: u16,
+    pub format: CaptureFormat,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self { command: Vec::new(), rows: 24, cols: 80, format: CaptureFormat::Screen }
+    }
+}
+
+/// Output of a captured command and how it ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub output: Vec<u8>,
+    pub status: ExitStatus,
+}
+
+impl Capture {
+    /// Exit code as a shell reports it: 128 plus the signal for a command
+    /// killed by one
+    pub fn exit_code(&self) -> i32 {
+        match self.status {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled { signal, .. } => 128 + signal as i32,
+        }
+    }
+}
+
+/// Runs `options.command` in a new session of `engine` until it exits,
+/// writing `input` to it as it arrives, and returns its output in the
+/// chosen format
+pub async fn capture(
+    engine: &TtyEngine,
+    options: &CaptureOptions,
+    input: Option<impl AsyncRead + Unpin + Send + 'static>,
+) -> Result<Capture, TtyError> {
+    if options.command.is_empty() {
+        return Err(TtyError::InvalidConfig("no command to run".to_string()));
+    }
+    let mut events = engine.subscribe_events();
+    let config = PtyConfig {
+        command: Some(options.command.clone()),
+        rows: options.rows,
+        cols: options.cols,
+        track_screen: true,
+        read_from_start: true,
+        ..PtyConfig::default()
+    };
+    let pty_id = engine.create_pty(config).await?;
+
+    let mut forward = None;
+    let result = async {
+        // Input is forwarded on its own so a command not reading it never holds
+        // up its output
+        if let Some(mut input) = input {
+            let mut stream = engine.pty_stream(pty_id)?;
+            forward = Some(tokio::spawn(async move {
+                // The command may exit before all its input arrives
+                if tokio::io::copy(&mut input, &mut stream).await.is_ok() {
+                    let _ = stream.write_all(&[VEOF]).await;
+                }
+            }));
+        }
+
+        let mut transcript = Vec::new();
+        let mut status = None;
+        let mut drain_until = None;
+        let mut chunk = [0; 8192];
+        loop {
+            let drained = async {
+                match drain_until {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                read = engine.read_from_pty(pty_id, &mut chunk) => match read? {
+                    0 => break,
+                    read => transcript.extend_from_slice(&chunk[..read]),
+                },
+                event = events.recv(), if status.is_none() => {
+                    if event.map_or(true, |event| event.id() == pty_id && is_exit(&event)) {
+                        status = engine.exit_status(pty_id)?;
+                        if status.is_some() {
+                            drain_until = Some(tokio::time::Instant::now() + DRAIN_TIMEOUT);
+                        }
+                    }
+                }
+                _ = drained => break,
+            }
+        }
+
+        // Output can end before the child is reaped
+        while status.is_none() {
+            match events.recv().await {
+                Ok(event) if event.id() != pty_id || !is_exit(&event) => continue,
+                _ => status = engine.exit_status(pty_id)?,
+            }
+        }
+        let output = match options.format {
+            CaptureFormat::Screen => screen_output(&engine.screen_text(pty_id)?),
+            CaptureFormat::Transcript => transcript,
+            CaptureFormat::Plain => strip_ansi(&transcript).into_bytes(),
+        };
+        Ok::<_, TtyError>(Capture { output, status: status.expect("exit status was collected") })
+    }
+    .await;
+
+    // Cleaned up whether or not the capture succeeded
+    if let Some(forward) = forward {
+        forward.abort();
+    }
+    // Hangs up background jobs left behind
+    let destroyed = engine.destroy_pty(pty_id).await;
+    let capture = result?;
+    destroyed?;
+    Ok(capture)
+}
+
+fn is_exit(event: &SessionEvent) -> bool {
+    matches!(event, SessionEvent::Exited { .. } | SessionEvent::Signaled { .. })
+}
+
+/// Screen text without the blank rows below the last output
+fn screen_output(text: &str) -> Vec<u8> {
+    let text = text.trim_end_matches('\n');
+    if text.is_empty() {
+        return Vec::new();
+    }
+    format!("{}\n", text).into_bytes()
+}
+
+/// Terminal output as plain text: escape sequences are removed, CRLF
+/// becomes LF and other control characters but tab are dropped
+pub fn strip_ansi(bytes: &[u8]) -> String {
+    let mut plain = PlainText::default();
+    Parser::new().advance(&mut plain, bytes);
+    plain.text
+}
+
+#[derive(Default)]
+struct PlainText {
+    text: String,
+    /// A carriage return not yet known to end a line
+    pending_cr: bool,
+}
+
+impl Perform for PlainText {
+    fn print(&mut self, c: char) {
+        if std::mem::take(&mut self.pending_cr) {
+            self.text.push('\r');
+        }
+        self.text.push(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' => {
+                self.pending_cr = false;
+                self.text.push('\n');
+            }
+            b'\r' => self.pending_cr = true,
+            b'\t' => self.print('\t'),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::Empty;
+
+    fn options(script: &str, format: CaptureFormat) -> CaptureOptions {
+        CaptureOptions {
+            command: vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()],
+            rows: 5,
+            cols: 30,
+            format,
+        }
+    }
+
+    const COLORED: &str = "printf '\\033[31mred\\033[0m\\n'; [ -t 1 ] && echo tty; exit 3";
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi(b"\x1b[1;31merror\x1b[0m:\tbad\r\n\x1b]0;title\x07ok"), "error:\tbad\nok");
+        // A bare carriage return is kept, as in progress output
+        assert_eq!(strip_ansi(b"10%\r50%\r\n\x07"), "10%\r50%\n");
+    }
+
+    #[test]
+    fn test_format_names() {
+        assert_eq!(CaptureFormat::parse("screen"), Some(CaptureFormat::Screen));
+        assert_eq!(CaptureFormat::parse("ansi"), Some(CaptureFormat::Transcript));
+        assert_eq!(CaptureFormat::parse("html"), None);
+    }
+
+    #[tokio::test]
+    async fn test_capture_formats() {
+        let engine = TtyEngine::new();
+        let capture = super::capture(&engine, &options(COLORED, CaptureFormat::Transcript), None::<Empty>).await.unwrap();
+        assert_eq!(capture.exit_code(), 3);
+        assert_eq!(capture.output, b"\x1b[31mred\x1b[0m\r\ntty\r\n");
+
+        let capture = super::capture(&engine, &options(COLORED, CaptureFormat::Plain), None::<Empty>).await.unwrap();
+        assert_eq!(String::from_utf8(capture.output).unwrap(), "red\ntty\n");
+
+        let capture = super::capture(&engine, &options(COLORED, CaptureFormat::Screen), None::<Empty>).await.unwrap();
+        assert_eq!(String::from_utf8(capture.output).unwrap(), "red\ntty\n");
+        assert_eq!(engine.get_session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_capture_final_screen() {
+        let engine = TtyEngine::new();
+        // Only the last rows stay on a 5 row screen, and the progress line
+        // shows its final state
+        let script = "seq 1 10; printf 'working...\\rdone      \\n'";
+        let capture = super::capture(&engine, &options(script, CaptureFormat::Screen), None::<Empty>).await.unwrap();
+        assert_eq!(String::from_utf8(capture.output).unwrap(), "8\n9\n10\ndone\n");
+    }
+
+    #[tokio::test]
+    async fn test_input_is_passed_through() {
+        let engine = TtyEngine::new();
+        let input: &[u8] = b"hello\n";
+        let script = "read line; echo \"got $line\"; cat; echo end";
+        let capture = super::capture(&engine, &options(script, CaptureFormat::Plain), Some(input)).await.unwrap();
+        let output = String::from_utf8(capture.output.clone()).unwrap();
+        // cat ends at the end-of-file sent when the input ran out
+        assert!(output.contains("got hello\n") && output.ends_with("end\n"), "{:?}", output);
+        assert_eq!(capture.exit_code(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_exit_codes() {
+        let engine = TtyEngine::new();
+        let capture = super::capture(&engine, &options("kill -TERM $$", CaptureFormat::Plain), None::<Empty>).await.unwrap();
+        assert_eq!(capture.exit_code(), 128 + 15);
+
+        let options = CaptureOptions::default();
+        assert!(matches!(super::capture(&engine, &options, None::<Empty>).await, Err(TtyError::InvalidConfig(_))));
+    }
+}
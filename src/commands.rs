@@ -0,0 +1,312 @@
+// Command records built from OSC 133 shell integration marks
+use crate::screen::Screen;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Records kept per session; older ones are dropped
+pub const MAX_COMMAND_RECORDS: usize = 1000;
+/// Output kept per record. Longer output keeps its end, where errors are.
+pub const MAX_COMMAND_OUTPUT: usize = 256 * 1024;
+/// Marks held for the session to process
+const MAX_PENDING_MARKS: usize = 64;
+
+/// Semantic prompt marks, `OSC 133 ; <kind> ST`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkKind {
+    /// `A`: the prompt is about to be drawn
+    PromptStart,
+    /// `B`: the prompt ended; what follows is the command line
+    CommandStart,
+    /// `C`: the command was accepted and its output follows
+    OutputStart,
+    /// `D[;exit]`: the command finished
+    CommandFinished(Option<i32>),
+}
+
+impl MarkKind {
+    /// Parses the parameters of an OSC 133 string. Extra `key=value`
+    /// options some shells append are ignored.
+    pub fn from_osc(params: &[&[u8]]) -> Option<MarkKind> {
+        let [b"133", kind, rest @ ..] = params else {
+            return None;
+        };
+        Some(match *kind {
+            b"A" => MarkKind::PromptStart,
+            b"B" => MarkKind::CommandStart,
+            b"C" => MarkKind::OutputStart,
+            b"D" => {
+                let code = rest.first().and_then(|code| std::str::from_utf8(code).ok());
+                let code = code.and_then(|code| code.parse().ok());
+                MarkKind::CommandFinished(code)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// A mark with where the cursor was, as an absolute line (see
+/// `Screen::cursor_line`) and column. Sessions without a screen have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptMark {
+    pub kind: MarkKind,
+    pub position: Option<(u64, usize)>,
+}
+
+/// Collects marks from parsed output until the session takes them
+#[derive(Debug, Clone, Default)]
+pub struct PendingMarks {
+    marks: Vec<PromptMark>,
+}
+
+impl PendingMarks {
+    pub fn take(&mut self) -> Vec<PromptMark> {
+        std::mem::take(&mut self.marks)
+    }
+
+    pub(crate) fn osc(&mut self, params: &[&[u8]], position: Option<(u64, usize)>) {
+        if let Some(kind) = MarkKind::from_osc(params) {
+            if self.marks.len() < MAX_PENDING_MARKS {
+                self.marks.push(PromptMark { kind, position });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandRecord {
+    /// Position in the session's command history, from 0
+    pub seq: u64,
+    /// Command line as shown after the prompt; empty without a screen
+    pub command: String,
+    /// Output text, trimmed of trailing blank lines
+    pub output: String,
+    /// Absolute screen lines the output covered, see `Screen::cursor_line`
+    pub output_lines: Range<u64>,
+    /// Missing while running, or if the shell didn't report it
+    pub exit_code: Option<i32>,
+    pub started_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    /// Working directory the command started in
+    pub cwd: Option<PathBuf>,
+}
+
+impl CommandRecord {
+    pub fn is_running(&self) -> bool {
+        self.finished_at.is_none()
+    }
+
+    /// Finished with a non-zero exit code
+    pub fn failed(&self) -> bool {
+        self.exit_code.is_some_and(|code| code != 0)
+    }
+}
+
+/// Turns a session's marks into command records
+#[derive(Debug, Default)]
+pub struct CommandTracker {
+    records: VecDeque<CommandRecord>,
+    /// Where the command line started, between B and C
+    command_start: Option<Option<(u64, usize)>>,
+    /// Where the running command's output started
+    output_start: Option<(u64, usize)>,
+    next_seq: u64,
+}
+
+impl CommandTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `mark`, reading text from `screen` when there is one. `cwd`
+    /// is asked for when a command starts. Returns the record of a command
+    /// the mark finished.
+    pub fn apply(
+        &mut self,
+        mark: PromptMark,
+        screen: Option<&Screen>,
+        cwd: impl FnOnce() -> Option<PathBuf>,
+    ) -> Option<CommandRecord> {
+        match mark.kind {
+            MarkKind::PromptStart => {
+                self.command_start = None;
+                // A new prompt without D: the shell doesn't report exit codes
+                self.finish(None, mark.position, screen)
+            }
+            MarkKind::CommandStart => {
+                self.command_start = Some(mark.position);
+                None
+            }
+            MarkKind::OutputStart => {
+                let finished = self.finish(None, mark.position, screen);
+                let command = match (self.command_start.take().flatten(), mark.position, screen) {
+                    (Some(start), Some(end), Some(screen)) => screen.text_between(start, end).trim().to_string(),
+                    _ => String::new(),
+                };
+                let line = mark.position.map_or(0, |(line, _)| line);
+                self.records.push_back(CommandRecord {
+                    seq: self.next_seq,
+                    command,
+                    output: String::new(),
+                    output_lines: line..line,
+                    exit_code: None,
+                    started_at: SystemTime::now(),
+                    finished_at: None,
+                    cwd: cwd(),
+                });
+                self.next_seq += 1;
+                self.output_start = mark.position;
+                if self.records.len() > MAX_COMMAND_RECORDS {
+                    self.records.pop_front();
+                }
+                finished
+            }
+            MarkKind::CommandFinished(exit_code) => self.finish(exit_code, mark.position, screen),
+        }
+    }
+
+    /// Completes the running command, if any
+    fn finish(
+        &mut self,
+        exit_code: Option<i32>,
+        end: Option<(u64, usize)>,
+        screen: Option<&Screen>,
+    ) -> Option<CommandRecord> {
+        let record = self.records.back_mut().filter(|record| record.is_running())?;
+        record.exit_code = exit_code;
+        record.finished_at = Some(SystemTime::now());
+        if let (Some(start), Some(end)) = (self.output_start.take(), end) {
+            // The line D is on only counts if output reached it
+            record.output_lines = start.0..(end.0 + u64::from(end.1 > 0)).max(start.0);
+            if let Some(screen) = screen {
+                record.output = tail(screen.text_between(start, end).trim_end().to_string(), MAX_COMMAND_OUTPUT);
+            }
+        }
+        Some(record.clone())
+    }
+
+    /// Records oldest first, including a running command
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = &CommandRecord> {
+        self.records.iter()
+    }
+
+    pub fn last(&self) -> Option<&CommandRecord> {
+        self.records.back()
+    }
+
+    pub fn last_failed(&self) -> Option<&CommandRecord> {
+        self.records.iter().rev().find(|record| record.failed())
+    }
+}
+
+/// The last `max` bytes of `text`, cut at a character boundary
+fn tail(mut text: String, max: usize) -> String {
+    if text.len() > max {
+        let mut cut = text.len() - max;
+        while !text.is_char_boundary(cut) {
+            cut += 1;
+        }
+        text.drain(..cut);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `bytes` through a screen, applying marks as a session would
+    fn track(screen: &mut Screen, tracker: &mut CommandTracker, bytes: &[u8]) -> Vec<CommandRecord> {
+        screen.advance(bytes);
+        let marks = screen.take_marks();
+        marks
+            .into_iter()
+            .filter_map(|mark| tracker.apply(mark, Some(screen), || Some(PathBuf::from("/work"))))
+            .collect()
+    }
+
+    fn prompt(command: &str) -> Vec<u8> {
+        format!("\x1b]133;A\x07$ \x1b]133;B\x07{}\r\n\x1b]133;C\x07", command).into_bytes()
+    }
+
+    #[test]
+    fn test_parse_marks() {
+        assert_eq!(MarkKind::from_osc(&[b"133", b"A"]), Some(MarkKind::PromptStart));
+        assert_eq!(MarkKind::from_osc(&[b"133", b"A", b"aid=42"]), Some(MarkKind::PromptStart));
+        assert_eq!(MarkKind::from_osc(&[b"133", b"D", b"127"]), Some(MarkKind::CommandFinished(Some(127))));
+        assert_eq!(MarkKind::from_osc(&[b"133", b"D"]), Some(MarkKind::CommandFinished(None)));
+        assert_eq!(MarkKind::from_osc(&[b"133", b"Z"]), None);
+        assert_eq!(MarkKind::from_osc(&[b"7", b"file:///"]), None);
+    }
+
+    #[test]
+    fn test_records_command_and_output() {
+        let mut screen = Screen::new(10, 40);
+        let mut tracker = CommandTracker::new();
+
+        let mut bytes = prompt("make test");
+        bytes.extend_from_slice(b"compiling\r\nerror: oops\r\n\x1b]133;D;2\x07");
+        let finished = track(&mut screen, &mut tracker, &bytes);
+
+        assert_eq!(finished.len(), 1);
+        let record = &finished[0];
+        assert_eq!(record.command, "make test");
+        assert_eq!(record.output, "compiling\nerror: oops");
+        assert_eq!(record.output_lines, 1..3);
+        assert_eq!(record.exit_code, Some(2));
+        assert!(record.failed() && !record.is_running());
+        assert_eq!(record.cwd.as_deref(), Some(std::path::Path::new("/work")));
+        assert!(record.finished_at.unwrap() >= record.started_at);
+    }
+
+    #[test]
+    fn test_last_failed_command() {
+        let mut screen = Screen::new(5, 40);
+        let mut tracker = CommandTracker::new();
+        for (command, code) in [("false", 1), ("true", 0), ("ls /missing", 2), ("echo ok", 0)] {
+            let mut bytes = prompt(command);
+            bytes.extend_from_slice(format!("out of {}\r\n\x1b]133;D;{}\x07", command, code).as_bytes());
+            track(&mut screen, &mut tracker, &bytes);
+        }
+
+        // Output that scrolled into scrollback is still found
+        let failed = tracker.last_failed().unwrap();
+        assert_eq!((failed.seq, failed.command.as_str()), (2, "ls /missing"));
+        assert_eq!(failed.output, "out of ls /missing");
+        assert_eq!(tracker.last().unwrap().command, "echo ok");
+        assert_eq!(tracker.records().count(), 4);
+    }
+
+    #[test]
+    fn test_running_and_unreported_commands() {
+        let mut screen = Screen::new(10, 40);
+        let mut tracker = CommandTracker::new();
+        track(&mut screen, &mut tracker, &prompt("sleep 100"));
+        assert!(tracker.last().unwrap().is_running());
+
+        // A shell that never sends D finishes commands at the next prompt
+        let finished = track(&mut screen, &mut tracker, b"\x1b]133;A\x07$ ");
+        assert_eq!(finished[0].exit_code, None);
+        assert!(!tracker.last().unwrap().is_running());
+        assert!(tracker.last_failed().is_none());
+
+        // D without a running command is ignored
+        assert!(track(&mut screen, &mut tracker, b"\x1b]133;D;1\x07").is_empty());
+    }
+
+    #[test]
+    fn test_records_and_output_are_bounded() {
+        let mut tracker = CommandTracker::new();
+        for _ in 0..MAX_COMMAND_RECORDS + 5 {
+            for kind in [MarkKind::OutputStart, MarkKind::CommandFinished(Some(0))] {
+                tracker.apply(PromptMark { kind, position: None }, None, || None);
+            }
+        }
+        assert_eq!(tracker.records().count(), MAX_COMMAND_RECORDS);
+        assert_eq!(tracker.records().next().unwrap().seq, 5);
+
+        assert_eq!(tail("aé日本".to_string(), 4), "本");
+        assert_eq!(tail("short".to_string(), 10), "short");
+    }
+}
@@ -0,0 +1,437 @@
+// Expect-style automation of sessions: send input, wait for output to match
+use crate::tty::{TtyEngine, TtyError};
+use regex::bytes::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Unmatched output kept for matching. Past this the oldest output is
+/// dropped and reported as `ExpectError::OutputDropped`, so a pattern that
+/// never matches can't grow it without bound.
+pub const MAX_BUFFER_LEN: usize = 1024 * 1024;
+/// Recent input and output shown in timeout and end-of-output errors
+pub const MAX_TRANSCRIPT_LEN: usize = 16 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ExpectError {
+    #[error(transparent)]
+    Tty(#[from] TtyError),
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("Timed out after {timeout:?} waiting for {pattern}. Transcript:\n{transcript}")]
+    Timeout { pattern: String, timeout: Duration, transcript: String },
+    #[error("Output ended while waiting for {pattern}. Transcript:\n{transcript}")]
+    Eof { pattern: String, transcript: String },
+    /// Unmatched output overflowed `MAX_BUFFER_LEN`; the rest is still
+    /// buffered and later calls carry on from it
+    #[error("Dropped {dropped} bytes of unmatched output")]
+    OutputDropped { dropped: usize },
+}
+
+/// What `Expect::expect` waits for: literal text or a regular expression
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    description: String,
+}
+
+impl Pattern {
+    pub fn literal(text: &str) -> Self {
+        Self {
+            regex: Regex::new(&regex::escape(text)).expect("escaped literal is a valid regex"),
+            description: format!("{:?}", text),
+        }
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self, ExpectError> {
+        Ok(Self::from(Regex::new(pattern)?))
+    }
+
+    /// Finds the first match in `buffer`, returning it with the length of
+    /// output it uses up
+    fn find(&self, buffer: &[u8]) -> Option<(Match, usize)> {
+        let captures = self.regex.captures(buffer)?;
+        let whole = captures.get(0)?;
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let found = Match {
+            before: text(&buffer[..whole.start()]),
+            groups: captures.iter().map(|group| group.map(|group| text(group.as_bytes()))).collect(),
+            names: self
+                .regex
+                .capture_names()
+                .enumerate()
+                .filter_map(|(index, name)| Some((name?.to_string(), index)))
+                .collect(),
+        };
+        Some((found, whole.end()))
+    }
+}
+
+/// Plain strings are matched literally
+impl From<&str> for Pattern {
+    fn from(text: &str) -> Self {
+        Pattern::literal(text)
+    }
+}
+
+impl From<Regex> for Pattern {
+    fn from(regex: Regex) -> Self {
+        Self { description: format!("/{}/", regex.as_str()), regex }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+/// Output matched by `Expect::expect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Output between the previous match and this one
+    pub before: String,
+    /// Capture groups; 0 is the whole match
+    groups: Vec<Option<String>>,
+    names: HashMap<String, usize>,
+}
+
+impl Match {
+    /// The matched text
+    pub fn text(&self) -> &str {
+        self.get(0).unwrap_or_default()
+    }
+
+    /// Capture group `index`, if it took part in the match
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.groups.get(index)?.as_deref()
+    }
+
+    /// Named capture group
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.get(*self.names.get(name)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// Drives one session: sends input and waits for output. Output is read
+/// through `TtyEngine::read_from_pty`, from the start of a session created
+/// with `PtyConfig::read_from_start` and otherwise from when the handle was
+/// created; don't read the session that way elsewhere at the same time.
+///
+/// Output between calls is held, but not without limit: if the session
+/// gets more than the output channel's capacity ahead, the call reading
+/// next fails with `TtyError::OutputLagged`, and unmatched output past
+/// `MAX_BUFFER_LEN` fails it with `ExpectError::OutputDropped`.
+pub struct Expect<'a> {
+    engine: &'a TtyEngine,
+    pty_id: u64,
+    /// Output read but not yet used up by a match
+    buffer: Vec<u8>,
+    transcript: VecDeque<(Direction, Vec<u8>)>,
+    transcript_len: usize,
+    eof: bool,
+}
+
+impl<'a> Expect<'a> {
+    pub fn new(engine: &'a TtyEngine, pty_id: u64) -> Result<Self, ExpectError> {
+        engine.start_reading(pty_id)?;
+        Ok(Self {
+            engine,
+            pty_id,
+            buffer: Vec::new(),
+            transcript: VecDeque::new(),
+            transcript_len: 0,
+            eof: false,
+        })
+    }
+
+    pub fn pty_id(&self) -> u64 {
+        self.pty_id
+    }
+
+    /// Writes all of `data` to the session
+    pub async fn send(&mut self, data: impl AsRef<[u8]>) -> Result<(), ExpectError> {
+        let data = data.as_ref();
+        let mut written = 0;
+        while written < data.len() {
+            written += self.engine.write_to_pty(self.pty_id, &data[written..]).await?;
+        }
+        self.log(Direction::Sent, data);
+        Ok(())
+    }
+
+    /// Sends `line` followed by a carriage return, as the Enter key does
+    pub async fn send_line(&mut self, line: &str) -> Result<(), ExpectError> {
+        self.send(format!("{}\r", line)).await
+    }
+
+    /// Waits up to `timeout` for output matching `pattern`. Output up to the
+    /// end of the match is used up; the rest stays for the next call.
+    pub async fn expect(&mut self, pattern: impl Into<Pattern>, timeout: Duration) -> Result<Match, ExpectError> {
+        let pattern = pattern.into();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some((found, end)) = pattern.find(&self.buffer) {
+                self.buffer.drain(..end);
+                return Ok(found);
+            }
+            if self.eof {
+                return Err(ExpectError::Eof { pattern: pattern.to_string(), transcript: self.transcript() });
+            }
+            match tokio::time::timeout_at(deadline, self.fill()).await {
+                Ok(filled) => filled?,
+                Err(_) => {
+                    return Err(ExpectError::Timeout {
+                        pattern: pattern.to_string(),
+                        timeout,
+                        transcript: self.transcript(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the session's output to end, e.g. because
+    /// its program exited, returning the output not matched before
+    pub async fn expect_eof(&mut self, timeout: Duration) -> Result<String, ExpectError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.eof {
+            match tokio::time::timeout_at(deadline, self.fill()).await {
+                Ok(filled) => filled?,
+                Err(_) => {
+                    return Err(ExpectError::Timeout {
+                        pattern: "end of output".to_string(),
+                        timeout,
+                        transcript: self.transcript(),
+                    })
+                }
+            }
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        Ok(String::from_utf8_lossy(&rest).into_owned())
+    }
+
+    /// Output read but not matched yet
+    pub fn buffer(&self) -> String {
+        String::from_utf8_lossy(&self.buffer).into_owned()
+    }
+
+    /// Recent input and output, with control characters escaped. Lines
+    /// sent are shown as `>>> line`.
+    pub fn transcript(&self) -> String {
+        let mut text = String::new();
+        for (direction, data) in &self.transcript {
+            match direction {
+                Direction::Sent => {
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        text.push('\n');
+                    }
+                    text.push_str(">>> ");
+                    text.push_str(&visible(data, false));
+                    text.push('\n');
+                }
+                Direction::Received => text.push_str(&visible(data, true)),
+            }
+        }
+        text
+    }
+
+    /// Reads the next piece of output into the buffer. Cancel safe.
+    async fn fill(&mut self) -> Result<(), ExpectError> {
+        let mut chunk = [0; 4096];
+        let read = self.engine.read_from_pty(self.pty_id, &mut chunk).await?;
+        if read == 0 {
+            self.eof = true;
+            return Ok(());
+        }
+        self.buffer.extend_from_slice(&chunk[..read]);
+        self.log(Direction::Received, &chunk[..read]);
+        if self.buffer.len() > MAX_BUFFER_LEN {
+            let dropped = self.buffer.len() - MAX_BUFFER_LEN;
+            self.buffer.drain(..dropped);
+            return Err(ExpectError::OutputDropped { dropped });
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, direction: Direction, data: &[u8]) {
+        match self.transcript.back_mut() {
+            Some((last, logged)) if *last == direction && direction == Direction::Received => logged.extend_from_slice(data),
+            _ => self.transcript.push_back((direction, data.to_vec())),
+        }
+        self.transcript_len += data.len();
+        while self.transcript_len > MAX_TRANSCRIPT_LEN {
+            let excess = self.transcript_len - MAX_TRANSCRIPT_LEN;
+            let Some((_, oldest)) = self.transcript.front_mut() else { break };
+            if oldest.len() > excess {
+                oldest.drain(..excess);
+                self.transcript_len -= excess;
+            } else {
+                self.transcript_len -= oldest.len();
+                self.transcript.pop_front();
+            }
+        }
+    }
+}
+
+/// `data` as text with control characters escaped. Output keeps its line
+/// breaks.
+fn visible(data: &[u8], keep_newlines: bool) -> String {
+    let text = String::from_utf8_lossy(data);
+    let text = if keep_newlines { text.replace("\r\n", "\n") } else { text.into_owned() };
+    text.chars()
+        .map(|c| match c {
+            '\n' if keep_newlines => c.to_string(),
+            c if c.is_control() => c.escape_debug().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tty::PtyConfig;
+
+    async fn spawn(engine: &TtyEngine, script: &str) -> u64 {
+        let config = PtyConfig {
+            command: Some(vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()]),
+            read_from_start: true,
+            ..PtyConfig::default()
+        };
+        engine.create_pty(config).await.unwrap()
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_patterns() {
+        let literal = Pattern::literal("a.b (1)");
+        assert!(literal.find(b"axb (1)").is_none());
+        let (found, end) = literal.find(b"xx a.b (1) yy").unwrap();
+        assert_eq!((found.before.as_str(), found.text(), end), ("xx ", "a.b (1)", 10));
+
+        let regex = Pattern::regex(r"exit (?P<code>\d+)( \(core\))?").unwrap();
+        let (found, _) = regex.find(b"$ false\r\nexit 1\r\n").unwrap();
+        assert_eq!((found.get(1), found.name("code"), found.get(2)), (Some("1"), Some("1"), None));
+        assert_eq!(regex.to_string(), r"/exit (?P<code>\d+)( \(core\))?/");
+
+        assert!(matches!(Pattern::regex("(unclosed"), Err(ExpectError::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_visible() {
+        assert_eq!(visible(b"a\r\nb\x1b[0m\x07", true), "a\nb\\u{1b}[0m\\u{7}");
+        assert_eq!(visible(b"ls\r", false), "ls\\r");
+    }
+
+    #[tokio::test]
+    async fn test_send_line_and_expect() {
+        let engine = TtyEngine::new();
+        let pty_id = spawn(&engine, "printf 'name? '; read name; echo \"hello $name\"; echo 'code=42'").await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+
+        session.expect("name? ", TIMEOUT).await.unwrap();
+        session.send_line("world").await.unwrap();
+        let found = session.expect("hello world", TIMEOUT).await.unwrap();
+        assert!(found.before.contains("world"));
+
+        let found = session.expect(Pattern::regex(r"code=(?P<code>\d+)").unwrap(), TIMEOUT).await.unwrap();
+        assert_eq!(found.name("code"), Some("42"));
+        assert_eq!(session.expect_eof(TIMEOUT).await.unwrap().trim(), "");
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_between_calls_is_kept() {
+        let engine = TtyEngine::new();
+        let pty_id = spawn(&engine, "echo one; echo two; echo three; exec sleep 5").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+
+        session.expect("one", TIMEOUT).await.unwrap();
+        let found = session.expect("three", TIMEOUT).await.unwrap();
+        assert_eq!(found.before, "\r\ntwo\r\n");
+        assert_eq!(session.buffer(), "\r\n");
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_reports_transcript() {
+        let engine = TtyEngine::new();
+        let pty_id = spawn(&engine, "echo ready; read line; echo \"got $line\"; exec sleep 5").await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+        session.expect("ready", TIMEOUT).await.unwrap();
+        session.send_line("go").await.unwrap();
+
+        let error = session.expect("never", Duration::from_millis(200)).await.unwrap_err();
+        let ExpectError::Timeout { pattern, transcript, .. } = &error else { panic!("{:?}", error) };
+        assert_eq!(pattern, "\"never\"");
+        assert!(transcript.starts_with("ready\n>>> go\\r\n"), "{:?}", transcript);
+        assert!(transcript.contains("got go"));
+        assert!(error.to_string().contains("Timed out after 200ms waiting for \"never\""));
+
+        assert!(matches!(session.expect_eof(Duration::from_millis(100)).await, Err(ExpectError::Timeout { .. })));
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_ending_fails_expect() {
+        let engine = TtyEngine::new();
+        let pty_id = spawn(&engine, "echo bye").await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+
+        let error = session.expect("never", TIMEOUT).await.unwrap_err();
+        assert!(matches!(&error, ExpectError::Eof { transcript, .. } if transcript.contains("bye")), "{:?}", error);
+        assert!(matches!(Expect::new(&engine, 999), Err(ExpectError::Tty(TtyError::PtyNotFound { id: 999 }))));
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_output_is_reported() {
+        let engine = TtyEngine::new();
+        // Paced so the output channel keeps up and only the buffer overflows
+        let blocks = MAX_BUFFER_LEN / 65536 + 1;
+        let script = format!(
+            "for i in $(seq {}); do head -c 65536 /dev/zero | tr '\\0' x; sleep 0.02; done; echo; echo done; exec sleep 5",
+            blocks
+        );
+        let pty_id = spawn(&engine, &script).await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+
+        let error = session.expect("done", TIMEOUT).await.unwrap_err();
+        assert!(matches!(error, ExpectError::OutputDropped { dropped } if dropped > 0), "{:?}", error);
+        // Each overflowing read is reported, and later output is still matched
+        let found = loop {
+            match session.expect("done", TIMEOUT).await {
+                Err(ExpectError::OutputDropped { .. }) => continue,
+                result => break result.unwrap(),
+            }
+        };
+        assert!(found.before.ends_with("x\r\n"));
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_destroyed_session_fails_expect() {
+        let engine = TtyEngine::new();
+        let pty_id = spawn(&engine, "exec sleep 5").await;
+        let mut session = Expect::new(&engine, pty_id).unwrap();
+        engine.destroy_pty(pty_id).await.unwrap();
+
+        let result = session.expect("never", TIMEOUT).await;
+        assert!(matches!(result, Err(ExpectError::Tty(TtyError::PtyNotFound { .. }))), "{:?}", result);
+        let result = session.expect_eof(TIMEOUT).await;
+        assert!(matches!(result, Err(ExpectError::Tty(TtyError::PtyNotFound { .. }))), "{:?}", result);
+    }
+}
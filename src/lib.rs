@@ -1,2 +1,15 @@
+pub mod capture;
+pub mod commands;
 pub mod config;
+pub mod expect;
+pub mod modes;
+pub mod queries;
+pub mod recording;
+pub mod replay;
+pub mod screen;
+pub mod scrollback;
+pub mod shell_state;
+pub mod themes;
 pub mod tty;
+pub mod vt;
+pub mod width;
@@ -0,0 +1,322 @@
+// Terminal modes announced by applications through DECSET/DECRST and friends
+use crate::commands::{PendingMarks, PromptMark};
+use crate::queries::{PendingQueries, Query};
+use crate::shell_state::{ShellChanges, ShellState};
+use crate::vt::{Params, Parser, Perform};
+
+/// Which mouse events the application asked to receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MouseTracking {
+    #[default]
+    Off,
+    /// Mode 9: presses only
+    X10,
+    /// Mode 1000: presses and releases
+    Normal,
+    /// Mode 1002: also motion while a button is held
+    ButtonEvent,
+    /// Mode 1003: all motion
+    AnyEvent,
+}
+
+/// How mouse reports are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MouseEncoding {
+    #[default]
+    Default,
+    /// Mode 1005
+    Utf8,
+    /// Mode 1006
+    Sgr,
+    /// Mode 1015
+    Urxvt,
+    /// Mode 1016
+    SgrPixels,
+}
+
+/// Mode state a session's output has selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modes {
+    /// 47, 1047 or 1049: a full-screen application owns the terminal
+    pub alt_screen: bool,
+    /// DECCKM (1): cursor keys send SS3 sequences
+    pub application_cursor: bool,
+    /// DECKPAM / DECKPNM: keypad sends application sequences
+    pub application_keypad: bool,
+    /// 2004
+    pub bracketed_paste: bool,
+    pub mouse_tracking: MouseTracking,
+    pub mouse_encoding: MouseEncoding,
+    /// 1004: report focus in/out
+    pub focus_events: bool,
+    /// 2026: the application is mid-frame and rendering should wait
+    pub synchronized_output: bool,
+    /// DECTCEM (25)
+    pub cursor_visible: bool,
+    /// DECAWM (7)
+    pub autowrap: bool,
+    /// DECOM (6): cursor addressing is relative to the scroll region
+    pub origin: bool,
+    /// 2027: widths are measured per grapheme cluster
+    pub grapheme_clusters: bool,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            alt_screen: false,
+            application_cursor: false,
+            application_keypad: false,
+            bracketed_paste: false,
+            mouse_tracking: MouseTracking::Off,
+            mouse_encoding: MouseEncoding::Default,
+            focus_events: false,
+            synchronized_output: false,
+            cursor_visible: true,
+            autowrap: true,
+            origin: false,
+            grapheme_clusters: false,
+        }
+    }
+}
+
+impl Modes {
+    /// Applies DECSET (`enabled`) or DECRST of private mode `mode`. Returns
+    /// false for modes that aren't tracked.
+    pub fn set_private(&mut self, mode: u16, enabled: bool) -> bool {
+        match mode {
+            1 => self.application_cursor = enabled,
+            6 => self.origin = enabled,
+            7 => self.autowrap = enabled,
+            25 => self.cursor_visible = enabled,
+            47 | 1047 | 1049 => self.alt_screen = enabled,
+            9 => self.set_mouse_tracking(MouseTracking::X10, enabled),
+            1000 => self.set_mouse_tracking(MouseTracking::Normal, enabled),
+            1002 => self.set_mouse_tracking(MouseTracking::ButtonEvent, enabled),
+            1003 => self.set_mouse_tracking(MouseTracking::AnyEvent, enabled),
+            1004 => self.focus_events = enabled,
+            1005 => self.set_mouse_encoding(MouseEncoding::Utf8, enabled),
+            1006 => self.set_mouse_encoding(MouseEncoding::Sgr, enabled),
+            1015 => self.set_mouse_encoding(MouseEncoding::Urxvt, enabled),
+            1016 => self.set_mouse_encoding(MouseEncoding::SgrPixels, enabled),
+            2004 => self.bracketed_paste = enabled,
+            2026 => self.synchronized_output = enabled,
+            2027 => self.grapheme_clusters = enabled,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Mode a DECRQM query for private mode `mode` should report
+    pub fn get_private(&self, mode: u16) -> Option<bool> {
+        Some(match mode {
+            1 => self.application_cursor,
+            6 => self.origin,
+            7 => self.autowrap,
+            25 => self.cursor_visible,
+            47 | 1047 | 1049 => self.alt_screen,
+            9 => self.mouse_tracking == MouseTracking::X10,
+            1000 => self.mouse_tracking == MouseTracking::Normal,
+            1002 => self.mouse_tracking == MouseTracking::ButtonEvent,
+            1003 => self.mouse_tracking == MouseTracking::AnyEvent,
+            1004 => self.focus_events,
+            1005 => self.mouse_encoding == MouseEncoding::Utf8,
+            1006 => self.mouse_encoding == MouseEncoding::Sgr,
+            1015 => self.mouse_encoding == MouseEncoding::Urxvt,
+            1016 => self.mouse_encoding == MouseEncoding::SgrPixels,
+            2004 => self.bracketed_paste,
+            2026 => self.synchronized_output,
+            2027 => self.grapheme_clusters,
+            _ => return None,
+        })
+    }
+
+    /// Any mouse mode reset turns tracking off, as in xterm
+    fn set_mouse_tracking(&mut self, tracking: MouseTracking, enabled: bool) {
+        self.mouse_tracking = if enabled { tracking } else { MouseTracking::Off };
+    }
+
+    fn set_mouse_encoding(&mut self, encoding: MouseEncoding, enabled: bool) {
+        if enabled {
+            self.mouse_encoding = encoding;
+        } else if self.mouse_encoding == encoding {
+            self.mouse_encoding = MouseEncoding::Default;
+        }
+    }
+
+    /// Handles the keypad mode escapes, returning false for anything else
+    pub(crate) fn apply_esc(&mut self, byte: u8) -> bool {
+        match byte {
+            b'=' => self.application_keypad = true,
+            b'>' => self.application_keypad = false,
+            b'c' => *self = Modes::default(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Calls `f` with each mode of a `CSI ? ... h`/`l` sequence
+pub(crate) fn for_each_private_mode(
+    params: &Params,
+    intermediates: &[u8],
+    ignore: bool,
+    action: char,
+    mut f: impl FnMut(u16, bool),
+) {
+    let enabled = match action {
+        'h' => true,
+        'l' => false,
+        _ => return,
+    };
+    if ignore || intermediates != b"?" {
+        return;
+    }
+    for group in params.iter() {
+        f(group[0], enabled);
+    }
+}
+
+/// Follows modes without keeping a screen, for sessions that don't track one.
+/// Also collects queries, apart from cursor reports which need a screen.
+#[derive(Debug, Clone, Default)]
+pub struct ModeTracker {
+    parser: Parser,
+    modes: Modes,
+    queries: PendingQueries,
+    marks: PendingMarks,
+    shell: ShellState,
+}
+
+impl ModeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&mut self, bytes: &[u8]) {
+        let mut parser = std::mem::take(&mut self.parser);
+        parser.advance(self, bytes);
+        self.parser = parser;
+    }
+
+    pub fn modes(&self) -> Modes {
+        self.modes
+    }
+
+    pub fn take_queries(&mut self) -> Vec<Query> {
+        self.queries.take()
+    }
+
+    /// Shell integration marks since the last call. Without a screen they
+    /// carry no position.
+    pub fn take_marks(&mut self) -> Vec<PromptMark> {
+        self.marks.take()
+    }
+
+    /// Working directory and titles the shell has reported
+    pub fn shell_state(&self) -> &ShellState {
+        &self.shell
+    }
+
+    /// Title and directory changes since the last call
+    pub fn take_shell_changes(&mut self) -> ShellChanges {
+        self.shell.take_changes()
+    }
+}
+
+impl Perform for ModeTracker {
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        self.queries.csi(params, intermediates, ignore, action, &self.modes, None);
+        self.shell.csi(params, intermediates, ignore, action);
+        let modes = &mut self.modes;
+        for_each_private_mode(params, intermediates, ignore, action, |mode, enabled| {
+            modes.set_private(mode, enabled);
+        });
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if !ignore && intermediates.is_empty() {
+            self.modes.apply_esc(byte);
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        self.queries.osc(params, bell_terminated);
+        self.shell.osc(params);
+        self.marks.osc(params, None);
+    }
+
+    fn hook(&mut self, _params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        self.queries.hook(intermediates, ignore, action);
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.queries.put(byte);
+    }
+
+    fn unhook(&mut self) {
+        self.queries.unhook();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(bytes: &[u8]) -> Modes {
+        let mut tracker = ModeTracker::new();
+        tracker.advance(bytes);
+        tracker.modes()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let modes = Modes::default();
+        assert!(modes.cursor_visible && modes.autowrap);
+        assert!(!modes.alt_screen && !modes.bracketed_paste);
+        assert_eq!(modes.mouse_tracking, MouseTracking::Off);
+    }
+
+    #[test]
+    fn test_decset_and_decrst() {
+        let modes = track(b"\x1b[?1049h\x1b[?2004;1004;2026;2027h\x1b[?1h\x1b[?25l");
+        assert!(modes.alt_screen && modes.bracketed_paste && modes.focus_events && modes.synchronized_output);
+        assert!(modes.grapheme_clusters);
+        assert!(modes.application_cursor);
+        assert!(!modes.cursor_visible);
+
+        let modes = track(b"\x1b[?1049h\x1b[?2004h\x1b[?1049;2004l");
+        assert_eq!(modes, Modes::default());
+
+        // Non-private sequences with the same final byte are not modes
+        assert_eq!(track(b"\x1b[4h\x1b[20h"), Modes::default());
+    }
+
+    #[test]
+    fn test_mouse_modes() {
+        let modes = track(b"\x1b[?1000h\x1b[?1006h");
+        assert_eq!((modes.mouse_tracking, modes.mouse_encoding), (MouseTracking::Normal, MouseEncoding::Sgr));
+
+        let modes = track(b"\x1b[?1000h\x1b[?1003h\x1b[?1006h\x1b[?1003l\x1b[?1015l");
+        assert_eq!((modes.mouse_tracking, modes.mouse_encoding), (MouseTracking::Off, MouseEncoding::Sgr));
+        assert_eq!(modes.get_private(1006), Some(true));
+        assert_eq!(modes.get_private(1003), Some(false));
+        assert_eq!(modes.get_private(12345), None);
+    }
+
+    #[test]
+    fn test_keypad_and_reset() {
+        assert!(track(b"\x1b=").application_keypad);
+        assert!(!track(b"\x1b=\x1b>").application_keypad);
+        assert_eq!(track(b"\x1b[?1049h\x1b=\x1bc"), Modes::default());
+    }
+
+    #[test]
+    fn test_sequence_split_across_chunks() {
+        let mut tracker = ModeTracker::new();
+        for chunk in [&b"\x1b"[..], b"[?10", b"49", b"h"] {
+            tracker.advance(chunk);
+        }
+        assert!(tracker.modes().alt_screen);
+    }
+}
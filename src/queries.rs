@@ -0,0 +1,288 @@
+// Terminal queries found in session output and the replies the engine sends
+use crate::modes::Modes;
+use crate::themes::Theme;
+use crate::vt::Params;
+
+/// Queries held for the session to answer; more are dropped so output nobody
+/// answers can't grow the queue without bound
+const MAX_PENDING_QUERIES: usize = 64;
+/// Longest XTGETTCAP request collected
+const MAX_TERMCAP_REQUEST: usize = 1024;
+
+/// Capabilities XTGETTCAP reports beyond the terminal name. Values are
+/// terminfo strings; `None` marks a boolean capability.
+const TERMCAP: &[(&str, Option<&str>)] = &[
+    ("Co", Some("256")),
+    ("colors", Some("256")),
+    ("RGB", None),
+    ("Tc", None),
+    ("setrgbf", Some("\x1b[38;2;%p1%d;%p2%d;%p3%dm")),
+    ("setrgbb", Some("\x1b[48;2;%p1%d;%p2%d;%p3%dm")),
+    ("smcup", Some("\x1b[?1049h")),
+    ("rmcup", Some("\x1b[?1049l")),
+    ("Sync", Some("\x1b[?2026%?%p1%{1}%-%tl%eh%;")),
+];
+
+/// A query with the terminal state needed to answer it, captured when the
+/// query was parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// DA1, `CSI c`
+    PrimaryAttributes,
+    /// DA2, `CSI > c`
+    SecondaryAttributes,
+    /// DSR 5, `CSI 5 n`
+    Status,
+    /// DSR 6 (`CSI 6 n`) or DECXCPR (`CSI ? 6 n`), with the 1-based position
+    CursorPosition { row: usize, col: usize, private: bool },
+    /// DECRQM, `CSI ? Ps $ p` or `CSI Ps $ p`
+    Mode { mode: u16, private: bool, state: Option<bool> },
+    /// XTVERSION, `CSI > q`
+    Version,
+    /// XTGETTCAP, `DCS + q Pt ST`, with the decoded capability names
+    Termcap(Vec<String>),
+    /// OSC 10 (`foreground`) or OSC 11 with `?`
+    Color { foreground: bool, bell_terminated: bool },
+}
+
+impl Query {
+    /// Bytes to write back to the PTY. `term` is the session's TERM, reported
+    /// as the terminal name.
+    pub fn reply(&self, theme: &Theme, term: &str) -> Vec<u8> {
+        match self {
+            // VT220 with ANSI color
+            Query::PrimaryAttributes => b"\x1b[?62;22c".to_vec(),
+            Query::SecondaryAttributes => format!("\x1b[>1;{};0c", version_number()).into_bytes(),
+            Query::Status => b"\x1b[0n".to_vec(),
+            Query::CursorPosition { row, col, private } => {
+                format!("\x1b[{}{};{}R", if *private { "?" } else { "" }, row, col).into_bytes()
+            }
+            Query::Mode { mode, private, state } => {
+                let value = match state {
+                    Some(true) => 1,
+                    Some(false) => 2,
+                    None => 0,
+                };
+                format!("\x1b[{}{};{}$y", if *private { "?" } else { "" }, mode, value).into_bytes()
+            }
+            Query::Version => format!("\x1bP>|pachyterm({})\x1b\\", env!("CARGO_PKG_VERSION")).into_bytes(),
+            Query::Termcap(names) => names.iter().flat_map(|name| termcap_reply(name, term)).collect(),
+            Query::Color { foreground, bell_terminated } => {
+                let (code, color) = if *foreground { (10, theme.foreground) } else { (11, theme.background) };
+                let terminator = if *bell_terminated { "\x07" } else { "\x1b\\" };
+                format!("\x1b]{};{}{}", code, color.to_x11(), terminator).into_bytes()
+            }
+        }
+    }
+}
+
+/// Version for DA2, e.g. 0.1.0 as 100
+fn version_number() -> u32 {
+    env!("CARGO_PKG_VERSION")
+        .split('.')
+        .take(3)
+        .map(|part| part.parse::<u32>().unwrap_or(0))
+        .fold(0, |number, part| number * 100 + part)
+}
+
+fn termcap_reply(name: &str, term: &str) -> Vec<u8> {
+    let value = match name {
+        "TN" | "name" => Some(Some(term)),
+        _ => TERMCAP.iter().find(|(cap, _)| *cap == name).map(|(_, value)| *value),
+    };
+    match value {
+        Some(Some(value)) => format!("\x1bP1+r{}={}\x1b\\", hex_encode(name), hex_encode(value)),
+        Some(None) => format!("\x1bP1+r{}\x1b\\", hex_encode(name)),
+        None => format!("\x1bP0+r{}\x1b\\", hex_encode(name)),
+    }
+    .into_bytes()
+}
+
+fn hex_encode(text: &str) -> String {
+    text.bytes().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<String> {
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Collects queries from parsed output until the session takes them
+#[derive(Debug, Clone, Default)]
+pub struct PendingQueries {
+    queries: Vec<Query>,
+    /// Data of an XTGETTCAP request in progress
+    termcap: Option<Vec<u8>>,
+}
+
+impl PendingQueries {
+    /// Queries collected since the last call, oldest first
+    pub fn take(&mut self) -> Vec<Query> {
+        std::mem::take(&mut self.queries)
+    }
+
+    fn push(&mut self, query: Query) {
+        if self.queries.len() < MAX_PENDING_QUERIES {
+            self.queries.push(query);
+        }
+    }
+
+    /// Handles a CSI sequence. `cursor` is the 1-based position a cursor
+    /// report would give, if known.
+    pub(crate) fn csi(
+        &mut self,
+        params: &Params,
+        intermediates: &[u8],
+        ignore: bool,
+        action: char,
+        modes: &Modes,
+        cursor: Option<(usize, usize)>,
+    ) {
+        if ignore {
+            return;
+        }
+        let first = params.get(0).unwrap_or(0);
+        let query = match (intermediates, action) {
+            (b"", 'c') if first == 0 => Query::PrimaryAttributes,
+            (b">", 'c') if first == 0 => Query::SecondaryAttributes,
+            (b"", 'n') if first == 5 => Query::Status,
+            (b"" | b"?", 'n') if first == 6 => match cursor {
+                Some((row, col)) => Query::CursorPosition { row, col, private: intermediates == b"?" },
+                None => return,
+            },
+            (b"?$", 'p') => Query::Mode { mode: first, private: true, state: modes.get_private(first) },
+            // No ANSI modes are implemented
+            (b"$", 'p') => Query::Mode { mode: first, private: false, state: None },
+            (b">", 'q') if first == 0 => Query::Version,
+            _ => return,
+        };
+        self.push(query);
+    }
+
+    pub(crate) fn osc(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let foreground = match params {
+            [b"10", b"?"] => true,
+            [b"11", b"?"] => false,
+            _ => return,
+        };
+        self.push(Query::Color { foreground, bell_terminated });
+    }
+
+    pub(crate) fn hook(&mut self, intermediates: &[u8], ignore: bool, action: char) {
+        self.termcap = (!ignore && intermediates == b"+" && action == 'q').then(Vec::new);
+    }
+
+    pub(crate) fn put(&mut self, byte: u8) {
+        if let Some(data) = self.termcap.as_mut().filter(|data| data.len() < MAX_TERMCAP_REQUEST) {
+            data.push(byte);
+        }
+    }
+
+    pub(crate) fn unhook(&mut self) {
+        if let Some(data) = self.termcap.take() {
+            // Names that aren't valid hex are reported as unknown, verbatim
+            let names = data
+                .split(|&byte| byte == b';')
+                .filter(|name| !name.is_empty())
+                .map(|name| hex_decode(name).unwrap_or_else(|| String::from_utf8_lossy(name).into_owned()))
+                .collect();
+            self.push(Query::Termcap(names));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vt::{Parser, Perform};
+
+    struct Collector {
+        queries: PendingQueries,
+        modes: Modes,
+    }
+
+    impl Perform for Collector {
+        fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+            self.queries.csi(params, intermediates, ignore, action, &self.modes, Some((3, 7)));
+        }
+
+        fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+            self.queries.osc(params, bell_terminated);
+        }
+
+        fn hook(&mut self, _params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+            self.queries.hook(intermediates, ignore, action);
+        }
+
+        fn put(&mut self, byte: u8) {
+            self.queries.put(byte);
+        }
+
+        fn unhook(&mut self) {
+            self.queries.unhook();
+        }
+    }
+
+    fn replies(bytes: &[u8]) -> String {
+        let mut collector = Collector { queries: PendingQueries::default(), modes: Modes::default() };
+        Parser::new().advance(&mut collector, bytes);
+        let replies: Vec<u8> = collector
+            .queries
+            .take()
+            .iter()
+            .flat_map(|query| query.reply(&Theme::DARK, "xterm-256color"))
+            .collect();
+        String::from_utf8(replies).unwrap()
+    }
+
+    #[test]
+    fn test_device_attributes_and_status() {
+        assert_eq!(replies(b"\x1b[c\x1b[0c"), "\x1b[?62;22c\x1b[?62;22c");
+        assert_eq!(replies(b"\x1b[>c"), format!("\x1b[>1;{};0c", version_number()));
+        assert_eq!(replies(b"\x1b[5n"), "\x1b[0n");
+        assert_eq!(replies(b"\x1b[6n\x1b[?6n"), "\x1b[3;7R\x1b[?3;7R");
+        // DA3 and other forms are left alone
+        assert_eq!(replies(b"\x1b[=c\x1b[1c\x1b[4n"), "");
+    }
+
+    #[test]
+    fn test_mode_reports() {
+        assert_eq!(replies(b"\x1b[?25$p\x1b[?2004$p\x1b[?9999$p"), "\x1b[?25;1$y\x1b[?2004;2$y\x1b[?9999;0$y");
+        assert_eq!(replies(b"\x1b[4$p"), "\x1b[4;0$y");
+    }
+
+    #[test]
+    fn test_version_and_termcap() {
+        assert_eq!(replies(b"\x1b[>q"), format!("\x1bP>|pachyterm({})\x1b\\", env!("CARGO_PKG_VERSION")));
+
+        // TN;Co;RGB;bogus
+        let reply = replies(b"\x1bP+q544E;436F;524742;626F677573\x1b\\");
+        let expected = format!(
+            "\x1bP1+r544E={}\x1b\\\x1bP1+r436F=323536\x1b\\\x1bP1+r524742\x1b\\\x1bP0+r626F677573\x1b\\",
+            hex_encode("xterm-256color")
+        );
+        assert_eq!(reply, expected);
+        assert_eq!(hex_decode(b"536574"), Some("Set".to_string()));
+    }
+
+    #[test]
+    fn test_color_queries_follow_the_terminator() {
+        assert_eq!(replies(b"\x1b]10;?\x07"), format!("\x1b]10;{}\x07", Theme::DARK.foreground.to_x11()));
+        assert_eq!(replies(b"\x1b]11;?\x1b\\"), format!("\x1b]11;{}\x1b\\", Theme::DARK.background.to_x11()));
+        // Setting a color is not a query
+        assert_eq!(replies(b"\x1b]11;#000000\x07"), "");
+    }
+
+    #[test]
+    fn test_pending_queries_are_bounded() {
+        let mut queries = PendingQueries::default();
+        for _ in 0..MAX_PENDING_QUERIES * 2 {
+            queries.push(Query::Status);
+        }
+        assert_eq!(queries.take().len(), MAX_PENDING_QUERIES);
+        assert!(queries.take().is_empty());
+    }
+}
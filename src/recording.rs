@@ -0,0 +1,291 @@
+// Session recording in the asciicast v2 format
+use crate::themes::Theme;
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where auto-recorded sessions go, under the home directory
+pub const RECORDINGS_DIR: &str = ".pachyterm/sessions";
+
+/// Events are buffered rather than costing a write on every chunk of
+/// output, and reach the file at most this long after being recorded:
+/// with a later event, or once `flush_due` has passed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordingOptions {
+    /// Also record what is written to the session as "i" events. This
+    /// includes anything typed, passwords too.
+    pub record_input: bool,
+}
+
+/// `~/.pachyterm/sessions`
+pub fn default_recordings_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(RECORDINGS_DIR))
+}
+
+/// A new file for session `id` under `base`: `<base>/<id>/<unix millis>.cast`.
+/// Session ids restart with the engine, so each recording gets its own file.
+pub fn recording_path(base: &Path, id: u64) -> PathBuf {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+    base.join(id.to_string()).join(format!("{}.cast", millis))
+}
+
+/// Terminal state written to the header
+#[derive(Debug, Clone)]
+pub struct CastHeader {
+    pub cols: u16,
+    pub rows: u16,
+    pub shell: String,
+    pub term: String,
+    pub theme: Theme,
+}
+
+/// Writes one session's events to an asciicast v2 file
+#[derive(Debug)]
+pub struct Recorder {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    options: RecordingOptions,
+    started: Instant,
+    flushed_at: Instant,
+    /// Events are waiting in the buffer
+    unflushed: bool,
+    output: Utf8Stream,
+    input: Utf8Stream,
+}
+
+impl Recorder {
+    /// Creates `path`, and its directory if needed, and writes the header
+    pub fn create(path: &Path, options: RecordingOptions, header: &CastHeader) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let palette: Vec<String> = header.theme.palette.iter().map(|color| color.to_hex()).collect();
+        let header = json!({
+            "version": 2,
+            "width": header.cols,
+            "height": header.rows,
+            "timestamp": timestamp,
+            "env": { "SHELL": header.shell, "TERM": header.term },
+            "theme": {
+                "fg": header.theme.foreground.to_hex(),
+                "bg": header.theme.background.to_hex(),
+                "palette": palette.join(":"),
+            },
+        });
+        writeln!(writer, "{}", header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            path: path.to_path_buf(),
+            options,
+            started: Instant::now(),
+            flushed_at: Instant::now(),
+            unflushed: false,
+            output: Utf8Stream::default(),
+            input: Utf8Stream::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn options(&self) -> RecordingOptions {
+        self.options
+    }
+
+    pub fn output(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let text = self.output.decode(bytes);
+        self.event("o", &text)
+    }
+
+    /// Records input if the options ask for it
+    pub fn input(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !self.options.record_input {
+            return Ok(());
+        }
+        let text = self.input.decode(bytes);
+        self.event("i", &text)
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", cols, rows))
+    }
+
+    /// When buffered events are due in the file, if any are waiting
+    pub fn flush_due(&self) -> Option<Instant> {
+        self.unflushed.then(|| self.flushed_at + FLUSH_INTERVAL)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.flushed_at = Instant::now();
+        self.unflushed = false;
+        Ok(())
+    }
+
+    /// Flushes the file. Dropping a recorder flushes too but loses errors.
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        self.writer.flush()?;
+        Ok(self.path)
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        // Written in one call so the buffer is never flushed partway through
+        // it, and a reader following the file never sees half an event
+        let line = format!("{}\n", json!([time, code, data]));
+        self.writer.write_all(line.as_bytes())?;
+        self.unflushed = true;
+        if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes UTF-8 arriving in chunks, holding back a character split across
+/// chunks. Invalid bytes become U+FFFD.
+#[derive(Debug, Default)]
+struct Utf8Stream {
+    pending: Vec<u8>,
+}
+
+impl Utf8Stream {
+    fn decode(&mut self, bytes: &[u8]) -> String {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(bytes);
+        let complete = complete_len(&data);
+        self.pending = data.split_off(complete);
+        String::from_utf8_lossy(&data).into_owned()
+    }
+}
+
+/// Length of `data` without a trailing incomplete sequence
+fn complete_len(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        let width = match byte {
+            0x00..=0x7f => return data.len(),
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        return if width > back { data.len() - back } else { data.len() };
+    }
+    data.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn header() -> CastHeader {
+        CastHeader {
+            cols: 80,
+            rows: 24,
+            shell: "/bin/zsh".to_string(),
+            term: "xterm-256color".to_string(),
+            theme: Theme::DARK,
+        }
+    }
+
+    fn read_cast(path: &Path) -> Vec<Value> {
+        let text = std::fs::read_to_string(path).unwrap();
+        text.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/1.cast");
+        Recorder::create(&path, RecordingOptions::default(), &header()).unwrap().finish().unwrap();
+
+        let lines = read_cast(&path);
+        assert_eq!(lines.len(), 1);
+        let header = &lines[0];
+        assert_eq!(header["version"], 2);
+        assert_eq!((header["width"].as_u64(), header["height"].as_u64()), (Some(80), Some(24)));
+        assert_eq!(header["env"]["SHELL"], "/bin/zsh");
+        assert_eq!(header["theme"]["bg"], "#1e1e1e");
+        assert_eq!(header["theme"]["palette"].as_str().unwrap().split(':').count(), 16);
+    }
+
+    #[test]
+    fn test_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.cast");
+        let mut recorder = Recorder::create(&path, RecordingOptions { record_input: true }, &header()).unwrap();
+        recorder.output(b"$ ").unwrap();
+        recorder.input(b"ls\r").unwrap();
+        recorder.resize(100, 30).unwrap();
+        // "é" split across two chunks
+        recorder.output(b"caf\xc3").unwrap();
+        recorder.output(b"\xa9\r\n").unwrap();
+        recorder.finish().unwrap();
+
+        let events: Vec<(String, String)> = read_cast(&path)[1..]
+            .iter()
+            .map(|event| (event[1].as_str().unwrap().to_string(), event[2].as_str().unwrap().to_string()))
+            .collect();
+        let expected = [("o", "$ "), ("i", "ls\r"), ("r", "100x30"), ("o", "caf"), ("o", "é\r\n")];
+        assert_eq!(events, expected.map(|(code, data)| (code.to_string(), data.to_string())));
+
+        let times: Vec<f64> = read_cast(&path)[1..].iter().map(|event| event[0].as_f64().unwrap()).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_events_are_buffered_until_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.cast");
+        let mut recorder = Recorder::create(&path, RecordingOptions::default(), &header()).unwrap();
+        assert_eq!(recorder.flush_due(), None);
+        recorder.output(b"$ ").unwrap();
+        assert_eq!(read_cast(&path).len(), 1);
+        assert!(recorder.flush_due().is_some());
+
+        recorder.flush().unwrap();
+        assert_eq!(read_cast(&path).len(), 2);
+        assert_eq!(recorder.flush_due(), None);
+    }
+
+    #[test]
+    fn test_input_skipped_unless_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.cast");
+        let mut recorder = Recorder::create(&path, RecordingOptions::default(), &header()).unwrap();
+        recorder.input(b"secret\r").unwrap();
+        recorder.finish().unwrap();
+        assert_eq!(read_cast(&path).len(), 1);
+    }
+
+    #[test]
+    fn test_utf8_stream() {
+        let mut stream = Utf8Stream::default();
+        assert_eq!(stream.decode(b"\xe6\x97"), "");
+        assert_eq!(stream.decode(b"\xa5x"), "日x");
+        assert_eq!(stream.decode(b"\xffy"), "\u{fffd}y");
+        assert_eq!(complete_len(b"ab\xf0\x9f\x98"), 2);
+        assert_eq!(complete_len(b"ab\xf0\x9f\x98\x80"), 6);
+    }
+
+    #[test]
+    fn test_recording_path() {
+        let path = recording_path(Path::new("/recordings"), 7);
+        assert_eq!(path.parent(), Some(Path::new("/recordings/7")));
+        assert_eq!(path.extension().and_then(|ext| ext.to_str()), Some("cast"));
+    }
+}
@@ -0,0 +1,344 @@
+// Playback of asciicast v2 recordings as pseudo-sessions
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Invalid JSON on line {line}: {source}")]
+    Json { line: usize, source: serde_json::Error },
+    #[error("Invalid asciicast header: {0}")]
+    InvalidHeader(String),
+    #[error("Invalid event on line {line}")]
+    InvalidEvent { line: usize },
+}
+
+/// How fast recorded time passes during playback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// As recorded, apart from the header's `idle_time_limit`
+    Original,
+    /// Recorded time divided by the factor; 2.0 plays twice as fast
+    Scaled(f64),
+    /// Everything as fast as the session takes it
+    Max,
+}
+
+impl ReplaySpeed {
+    /// Recorded seconds per real second
+    fn rate(self) -> f64 {
+        match self {
+            ReplaySpeed::Original => 1.0,
+            ReplaySpeed::Scaled(factor) if factor > 0.0 => factor,
+            ReplaySpeed::Scaled(_) => 1.0,
+            ReplaySpeed::Max => f64::INFINITY,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub speed: ReplaySpeed,
+    /// Open paused at the start, e.g. to seek first
+    pub start_paused: bool,
+    /// Maintain a screen model, as `PtyConfig::track_screen`
+    pub track_screen: bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self { speed: ReplaySpeed::Original, start_paused: false, track_screen: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CastEvent {
+    Output(String),
+    Input(String),
+    Resize { cols: u16, rows: u16 },
+}
+
+/// A parsed asciicast v2 recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cast {
+    pub cols: u16,
+    pub rows: u16,
+    /// Longest pause kept during playback, in seconds
+    pub idle_time_limit: Option<f64>,
+    /// Events with their time in seconds, in order
+    pub events: Vec<(f64, CastEvent)>,
+}
+
+impl Cast {
+    /// Parses a recording. Marker events and unknown event types are skipped.
+    pub fn parse(text: &str) -> Result<Cast, ReplayError> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let (_, header) = lines.next().ok_or_else(|| ReplayError::InvalidHeader("empty file".to_string()))?;
+        let header: Value = serde_json::from_str(header).map_err(|source| ReplayError::Json { line: 1, source })?;
+        if header["version"].as_u64() != Some(2) {
+            return Err(ReplayError::InvalidHeader(format!("unsupported version {}", header["version"])));
+        }
+        let size = |key: &str| {
+            header[key]
+                .as_u64()
+                .and_then(|value| u16::try_from(value).ok())
+                .filter(|&value| value > 0)
+                .ok_or_else(|| ReplayError::InvalidHeader(format!("missing {}", key)))
+        };
+        let (cols, rows) = (size("width")?, size("height")?);
+        let idle_time_limit = header["idle_time_limit"].as_f64().filter(|&limit| limit > 0.0);
+
+        let mut events = Vec::new();
+        let mut last = 0.0;
+        for (index, line) in lines {
+            let line_number = index + 1;
+            let event: Value = serde_json::from_str(line).map_err(|source| ReplayError::Json { line: line_number, source })?;
+            let invalid = || ReplayError::InvalidEvent { line: line_number };
+            let (Some(time), Some(code), Some(data)) = (event[0].as_f64(), event[1].as_str(), event[2].as_str()) else {
+                return Err(invalid());
+            };
+            // Times are played back as durations, so must fit in one
+            if !(0.0..=Duration::MAX.as_secs_f64()).contains(&time) {
+                return Err(invalid());
+            }
+            let event = match code {
+                "o" => CastEvent::Output(data.to_string()),
+                "i" => CastEvent::Input(data.to_string()),
+                "r" => {
+                    let (cols, rows) = data.split_once('x').ok_or_else(invalid)?;
+                    let cols = cols.parse().ok().filter(|&cols| cols > 0).ok_or_else(invalid)?;
+                    let rows = rows.parse().ok().filter(|&rows| rows > 0).ok_or_else(invalid)?;
+                    CastEvent::Resize { cols, rows }
+                }
+                _ => continue,
+            };
+            // Some writers emit slightly out of order times; never go backwards
+            last = time.max(last);
+            events.push((last, event));
+        }
+        Ok(Cast { cols, rows, idle_time_limit, events })
+    }
+
+    /// Time of the last event, in seconds
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |(time, _)| *time)
+    }
+}
+
+/// Where playback is in a cast
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayStatus {
+    pub position: Duration,
+    pub duration: Duration,
+    pub paused: bool,
+    pub speed: ReplaySpeed,
+    /// Every event has been played
+    pub finished: bool,
+}
+
+/// Playback position, shared between the replay task and status queries
+#[derive(Debug)]
+pub(crate) struct Timeline {
+    cast: Cast,
+    /// Index of the next event to play
+    next: usize,
+    /// Recorded time reached, in seconds, as of `updated_at`
+    position: f64,
+    updated_at: Instant,
+    paused: bool,
+    speed: ReplaySpeed,
+}
+
+impl Timeline {
+    pub(crate) fn new(cast: Cast, options: &ReplayOptions) -> Self {
+        Self {
+            cast,
+            next: 0,
+            position: 0.0,
+            updated_at: Instant::now(),
+            paused: options.start_paused,
+            speed: options.speed,
+        }
+    }
+
+    pub(crate) fn cast(&self) -> &Cast {
+        &self.cast
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.next == self.cast.events.len()
+    }
+
+    /// Recorded time now, moving on while playing up to the next event
+    fn current(&self) -> f64 {
+        if self.paused || self.is_finished() {
+            return self.position;
+        }
+        let advanced = self.position + self.updated_at.elapsed().as_secs_f64() * self.speed.rate();
+        advanced.min(self.cast.events[self.next].0)
+    }
+
+    /// Fixes the current position before playback changes pace
+    fn settle(&mut self) {
+        self.position = self.current();
+        self.updated_at = Instant::now();
+    }
+
+    pub(crate) fn status(&self) -> ReplayStatus {
+        ReplayStatus {
+            position: Duration::from_secs_f64(self.current()),
+            duration: Duration::from_secs_f64(self.cast.duration()),
+            paused: self.paused,
+            speed: self.speed,
+            finished: self.is_finished(),
+        }
+    }
+
+    pub(crate) fn set_paused(&mut self, paused: bool) {
+        self.settle();
+        self.paused = paused;
+    }
+
+    pub(crate) fn set_speed(&mut self, speed: ReplaySpeed) {
+        self.settle();
+        self.speed = speed;
+    }
+
+    /// Real time until the next event is due, or `None` while paused or
+    /// finished. Pauses longer than the idle limit are shortened to it.
+    pub(crate) fn next_delay(&self) -> Option<Duration> {
+        if self.paused || self.is_finished() {
+            return None;
+        }
+        let mut gap = self.cast.events[self.next].0 - self.current();
+        if let Some(limit) = self.cast.idle_time_limit {
+            gap = gap.min(limit);
+        }
+        // A slow enough speed can stretch even a valid gap past `Duration::MAX`
+        Some(Duration::try_from_secs_f64((gap / self.speed.rate()).max(0.0)).unwrap_or(Duration::MAX))
+    }
+
+    /// Takes the next event, moving the position to it
+    pub(crate) fn advance(&mut self) -> Option<CastEvent> {
+        let (time, event) = self.cast.events.get(self.next)?.clone();
+        self.next += 1;
+        self.position = time;
+        self.updated_at = Instant::now();
+        Some(event)
+    }
+
+    /// Moves to `target`. Returns whether playback has to restart from the
+    /// beginning, followed by the events to play at once to get there.
+    pub(crate) fn seek(&mut self, target: Duration) -> (bool, Vec<CastEvent>) {
+        let target = target.as_secs_f64().min(self.cast.duration());
+        let restart = target < self.current();
+        if restart {
+            self.next = 0;
+        }
+        let mut events = Vec::new();
+        while let Some((time, event)) = self.cast.events.get(self.next) {
+            if *time > target {
+                break;
+            }
+            events.push(event.clone());
+            self.next += 1;
+        }
+        self.position = target;
+        self.updated_at = Instant::now();
+        (restart, events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAST: &str = concat!(
+        "{\"version\": 2, \"width\": 80, \"height\": 24, \"idle_time_limit\": 2.0}\n",
+        "[0.5, \"o\", \"$ \"]\n",
+        "[1.0, \"i\", \"ls\\r\"]\n",
+        "[1.5, \"m\", \"marker\"]\n",
+        "\n",
+        "[2.0, \"r\", \"100x30\"]\n",
+        "[10.0, \"o\", \"done\\r\\n\"]\n",
+    );
+
+    fn timeline(speed: ReplaySpeed, start_paused: bool) -> Timeline {
+        let options = ReplayOptions { speed, start_paused, ..ReplayOptions::default() };
+        Timeline::new(Cast::parse(CAST).unwrap(), &options)
+    }
+
+    #[test]
+    fn test_parse() {
+        let cast = Cast::parse(CAST).unwrap();
+        assert_eq!((cast.cols, cast.rows, cast.idle_time_limit), (80, 24, Some(2.0)));
+        assert_eq!(
+            cast.events,
+            [
+                (0.5, CastEvent::Output("$ ".to_string())),
+                (1.0, CastEvent::Input("ls\r".to_string())),
+                (2.0, CastEvent::Resize { cols: 100, rows: 30 }),
+                (10.0, CastEvent::Output("done\r\n".to_string())),
+            ]
+        );
+        assert_eq!(cast.duration(), 10.0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(Cast::parse(""), Err(ReplayError::InvalidHeader(_))));
+        assert!(matches!(Cast::parse("{\"version\": 1, \"width\": 80, \"height\": 24}"), Err(ReplayError::InvalidHeader(_))));
+        assert!(matches!(Cast::parse("{\"version\": 2, \"height\": 24}"), Err(ReplayError::InvalidHeader(_))));
+        let header = "{\"version\": 2, \"width\": 80, \"height\": 24}\n";
+        assert!(matches!(Cast::parse(&format!("{}[1.0, \"o\"\n", header)), Err(ReplayError::Json { line: 2, .. })));
+        assert!(matches!(Cast::parse(&format!("{}[1.0, \"r\", \"wide\"]\n", header)), Err(ReplayError::InvalidEvent { line: 2 })));
+        assert!(matches!(Cast::parse(&format!("{}[1e20, \"o\", \"$ \"]\n", header)), Err(ReplayError::InvalidEvent { line: 2 })));
+    }
+
+    #[test]
+    fn test_delays_follow_speed_and_idle_limit() {
+        let mut timeline = timeline(ReplaySpeed::Scaled(2.0), false);
+        assert!(timeline.next_delay().unwrap() <= Duration::from_millis(250));
+        timeline.advance();
+        timeline.advance();
+        timeline.advance();
+        // 8 recorded seconds, cut to the 2 second limit, at twice the speed
+        let delay = timeline.next_delay().unwrap();
+        assert!(delay <= Duration::from_secs(1) && delay > Duration::from_millis(900), "{:?}", delay);
+
+        assert_eq!(self::timeline(ReplaySpeed::Max, false).next_delay(), Some(Duration::ZERO));
+        assert_eq!(self::timeline(ReplaySpeed::Original, true).next_delay(), None);
+        assert_eq!(self::timeline(ReplaySpeed::Scaled(1e-300), false).next_delay(), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut timeline = timeline(ReplaySpeed::Original, true);
+        let (restart, events) = timeline.seek(Duration::from_secs(2));
+        assert!(!restart);
+        assert_eq!(events.len(), 3);
+        assert_eq!(timeline.status().position, Duration::from_secs(2));
+
+        // Going back replays from the start
+        let (restart, events) = timeline.seek(Duration::from_millis(600));
+        assert!(restart);
+        assert_eq!(events, [CastEvent::Output("$ ".to_string())]);
+
+        // Past the end stops at the end
+        let (_, events) = timeline.seek(Duration::from_secs(60));
+        assert_eq!(events.len(), 3);
+        assert!(timeline.status().finished);
+        assert_eq!(timeline.status().position, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_pause_holds_position() {
+        let mut timeline = timeline(ReplaySpeed::Original, false);
+        timeline.advance();
+        timeline.set_paused(true);
+        let position = timeline.status().position;
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(timeline.status().position, position);
+        assert!(timeline.status().paused);
+    }
+}
@@ -0,0 +1,1577 @@
+// Terminal screen model: applies parsed VT actions to a grid of cells
+use crate::modes::{for_each_private_mode, Modes};
+use crate::commands::{PendingMarks, PromptMark};
+use crate::queries::{PendingQueries, Query};
+use crate::scrollback::{Scrollback, ScrollbackLimits};
+use crate::shell_state::{ShellChanges, ShellState};
+use crate::vt::{Params, Parser, Perform};
+use crate::width::WidthConfig;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Default distance between tab stops
+const TAB_WIDTH: usize = 8;
+
+/// Zero-width characters kept per cell; further ones are dropped so a stream
+/// of combining marks can't grow a cell without bound
+const MAX_COMBINING: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Color {
+    #[default]
+    Default,
+    /// Palette index: 0-7 normal, 8-15 bright, 16-255 the xterm 256-color cube
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attrs {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
+}
+
+/// Colors and attributes applied to newly written cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Pen {
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attrs,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub c: char,
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attrs,
+    /// Columns taken by `c`: 2 for the left half of a wide character, 0 for a
+    /// placeholder holding its right half or padding a wide character that
+    /// didn't fit at the end of a wrapped row
+    pub width: u8,
+    /// Zero-width characters drawn with `c`: combining marks, variation
+    /// selectors and the rest of a joined emoji sequence
+    pub combining: Option<Box<str>>,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self::blank(Color::Default)
+    }
+}
+
+impl Cell {
+    /// Erased cell; erasing keeps the current background like xterm does
+    pub fn blank(bg: Color) -> Self {
+        Self { c: ' ', fg: Color::Default, bg, attrs: Attrs::default(), width: 1, combining: None }
+    }
+
+    fn with_pen(c: char, pen: Pen) -> Self {
+        Self { c, fg: pen.fg, bg: pen.bg, attrs: pen.attrs, width: 1, combining: None }
+    }
+
+    /// Holds the right half of the wide character in `left`
+    fn right_half(left: &Cell) -> Self {
+        Self { width: 0, ..Self::blank(left.bg) }
+    }
+
+    /// The character followed by anything drawn on it
+    pub fn text(&self) -> String {
+        let mut text = String::from(self.c);
+        text.push_str(self.combining.as_deref().unwrap_or_default());
+        text
+    }
+
+    /// Fills the end of a wrapped row a wide character didn't fit in
+    fn padding() -> Self {
+        Self { width: 0, ..Self::default() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub cells: Vec<Cell>,
+    /// The line continues on the next row because text wrapped at the margin
+    pub wrapped: bool,
+}
+
+impl Row {
+    fn new(cols: usize, bg: Color) -> Self {
+        Self { cells: vec![Cell::blank(bg); cols], wrapped: false }
+    }
+
+    /// Row contents with trailing blanks removed
+    pub fn text(&self) -> String {
+        self.text_in(0..self.cells.len()).trim_end_matches(' ').to_string()
+    }
+
+    /// Contents of columns `cols`, blanks included. Padding that ends a
+    /// wrapped row is left out since the line continues on the next row.
+    fn text_in(&self, cols: std::ops::Range<usize>) -> String {
+        let mut text = String::new();
+        for col in cols.start.min(self.cells.len())..cols.end.min(self.cells.len()) {
+            let cell = &self.cells[col];
+            if cell.width != 0 {
+                text.push_str(&cell.text());
+            } else if self.is_padding(col) && !self.wrapped {
+                text.push(' ');
+            }
+        }
+        text
+    }
+
+    fn is_blank(&self) -> bool {
+        self.cells.iter().all(|cell| *cell == Cell::default())
+    }
+
+    /// True if cell `col` only pads the row, as opposed to holding content or
+    /// the right half of a wide character
+    fn is_padding(&self, col: usize) -> bool {
+        self.cells[col].width == 0 && (col == 0 || self.cells[col - 1].width != 2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cursor {
+    pub row: usize,
+    pub col: usize,
+}
+
+/// State saved by DECSC / SCOSC and restored by DECRC / SCORC
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    cursor: Cursor,
+    pen: Pen,
+    pending_wrap: bool,
+    origin_mode: bool,
+}
+
+/// Visible terminal contents plus the state needed to keep applying output
+#[derive(Debug, Clone)]
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    /// Active buffer: the primary screen, or the alternate one while
+    /// `modes.alt_screen` is set
+    grid: Vec<Row>,
+    /// The inactive buffer
+    other_grid: Vec<Row>,
+    cursor: Cursor,
+    /// The last column was written; the next print wraps first
+    pending_wrap: bool,
+    pen: Pen,
+    /// Inclusive scroll region rows
+    scroll_top: usize,
+    scroll_bottom: usize,
+    tab_stops: Vec<bool>,
+    saved_cursor: Option<SavedCursor>,
+    modes: Modes,
+    last_printed: Option<char>,
+    width: WidthConfig,
+    queries: PendingQueries,
+    marks: PendingMarks,
+    shell: ShellState,
+    /// Lines scrolled off the top of the primary screen
+    scrollback: Scrollback,
+    /// Count of those lines, including ones since evicted or cleared. Row
+    /// `r` of the primary screen is absolute line `lines_scrolled + r`.
+    lines_scrolled: u64,
+    parser: Parser,
+}
+
+impl Screen {
+    pub fn new(rows: u16, cols: u16) -> Self {
+        Self::with_scrollback(rows, cols, ScrollbackLimits::default())
+    }
+
+    pub fn with_scrollback(rows: u16, cols: u16, limits: ScrollbackLimits) -> Self {
+        let rows = (rows as usize).max(1);
+        let cols = (cols as usize).max(1);
+        Self {
+            rows,
+            cols,
+            grid: blank_grid(rows, cols),
+            other_grid: blank_grid(rows, cols),
+            cursor: Cursor::default(),
+            pending_wrap: false,
+            pen: Pen::default(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            tab_stops: default_tab_stops(cols),
+            saved_cursor: None,
+            modes: Modes::default(),
+            last_printed: None,
+            width: WidthConfig::default(),
+            queries: PendingQueries::default(),
+            marks: PendingMarks::default(),
+            shell: ShellState::default(),
+            scrollback: Scrollback::new(limits),
+            lines_scrolled: 0,
+            parser: Parser::new(),
+        }
+    }
+
+    /// Parses `bytes` and applies them. Sequences split across calls are
+    /// completed by the next call.
+    pub fn advance(&mut self, bytes: &[u8]) {
+        let mut parser = std::mem::take(&mut self.parser);
+        parser.advance(self, bytes);
+        self.parser = parser;
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+
+    pub fn pen(&self) -> Pen {
+        self.pen
+    }
+
+    pub fn modes(&self) -> Modes {
+        self.modes
+    }
+
+    /// True while a full-screen application has switched to the alternate buffer
+    pub fn is_alt_screen(&self) -> bool {
+        self.modes.alt_screen
+    }
+
+    /// Carries over modes followed before the screen was attached. An active
+    /// alternate screen starts blank since its contents were never seen.
+    pub(crate) fn set_modes(&mut self, modes: Modes) {
+        self.modes = modes;
+    }
+
+    pub fn width_config(&self) -> WidthConfig {
+        self.width
+    }
+
+    /// Changes how characters printed from now on are measured
+    pub fn set_width_config(&mut self, config: WidthConfig) {
+        self.width = config;
+    }
+
+    /// Queries found in output since the last call, for the session to answer
+    pub fn take_queries(&mut self) -> Vec<Query> {
+        self.queries.take()
+    }
+
+    /// Shell integration marks found in output since the last call
+    pub fn take_marks(&mut self) -> Vec<PromptMark> {
+        self.marks.take()
+    }
+
+    /// Working directory and titles the shell has reported
+    pub fn shell_state(&self) -> &ShellState {
+        &self.shell
+    }
+
+    /// Carries over what the shell reported before the screen was attached
+    pub(crate) fn set_shell_state(&mut self, shell: ShellState) {
+        self.shell = shell;
+    }
+
+    /// Title and directory changes since the last call
+    pub fn take_shell_changes(&mut self) -> ShellChanges {
+        self.shell.take_changes()
+    }
+
+    /// Absolute line of the cursor, counting every line that has been on
+    /// the primary screen. Stable as output scrolls into scrollback.
+    pub fn cursor_line(&self) -> u64 {
+        self.lines_scrolled + self.cursor.row as u64
+    }
+
+    /// Primary screen row or scrollback line at absolute `line`, if still held
+    fn absolute_row(&self, line: u64) -> Option<&Row> {
+        let first = self.lines_scrolled.saturating_sub(self.scrollback.len() as u64);
+        let index = usize::try_from(line.checked_sub(first)?).ok()?;
+        match index.checked_sub(self.scrollback.len()) {
+            None => self.scrollback.line(index),
+            Some(row) => (if self.modes.alt_screen { &self.other_grid } else { &self.grid }).get(row),
+        }
+    }
+
+    /// Text from `start` up to `end`, both absolute (line, column) positions.
+    /// Rows joined by a soft wrap continue without a newline; lines no longer
+    /// held are skipped.
+    pub fn text_between(&self, start: (u64, usize), end: (u64, usize)) -> String {
+        let mut text = String::new();
+        for line in start.0..=end.0 {
+            let Some(row) = self.absolute_row(line) else { continue };
+            let from = if line == start.0 { start.1 } else { 0 };
+            let to = if line == end.0 { end.1 } else { row.cells.len() };
+            let part = row.text_in(from..to);
+            if row.wrapped && line != end.0 {
+                text.push_str(&part);
+            } else {
+                text.push_str(part.trim_end_matches(' '));
+                if line != end.0 {
+                    text.push('\n');
+                }
+            }
+        }
+        text
+    }
+
+    /// Inclusive top and bottom rows of the scroll region
+    pub fn scroll_region(&self) -> (usize, usize) {
+        (self.scroll_top, self.scroll_bottom)
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> Option<&Cell> {
+        self.grid.get(row).and_then(|r| r.cells.get(col))
+    }
+
+    pub fn row(&self, row: usize) -> Option<&Row> {
+        self.grid.get(row)
+    }
+
+    pub fn rows_iter(&self) -> impl Iterator<Item = &Row> {
+        self.grid.iter()
+    }
+
+    pub fn scrollback(&self) -> &Scrollback {
+        &self.scrollback
+    }
+
+    pub fn scrollback_mut(&mut self) -> &mut Scrollback {
+        &mut self.scrollback
+    }
+
+    /// The last `count` lines of scrollback followed by the visible screen,
+    /// ignoring blank rows below the cursor and the last written line
+    pub fn recent_lines(&self, count: usize) -> Vec<String> {
+        let used_rows = self
+            .grid
+            .iter()
+            .rposition(|row| !row.text().is_empty())
+            .map_or(0, |last| last + 1)
+            .max(self.cursor.row + 1);
+
+        let visible = self.grid[..used_rows].iter().map(Row::text);
+        let history = self.scrollback.iter().map(Row::text);
+        let total = self.scrollback.len() + used_rows;
+        history.chain(visible).skip(total.saturating_sub(count)).collect()
+    }
+
+    /// Visible text, one line per row with trailing blanks removed
+    pub fn text(&self) -> String {
+        self.grid.iter().map(Row::text).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Copy of every visible cell, row by row
+    pub fn cells(&self) -> Vec<Vec<Cell>> {
+        self.grid.iter().map(|row| row.cells.clone()).collect()
+    }
+
+    /// Changes the grid size. A new width rewraps soft-wrapped lines of the
+    /// primary screen and scrollback. When rows shrink, lines above the cursor
+    /// are dropped first so the cursor line stays visible.
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        let rows = (rows as usize).max(1);
+        let cols = (cols as usize).max(1);
+
+        if cols != self.cols {
+            self.reflow(cols);
+        }
+        if rows < self.rows {
+            let overflow = (self.cursor.row + 1).saturating_sub(rows);
+            let dropped: Vec<Row> = self.grid.drain(..overflow).collect();
+            if !self.modes.alt_screen {
+                self.lines_scrolled += dropped.len() as u64;
+                dropped.into_iter().for_each(|row| self.scrollback.push(row));
+            }
+            self.cursor.row -= overflow;
+        }
+        for grid in [&mut self.grid, &mut self.other_grid] {
+            grid.truncate(rows);
+            while grid.len() < rows {
+                grid.push(Row::new(cols, Color::Default));
+            }
+            for row in grid.iter_mut() {
+                row.cells.resize(cols, Cell::default());
+            }
+        }
+
+        self.tab_stops.resize(cols, false);
+        for col in self.cols..cols {
+            self.tab_stops[col] = col % TAB_WIDTH == 0;
+        }
+
+        self.rows = rows;
+        self.cols = cols;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.cursor.row = self.cursor.row.min(rows - 1);
+        self.cursor.col = self.cursor.col.min(cols - 1);
+        self.pending_wrap = false;
+    }
+
+    /// Rewraps the primary screen and scrollback to `cols`. The line at the
+    /// top of the screen stays there unless the cursor would fall off the
+    /// bottom, in which case more lines move into scrollback.
+    fn reflow(&mut self, cols: usize) {
+        let alt = self.modes.alt_screen;
+        let grid = std::mem::take(if alt { &mut self.other_grid } else { &mut self.grid });
+        // The cursor belongs to the alternate screen while it's active
+        let cursor = (!alt).then_some(self.cursor);
+        let history = self.scrollback.take_lines();
+        let history_len = history.len();
+        let first_line = self.lines_scrolled.saturating_sub(history_len as u64);
+
+        // Blank rows below the content and cursor are recreated afterwards
+        let used = grid
+            .iter()
+            .rposition(|row| !row.is_blank())
+            .map_or(0, |last| last + 1)
+            .max(cursor.map_or(0, |cursor| cursor.row + 1));
+
+        // Join rows into logical lines, dropping padding, and note the offsets
+        // of the screen's top-left cell and the cursor within them
+        let mut lines: Vec<Vec<Cell>> = Vec::new();
+        let mut top = None;
+        let mut cursor_at = None;
+        let mut continues = false;
+        for (index, row) in history.into_iter().chain(grid.into_iter().take(used)).enumerate() {
+            if !continues {
+                lines.push(Vec::new());
+            }
+            let line_index = lines.len() - 1;
+            let line = &mut lines[line_index];
+            if index == history_len {
+                top = Some((line_index, line.len()));
+            }
+            if let Some(cursor) = cursor.filter(|cursor| index == history_len + cursor.row) {
+                let before = (0..cursor.col.min(row.cells.len())).filter(|&col| !row.is_padding(col)).count();
+                // A pending wrap means the next character goes after the last one
+                cursor_at = Some((line_index, line.len() + before + usize::from(self.pending_wrap)));
+            }
+            line.extend((0..row.cells.len()).filter(|&col| !row.is_padding(col)).map(|col| row.cells[col].clone()));
+            continues = row.wrapped;
+        }
+
+        let mut rows = Vec::new();
+        let mut top_row = None;
+        let mut cursor_pos = None;
+        for (line_index, mut line) in lines.into_iter().enumerate() {
+            let content = line.iter().rposition(|cell| *cell != Cell::default()).map_or(0, |last| last + 1);
+            let keep = match cursor_at {
+                Some((cursor_line, offset)) if cursor_line == line_index => content.max(offset + 1),
+                _ => content,
+            };
+            line.resize(keep, Cell::default());
+
+            let first = rows.len();
+            let starts = rewrap(&line, cols, &mut rows);
+            let locate = |offset: usize| {
+                let row = starts.iter().rposition(|&start| start <= offset).unwrap_or(0);
+                (first + row, offset - starts[row])
+            };
+            if let Some((_, offset)) = top.filter(|&(top_line, _)| top_line == line_index) {
+                top_row = Some(locate(offset).0);
+            }
+            if let Some((_, offset)) = cursor_at.filter(|&(cursor_line, _)| cursor_line == line_index) {
+                cursor_pos = Some(locate(offset));
+            }
+        }
+
+        let mut top_row = top_row.unwrap_or(rows.len());
+        if let Some((row, _)) = cursor_pos {
+            top_row = top_row.max((row + 1).saturating_sub(self.rows));
+        }
+        let mut grid = rows.split_off(top_row.min(rows.len()));
+        // Lines before the oldest one held keep their numbers
+        self.lines_scrolled = first_line + rows.len() as u64;
+        rows.into_iter().for_each(|row| self.scrollback.push(row));
+        grid.truncate(self.rows);
+        grid.resize_with(self.rows, || Row::new(cols, Color::Default));
+
+        if let Some((row, col)) = cursor_pos {
+            self.cursor = Cursor { row: row - top_row, col };
+        }
+        *(if alt { &mut self.other_grid } else { &mut self.grid }) = grid;
+    }
+
+    fn print_char(&mut self, c: char) {
+        let clusters = self.width.grapheme_clusters || self.modes.grapheme_clusters;
+        if let Some(col) = self.previous_cell() {
+            let joins = if clusters {
+                let mut cluster = self.grid[self.cursor.row].cells[col].text();
+                cluster.push(c);
+                cluster.graphemes(true).nth(1).is_none()
+            } else {
+                self.width.char_width(c) == 0
+            };
+            if joins {
+                self.join_previous(col, c, clusters);
+                return;
+            }
+        }
+        let width = match self.width.char_width(c) {
+            // Nothing to draw it on
+            0 => return,
+            width => width.min(self.cols),
+        };
+
+        if self.pending_wrap && self.modes.autowrap {
+            self.grid[self.cursor.row].wrapped = true;
+            self.cursor.col = 0;
+            self.linefeed();
+        }
+        self.pending_wrap = false;
+
+        if self.cursor.col + width > self.cols {
+            // A wide character doesn't fit in the last column
+            if self.modes.autowrap {
+                let Cursor { row, col } = self.cursor;
+                self.split_wide(row, col);
+                self.grid[row].cells[col] = Cell::padding();
+                self.grid[row].wrapped = true;
+                self.cursor.col = 0;
+                self.linefeed();
+            } else {
+                self.cursor.col = self.cols - width;
+            }
+        }
+
+        let Cursor { row, col } = self.cursor;
+        self.split_wide(row, col);
+        let mut cell = Cell::with_pen(c, self.pen);
+        if width == 2 {
+            self.split_wide(row, col + 1);
+            self.grid[row].cells[col + 1] = Cell::right_half(&cell);
+            cell.width = 2;
+        }
+        self.grid[row].cells[col] = cell;
+        self.advance_cursor(col + width);
+        self.last_printed = Some(c);
+    }
+
+    /// Moves the cursor to `col` after printing, deferring the wrap when it's
+    /// past the right margin
+    fn advance_cursor(&mut self, col: usize) {
+        if col < self.cols {
+            self.cursor.col = col;
+        } else {
+            self.cursor.col = self.cols - 1;
+            self.pending_wrap = true;
+        }
+    }
+
+    /// Column of the last cell written before the cursor on its row, where
+    /// zero-width characters are drawn
+    fn previous_cell(&self) -> Option<usize> {
+        let col = if self.pending_wrap { self.cursor.col } else { self.cursor.col.checked_sub(1)? };
+        let cells = &self.grid[self.cursor.row].cells;
+        Some(if col > 0 && cells[col].width == 0 && cells[col - 1].width == 2 { col - 1 } else { col })
+    }
+
+    /// Draws `c` on the cell at `col` of the cursor row. With grapheme
+    /// clusters, a sequence that becomes wide, such as a character followed
+    /// by VS16, takes the next cell if the cursor is on it.
+    fn join_previous(&mut self, col: usize, c: char, clusters: bool) {
+        let row = self.cursor.row;
+        let cell = &mut self.grid[row].cells[col];
+        let mut combining = cell.combining.take().map(String::from).unwrap_or_default();
+        if combining.chars().count() < MAX_COMBINING {
+            combining.push(c);
+        }
+        cell.combining = Some(combining.into_boxed_str());
+
+        if clusters && cell.width == 1 && self.width.cluster_width(&cell.text()) == 2 && !self.pending_wrap && self.cursor.col == col + 1 {
+            cell.width = 2;
+            let right = Cell::right_half(cell);
+            self.split_wide(row, col + 1);
+            self.grid[row].cells[col + 1] = right;
+            self.advance_cursor(col + 2);
+        }
+    }
+
+    /// Blanks the other half of a wide character at `col` that's about to
+    /// lose one of its halves
+    fn split_wide(&mut self, row: usize, col: usize) {
+        let cells = &mut self.grid[row].cells;
+        match cells[col].width {
+            2 if col + 1 < cells.len() => cells[col + 1] = Cell::blank(cells[col + 1].bg),
+            0 if col > 0 && cells[col - 1].width == 2 => cells[col - 1] = Cell::blank(cells[col - 1].bg),
+            _ => {}
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell::blank(self.pen.bg)
+    }
+
+    fn blank_row(&self) -> Row {
+        Row::new(self.cols, self.pen.bg)
+    }
+
+    /// Moves down a line, scrolling the region at its bottom margin
+    fn linefeed(&mut self) {
+        if self.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.row > 0 {
+            self.cursor.row -= 1;
+        }
+    }
+
+    fn scroll_up(&mut self, count: usize) {
+        let count = count.min(self.scroll_bottom - self.scroll_top + 1);
+        // Only lines leaving the top of the primary screen are history; the
+        // alternate screen and partial regions just discard them
+        let keep = self.scroll_top == 0 && !self.modes.alt_screen;
+        for _ in 0..count {
+            let row = self.grid.remove(self.scroll_top);
+            if keep {
+                self.lines_scrolled += 1;
+                self.scrollback.push(row);
+            }
+            let blank = self.blank_row();
+            self.grid.insert(self.scroll_bottom, blank);
+        }
+    }
+
+    fn scroll_down(&mut self, count: usize) {
+        let count = count.min(self.scroll_bottom - self.scroll_top + 1);
+        for _ in 0..count {
+            self.grid.remove(self.scroll_bottom);
+            let blank = self.blank_row();
+            self.grid.insert(self.scroll_top, blank);
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        let (top, bottom) = if self.modes.origin { (self.scroll_top, self.scroll_bottom) } else { (0, self.rows - 1) };
+        self.cursor.row = (top + row).min(bottom);
+        self.cursor.col = col.min(self.cols - 1);
+        self.pending_wrap = false;
+    }
+
+    fn move_up(&mut self, count: usize) {
+        let limit = if self.cursor.row >= self.scroll_top { self.scroll_top } else { 0 };
+        self.cursor.row = self.cursor.row.saturating_sub(count).max(limit);
+        self.pending_wrap = false;
+    }
+
+    fn move_down(&mut self, count: usize) {
+        let limit = if self.cursor.row <= self.scroll_bottom { self.scroll_bottom } else { self.rows - 1 };
+        self.cursor.row = (self.cursor.row + count).min(limit);
+        self.pending_wrap = false;
+    }
+
+    fn move_right(&mut self, count: usize) {
+        self.cursor.col = (self.cursor.col + count).min(self.cols - 1);
+        self.pending_wrap = false;
+    }
+
+    fn move_left(&mut self, count: usize) {
+        self.cursor.col = self.cursor.col.saturating_sub(count);
+        self.pending_wrap = false;
+    }
+
+    fn tab_forward(&mut self, count: usize) {
+        for _ in 0..count {
+            self.cursor.col = (self.cursor.col + 1..self.cols)
+                .find(|&col| self.tab_stops[col])
+                .unwrap_or(self.cols - 1);
+        }
+        self.pending_wrap = false;
+    }
+
+    fn tab_backward(&mut self, count: usize) {
+        for _ in 0..count {
+            self.cursor.col = (0..self.cursor.col).rev().find(|&col| self.tab_stops[col]).unwrap_or(0);
+        }
+        self.pending_wrap = false;
+    }
+
+    fn erase_cells(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        let blank = self.blank();
+        let end = cols.end.min(self.cols);
+        let start = cols.start.min(end);
+        if start < end {
+            self.split_wide(row, start);
+            self.split_wide(row, end - 1);
+        }
+        for cell in &mut self.grid[row].cells[start..end] {
+            *cell = blank.clone();
+        }
+    }
+
+    fn erase_rows(&mut self, rows: std::ops::Range<usize>) {
+        for row in rows {
+            self.grid[row] = self.blank_row();
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            0 => {
+                self.erase_cells(row, col..self.cols);
+                self.grid[row].wrapped = false;
+                self.erase_rows(row + 1..self.rows);
+            }
+            1 => {
+                self.erase_rows(0..row);
+                self.erase_cells(row, 0..col + 1);
+            }
+            2 => self.erase_rows(0..self.rows),
+            3 => self.scrollback.clear(),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            0 => {
+                self.erase_cells(row, col..self.cols);
+                self.grid[row].wrapped = false;
+            }
+            1 => self.erase_cells(row, 0..col + 1),
+            2 => {
+                self.erase_cells(row, 0..self.cols);
+                self.grid[row].wrapped = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_lines(&mut self, count: usize) {
+        if !(self.scroll_top..=self.scroll_bottom).contains(&self.cursor.row) {
+            return;
+        }
+        let count = count.min(self.scroll_bottom - self.cursor.row + 1);
+        for _ in 0..count {
+            self.grid.remove(self.scroll_bottom);
+            let blank = self.blank_row();
+            self.grid.insert(self.cursor.row, blank);
+        }
+        self.cursor.col = 0;
+        self.pending_wrap = false;
+    }
+
+    fn delete_lines(&mut self, count: usize) {
+        if !(self.scroll_top..=self.scroll_bottom).contains(&self.cursor.row) {
+            return;
+        }
+        let count = count.min(self.scroll_bottom - self.cursor.row + 1);
+        for _ in 0..count {
+            self.grid.remove(self.cursor.row);
+            let blank = self.blank_row();
+            self.grid.insert(self.scroll_bottom, blank);
+        }
+        self.cursor.col = 0;
+        self.pending_wrap = false;
+    }
+
+    fn insert_chars(&mut self, count: usize) {
+        let blank = self.blank();
+        let Cursor { row, col } = self.cursor;
+        self.split_wide(row, col);
+        let cells = &mut self.grid[row].cells;
+        let count = count.min(cells.len() - col);
+        cells.truncate(cells.len() - count);
+        cells.splice(col..col, std::iter::repeat_n(blank, count));
+        // A wide character pushed halfway off the edge
+        if let Some(last) = cells.last_mut().filter(|cell| cell.width == 2) {
+            *last = Cell::blank(last.bg);
+        }
+        self.pending_wrap = false;
+    }
+
+    fn delete_chars(&mut self, count: usize) {
+        let blank = self.blank();
+        let Cursor { row, col } = self.cursor;
+        let count = count.min(self.cols - col);
+        self.split_wide(row, col);
+        self.split_wide(row, col + count - 1);
+        let cells = &mut self.grid[row].cells;
+        cells.drain(col..col + count);
+        cells.extend(std::iter::repeat_n(blank, count));
+        self.pending_wrap = false;
+    }
+
+    fn set_scroll_region(&mut self, params: &Params) {
+        let top = params.get_or(0, 1) as usize - 1;
+        let bottom = (params.get_or(1, self.rows as u16) as usize).min(self.rows) - 1;
+        if top < bottom {
+            self.scroll_top = top;
+            self.scroll_bottom = bottom;
+            self.move_to(0, 0);
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(SavedCursor {
+            cursor: self.cursor,
+            pen: self.pen,
+            pending_wrap: self.pending_wrap,
+            origin_mode: self.modes.origin,
+        });
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor.unwrap_or(SavedCursor {
+            cursor: Cursor::default(),
+            pen: Pen::default(),
+            pending_wrap: false,
+            origin_mode: false,
+        });
+        self.cursor.row = saved.cursor.row.min(self.rows - 1);
+        self.cursor.col = saved.cursor.col.min(self.cols - 1);
+        self.pen = saved.pen;
+        self.pending_wrap = saved.pending_wrap;
+        self.modes.origin = saved.origin_mode;
+    }
+
+    /// Switches between the primary and alternate buffers
+    fn set_alt_screen(&mut self, enabled: bool, clear: bool) {
+        if enabled == self.modes.alt_screen {
+            return;
+        }
+        if !enabled && clear {
+            self.erase_rows(0..self.rows);
+        }
+        std::mem::swap(&mut self.grid, &mut self.other_grid);
+        self.modes.alt_screen = enabled;
+        if enabled && clear {
+            self.erase_rows(0..self.rows);
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            47 => self.set_alt_screen(enabled, false),
+            1047 => self.set_alt_screen(enabled, true),
+            1048 if enabled => self.save_cursor(),
+            1048 => self.restore_cursor(),
+            1049 => {
+                if enabled {
+                    self.save_cursor();
+                    self.set_alt_screen(true, true);
+                } else {
+                    self.set_alt_screen(false, false);
+                    self.restore_cursor();
+                }
+            }
+            6 => {
+                self.modes.origin = enabled;
+                self.move_to(0, 0);
+            }
+            _ => {
+                self.modes.set_private(mode, enabled);
+            }
+        }
+    }
+
+    /// RIS: everything but the scrollback and titles returns to its initial
+    /// state
+    fn reset(&mut self) {
+        let scrollback = std::mem::take(&mut self.scrollback);
+        let queries = std::mem::take(&mut self.queries);
+        let marks = std::mem::take(&mut self.marks);
+        let shell = std::mem::take(&mut self.shell);
+        *self = Self {
+            width: self.width,
+            lines_scrolled: self.lines_scrolled,
+            ..Self::new(self.rows as u16, self.cols as u16)
+        };
+        self.scrollback = scrollback;
+        self.queries = queries;
+        self.marks = marks;
+        self.shell = shell;
+    }
+
+    /// 1-based cursor position as reported to applications, relative to the
+    /// scroll region in origin mode. A cursor restored outside the region is
+    /// reported at its nearest edge.
+    fn reported_cursor(&self) -> (usize, usize) {
+        let row = if self.modes.origin {
+            self.cursor.row.clamp(self.scroll_top, self.scroll_bottom) - self.scroll_top
+        } else {
+            self.cursor.row
+        };
+        (row + 1, self.cursor.col + 1)
+    }
+
+    fn set_graphic_rendition(&mut self, params: &Params) {
+        if params.is_empty() {
+            self.pen = Pen::default();
+            return;
+        }
+
+        let mut groups = params.iter();
+        while let Some(group) = groups.next() {
+            let attrs = &mut self.pen.attrs;
+            match group[0] {
+                0 => self.pen = Pen::default(),
+                1 => attrs.bold = true,
+                2 => attrs.dim = true,
+                3 => attrs.italic = true,
+                // 4:0 turns underline off; other styles all count as underline
+                4 => attrs.underline = group.get(1) != Some(&0),
+                7 => attrs.inverse = true,
+                8 => attrs.hidden = true,
+                9 => attrs.strikethrough = true,
+                21 => attrs.underline = true,
+                22 => {
+                    attrs.bold = false;
+                    attrs.dim = false;
+                }
+                23 => attrs.italic = false,
+                24 => attrs.underline = false,
+                27 => attrs.inverse = false,
+                28 => attrs.hidden = false,
+                29 => attrs.strikethrough = false,
+                code @ 30..=37 => self.pen.fg = Color::Indexed((code - 30) as u8),
+                38 => {
+                    if let Some(color) = extended_color(group, &mut groups) {
+                        self.pen.fg = color;
+                    }
+                }
+                39 => self.pen.fg = Color::Default,
+                code @ 40..=47 => self.pen.bg = Color::Indexed((code - 40) as u8),
+                48 => {
+                    if let Some(color) = extended_color(group, &mut groups) {
+                        self.pen.bg = color;
+                    }
+                }
+                49 => self.pen.bg = Color::Default,
+                code @ 90..=97 => self.pen.fg = Color::Indexed((code - 90 + 8) as u8),
+                code @ 100..=107 => self.pen.bg = Color::Indexed((code - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Perform for Screen {
+    fn print(&mut self, c: char) {
+        self.print_char(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            0x08 => self.move_left(1),
+            0x09 => self.tab_forward(1),
+            0x0A..=0x0C => {
+                self.linefeed();
+                self.pending_wrap = false;
+            }
+            0x0D => {
+                self.cursor.col = 0;
+                self.pending_wrap = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        // Only DSR 6 and DECXCPR report the cursor
+        let cursor = (action == 'n' && params.get(0) == Some(6)).then(|| self.reported_cursor());
+        self.queries.csi(params, intermediates, ignore, action, &self.modes, cursor);
+        self.shell.csi(params, intermediates, ignore, action);
+
+        let mut private_modes = Vec::new();
+        for_each_private_mode(params, intermediates, ignore, action, |mode, enabled| private_modes.push((mode, enabled)));
+        for (mode, enabled) in private_modes {
+            self.set_private_mode(mode, enabled);
+        }
+
+        // Other private (`?`, `>`, ...) and intermediate forms belong to other handlers
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+
+        let count = params.get_or(0, 1) as usize;
+        match action {
+            'A' => self.move_up(count),
+            'B' | 'e' => self.move_down(count),
+            'C' | 'a' => self.move_right(count),
+            'D' => self.move_left(count),
+            'E' => {
+                self.move_down(count);
+                self.cursor.col = 0;
+            }
+            'F' => {
+                self.move_up(count);
+                self.cursor.col = 0;
+            }
+            'G' | '`' => {
+                self.cursor.col = (count - 1).min(self.cols - 1);
+                self.pending_wrap = false;
+            }
+            'H' | 'f' => self.move_to(params.get_or(0, 1) as usize - 1, params.get_or(1, 1) as usize - 1),
+            'd' => {
+                let col = self.cursor.col;
+                self.move_to(count - 1, col);
+            }
+            'I' => self.tab_forward(count),
+            'Z' => self.tab_backward(count),
+            'J' => self.erase_display(params.get(0).unwrap_or(0)),
+            'K' => self.erase_line(params.get(0).unwrap_or(0)),
+            'L' => self.insert_lines(count),
+            'M' => self.delete_lines(count),
+            '@' => self.insert_chars(count),
+            'P' => self.delete_chars(count),
+            'X' => {
+                let Cursor { row, col } = self.cursor;
+                self.erase_cells(row, col..col + count);
+                self.pending_wrap = false;
+            }
+            'S' => self.scroll_up(count),
+            'T' => self.scroll_down(count),
+            'b' => {
+                if let Some(c) = self.last_printed {
+                    for _ in 0..count.min(self.rows * self.cols) {
+                        self.print_char(c);
+                    }
+                }
+            }
+            'g' => match params.get(0).unwrap_or(0) {
+                0 => self.tab_stops[self.cursor.col] = false,
+                3 => self.tab_stops.iter_mut().for_each(|stop| *stop = false),
+                _ => {}
+            },
+            'm' => self.set_graphic_rendition(params),
+            'r' => self.set_scroll_region(params),
+            's' if params.is_empty() => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+        if byte != b'c' && self.modes.apply_esc(byte) {
+            return;
+        }
+
+        match byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => {
+                self.linefeed();
+                self.pending_wrap = false;
+            }
+            b'E' => {
+                self.cursor.col = 0;
+                self.linefeed();
+                self.pending_wrap = false;
+            }
+            b'H' => self.tab_stops[self.cursor.col] = true,
+            b'M' => {
+                self.reverse_index();
+                self.pending_wrap = false;
+            }
+            b'c' => self.reset(),
+            _ => {}
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        self.queries.osc(params, bell_terminated);
+        self.shell.osc(params);
+        // A pending wrap puts the end of the line before the mark
+        let col = self.cursor.col + usize::from(self.pending_wrap);
+        self.marks.osc(params, Some((self.cursor_line(), col)));
+    }
+
+    fn hook(&mut self, _params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        self.queries.hook(intermediates, ignore, action);
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.queries.put(byte);
+    }
+
+    fn unhook(&mut self) {
+        self.queries.unhook();
+    }
+}
+
+/// Splits a logical line into rows of `cols` cells appended to `rows`,
+/// moving wide characters that would straddle the margin to the next row.
+/// Returns the line offset each new row starts at.
+fn rewrap(line: &[Cell], cols: usize, rows: &mut Vec<Row>) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut cells = Vec::with_capacity(cols);
+    let mut offset = 0;
+    while offset < line.len() {
+        let cell = &line[offset];
+        let wide = cell.width == 2 && line.get(offset + 1).is_some_and(|next| next.width == 0);
+        let span = if wide { 2 } else { 1 };
+        if cells.len() + span.min(cols) > cols {
+            cells.resize(cols, Cell::padding());
+            rows.push(Row { cells: std::mem::replace(&mut cells, Vec::with_capacity(cols)), wrapped: true });
+            starts.push(offset);
+        }
+        if wide && cols < 2 {
+            // Too narrow to ever fit; keep the character and drop its right half
+            cells.push(Cell { width: 1, ..cell.clone() });
+        } else {
+            cells.extend_from_slice(&line[offset..offset + span]);
+        }
+        offset += span;
+    }
+    cells.resize(cols, Cell::default());
+    rows.push(Row { cells, wrapped: false });
+    starts
+}
+
+fn blank_grid(rows: usize, cols: usize) -> Vec<Row> {
+    (0..rows).map(|_| Row::new(cols, Color::Default)).collect()
+}
+
+fn default_tab_stops(cols: usize) -> Vec<bool> {
+    (0..cols).map(|col| col % TAB_WIDTH == 0 && col != 0).collect()
+}
+
+/// Reads the color following SGR 38/48, in either the colon form
+/// (`38:5:n`, `38:2::r:g:b`, `38:2:r:g:b`) or the legacy semicolon form
+/// (`38;5;n`, `38;2;r;g;b`), consuming trailing groups for the latter.
+fn extended_color<'a>(group: &[u16], rest: &mut impl Iterator<Item = &'a [u16]>) -> Option<Color> {
+    let values: Vec<u16> = if group.len() > 1 {
+        group[1..].to_vec()
+    } else {
+        let kind = rest.next()?.first().copied()?;
+        let needed = match kind {
+            5 => 1,
+            2 => 3,
+            _ => return None,
+        };
+        let mut values = vec![kind];
+        for _ in 0..needed {
+            values.push(rest.next()?.first().copied()?);
+        }
+        values
+    };
+
+    let byte = |value: u16| value.min(255) as u8;
+    match values.as_slice() {
+        [5, index, ..] => Some(Color::Indexed(byte(*index))),
+        // The colon form may carry a color space id before the components
+        [2, _, r, g, b, ..] if group.len() > 5 => Some(Color::Rgb(byte(*r), byte(*g), byte(*b))),
+        [2, r, g, b, ..] => Some(Color::Rgb(byte(*r), byte(*g), byte(*b))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(rows: u16, cols: u16, bytes: &[u8]) -> Screen {
+        let mut screen = Screen::new(rows, cols);
+        screen.advance(bytes);
+        screen
+    }
+
+    #[test]
+    fn test_print_and_newlines() {
+        let screen = apply(3, 10, b"hello\r\nworld");
+        assert_eq!(screen.text(), "hello\nworld\n");
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 5 });
+    }
+
+    #[test]
+    fn test_autowrap_defers_until_next_print() {
+        let mut screen = apply(3, 4, b"abcd");
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 3 });
+        assert!(!screen.row(0).unwrap().wrapped);
+
+        screen.advance(b"e");
+        assert_eq!(screen.text(), "abcd\ne\n");
+        assert!(screen.row(0).unwrap().wrapped);
+
+        // A carriage return cancels the pending wrap
+        let screen = apply(3, 4, b"abcd\rX");
+        assert_eq!(screen.row(0).unwrap().text(), "Xbcd");
+    }
+
+    #[test]
+    fn test_scrolls_at_bottom_and_within_region() {
+        let screen = apply(3, 5, b"1\r\n2\r\n3\r\n4");
+        assert_eq!(screen.text(), "2\n3\n4");
+
+        // Region rows 2-3: row 1 stays put while the region scrolls
+        let screen = apply(4, 6, b"top\x1b[4;1Hbottom\x1b[2;3r\x1b[2;1Ha\r\nb\r\nc");
+        assert_eq!(screen.text(), "top\nb\nc\nbottom");
+    }
+
+    #[test]
+    fn test_cursor_movement_and_origin_clamping() {
+        let mut screen = Screen::new(5, 10);
+        screen.advance(b"\x1b[3;4H");
+        assert_eq!(screen.cursor(), Cursor { row: 2, col: 3 });
+        screen.advance(b"\x1b[10A\x1b[2C");
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 5 });
+        screen.advance(b"\x1b[99;99H");
+        assert_eq!(screen.cursor(), Cursor { row: 4, col: 9 });
+        screen.advance(b"\x1b[G\x1b[2d");
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 0 });
+    }
+
+    #[test]
+    fn test_erase_display_and_line() {
+        let mut screen = apply(3, 5, b"aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H");
+        screen.advance(b"\x1b[K");
+        assert_eq!(screen.text(), "aaaaa\nbb\nccccc");
+        screen.advance(b"\x1b[1K");
+        assert_eq!(screen.text(), "aaaaa\n\nccccc");
+        screen.advance(b"\x1b[J");
+        assert_eq!(screen.text(), "aaaaa\n\n");
+        screen.advance(b"\x1b[2J");
+        assert_eq!(screen.text(), "\n\n");
+    }
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut screen = apply(3, 6, b"abcdef\x1b[1;3H");
+        screen.advance(b"\x1b[2@");
+        assert_eq!(screen.row(0).unwrap().text(), "ab  cd");
+        screen.advance(b"\x1b[3P");
+        assert_eq!(screen.row(0).unwrap().text(), "abd");
+        screen.advance(b"\x1b[2X");
+        assert_eq!(screen.row(0).unwrap().text(), "ab");
+
+        let mut screen = apply(3, 3, b"1\r\n2\r\n3");
+        screen.advance(b"\x1b[2;1H\x1b[L");
+        assert_eq!(screen.text(), "1\n\n2");
+        screen.advance(b"\x1b[2M");
+        assert_eq!(screen.text(), "1\n\n");
+    }
+
+    #[test]
+    fn test_sgr_colors_and_attributes() {
+        let screen = apply(1, 10, b"\x1b[1;3;4;7;31;42ma\x1b[22;23;24;27;39;49mb");
+        let styled = screen.cell(0, 0).unwrap();
+        assert_eq!(styled.fg, Color::Indexed(1));
+        assert_eq!(styled.bg, Color::Indexed(2));
+        assert_eq!(
+            styled.attrs,
+            Attrs { bold: true, italic: true, underline: true, inverse: true, ..Attrs::default() }
+        );
+        assert_eq!(*screen.cell(0, 1).unwrap(), Cell { c: 'b', ..Cell::default() });
+
+        let screen = apply(1, 10, b"\x1b[95;38;5;208;48;2;1;2;3mx");
+        assert_eq!(screen.cell(0, 0).unwrap().fg, Color::Indexed(208));
+        assert_eq!(screen.cell(0, 0).unwrap().bg, Color::Rgb(1, 2, 3));
+
+        let screen = apply(1, 10, b"\x1b[38:2::10:20:30;48:5:17;4:0mx");
+        let cell = screen.cell(0, 0).unwrap();
+        assert_eq!((cell.fg, cell.bg, cell.attrs.underline), (Color::Rgb(10, 20, 30), Color::Indexed(17), false));
+
+        let screen = apply(1, 10, b"\x1b[1;100mx\x1b[mb");
+        assert_eq!(screen.cell(0, 0).unwrap().bg, Color::Indexed(8));
+        assert_eq!(screen.pen(), Pen::default());
+    }
+
+    #[test]
+    fn test_erase_uses_current_background() {
+        let screen = apply(2, 3, b"\x1b[44m\x1b[2J");
+        assert!(screen.cells().iter().flatten().all(|cell| cell.bg == Color::Indexed(4)));
+    }
+
+    #[test]
+    fn test_tab_stops() {
+        let mut screen = Screen::new(1, 30);
+        screen.advance(b"\t");
+        assert_eq!(screen.cursor().col, 8);
+        screen.advance(b"\x1b[3G\x1bH\x1b[G\t");
+        assert_eq!(screen.cursor().col, 2);
+        screen.advance(b"\x1b[3g\t");
+        assert_eq!(screen.cursor().col, 29);
+        screen.advance(b"\x1b[Z");
+        assert_eq!(screen.cursor().col, 0);
+    }
+
+    #[test]
+    fn test_save_and_restore_cursor() {
+        let mut screen = apply(3, 10, b"\x1b[2;5H\x1b[31m\x1b7\x1b[H\x1b[m");
+        screen.advance(b"\x1b8x");
+        assert_eq!(screen.cell(1, 4).unwrap().fg, Color::Indexed(1));
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 5 });
+
+        screen.advance(b"\x1b[3;1H\x1b[s\x1b[H\x1b[uy");
+        assert_eq!(screen.cell(2, 0).unwrap().c, 'y');
+    }
+
+    #[test]
+    fn test_reverse_index_and_reset() {
+        let mut screen = apply(3, 3, b"1\r\n2\r\n3\x1b[H\x1bM");
+        assert_eq!(screen.text(), "\n1\n2");
+        screen.advance(b"\x1b[31m\x1bc");
+        assert_eq!(screen.text(), "\n\n");
+        assert_eq!(screen.pen(), Pen::default());
+    }
+
+    #[test]
+    fn test_alternate_screen_preserves_primary() {
+        let mut screen = apply(3, 10, b"shell$ vim\x1b[1;3H");
+        screen.advance(b"\x1b[?1049h");
+        assert!(screen.is_alt_screen());
+        assert_eq!(screen.text(), "\n\n");
+
+        screen.advance(b"\x1b[Heditor\x1b[3;1H~");
+        assert_eq!(screen.text(), "editor\n\n~");
+
+        screen.advance(b"\x1b[?1049l");
+        assert!(!screen.is_alt_screen());
+        assert_eq!(screen.text(), "shell$ vim\n\n");
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 2 });
+    }
+
+    #[test]
+    fn test_screen_modes_affect_output() {
+        // DECAWM off: printing at the margin overwrites the last column
+        let screen = apply(2, 3, b"\x1b[?7labcd");
+        assert_eq!(screen.text(), "abd\n");
+        assert!(!screen.modes().autowrap);
+
+        // DECOM on: addressing is relative to the scroll region
+        let screen = apply(4, 3, b"\x1b[2;3r\x1b[?6h\x1b[1;1Hx");
+        assert_eq!(screen.text(), "\nx\n\n");
+
+        // 47 switches without clearing, so the old alternate contents return
+        let screen = apply(2, 5, b"\x1b[?47halt\x1b[?47lmain\x1b[?47h");
+        assert_eq!(screen.row(0).unwrap().text(), "alt");
+    }
+
+    #[test]
+    fn test_scrolled_lines_enter_scrollback() {
+        let mut screen = apply(2, 5, b"1\r\n2\r\n3\r\n4");
+        assert_eq!(screen.scrollback().iter().map(Row::text).collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(screen.recent_lines(3), ["2", "3", "4"]);
+        assert_eq!(screen.recent_lines(10), ["1", "2", "3", "4"]);
+
+        screen.advance(b"\x1b[3J");
+        assert!(screen.scrollback().is_empty());
+    }
+
+    #[test]
+    fn test_alt_screen_and_regions_keep_scrollback_clean() {
+        let screen = apply(2, 5, b"keep\x1b[?1049h1\r\n2\r\n3\r\n4\x1b[?1049l");
+        assert!(screen.scrollback().is_empty());
+        assert_eq!(screen.text(), "keep\n");
+
+        let screen = apply(3, 5, b"top\x1b[2;3r\x1b[2;1H1\r\n2\r\n3");
+        assert!(screen.scrollback().is_empty());
+
+        let mut screen = Screen::with_scrollback(1, 5, ScrollbackLimits { max_lines: 1, ..ScrollbackLimits::default() });
+        screen.advance(b"a\r\nb\r\nc");
+        assert_eq!(screen.recent_lines(5), ["b", "c"]);
+    }
+
+    #[test]
+    fn test_resize_keeps_cursor_line_visible() {
+        let mut screen = apply(4, 5, b"1\r\n2\r\n3\r\n4");
+        screen.resize(2, 3);
+        assert_eq!(screen.text(), "3\n4");
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 1 });
+
+        screen.resize(3, 6);
+        assert_eq!(screen.text(), "3\n4\n");
+        assert_eq!(screen.scroll_region(), (0, 2));
+    }
+
+    #[test]
+    fn test_wide_chars_take_two_cells() {
+        let screen = apply(2, 5, "日本語".as_bytes());
+        assert_eq!(screen.text(), "日本\n語");
+        assert_eq!(screen.row(0).unwrap().cells[4], Cell::padding());
+        assert!(screen.row(0).unwrap().wrapped);
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 2 });
+
+        // Exactly filling the row defers the wrap like a narrow character
+        let screen = apply(2, 4, "日本".as_bytes());
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 3 });
+        assert!(screen.pending_wrap);
+    }
+
+    #[test]
+    fn test_overwriting_half_of_a_wide_char() {
+        let screen = apply(1, 6, "日本\x1b[2Gx".as_bytes());
+        assert_eq!(screen.text(), " x本");
+        let screen = apply(1, 6, "日本\x1b[3Gx".as_bytes());
+        assert_eq!(screen.text(), "日x");
+        assert_eq!(screen.row(0).unwrap().cells[3].width, 1);
+
+        let screen = apply(1, 6, "日本\x1b[2G\x1b[K".as_bytes());
+        assert_eq!(screen.text(), "");
+        let screen = apply(1, 6, "ab日\x1b[1G\x1b[3@".as_bytes());
+        assert_eq!(screen.text(), "   ab");
+    }
+
+    #[test]
+    fn test_zero_width_chars_join_the_previous_cell() {
+        let screen = apply(1, 10, "cafe\u{301} 日\u{302}!".as_bytes());
+        assert_eq!(screen.text(), "cafe\u{301} 日\u{302}!");
+        assert_eq!(screen.cell(0, 3).unwrap().combining.as_deref(), Some("\u{301}"));
+        assert_eq!(screen.cursor().col, 8);
+
+        // Nothing to draw on at the start of a line
+        let screen = apply(1, 10, "\u{301}a".as_bytes());
+        assert_eq!(screen.text(), "a");
+    }
+
+    #[test]
+    fn test_emoji_sequences_and_grapheme_mode() {
+        let family = "👨\u{200D}👩\u{200D}👧";
+        // Like wcwidth, the parts take their own cells by default
+        let screen = apply(1, 10, format!("{}|", family).as_bytes());
+        assert_eq!(screen.cursor().col, 7);
+        assert_eq!(screen.text(), format!("{}|", family));
+
+        let screen = apply(1, 10, format!("\x1b[?2027h{}|☀\u{FE0F}|", family).as_bytes());
+        assert_eq!(screen.cell(0, 0).unwrap().text(), family);
+        assert_eq!(screen.cell(0, 3).unwrap().width, 2);
+        assert_eq!(screen.cursor().col, 6);
+        assert_eq!(screen.text(), format!("{}|☀\u{FE0F}|", family));
+
+        let mut screen = Screen::new(1, 10);
+        screen.set_width_config(WidthConfig { emoji_wide: false, ..WidthConfig::default() });
+        screen.advance("🔥ok".as_bytes());
+        assert_eq!(screen.cursor().col, 3);
+    }
+
+    #[test]
+    fn test_cursor_reports_follow_origin_mode() {
+        let mut screen = apply(5, 10, b"\x1b[3;4H\x1b[6n\x1b[2;5r\x1b[?6h\x1b[2;3H\x1b[6n\x1b[?6$p");
+        assert_eq!(
+            screen.take_queries(),
+            [
+                Query::CursorPosition { row: 3, col: 4, private: false },
+                Query::CursorPosition { row: 2, col: 3, private: false },
+                Query::Mode { mode: 6, private: true, state: Some(true) },
+            ]
+        );
+        assert!(screen.take_queries().is_empty());
+
+        // The cursor saved at the top of the screen is above the region
+        let mut screen = apply(12, 20, b"\x1b[?6h\x1b7\x1b[5;10r\x1b8\x1b[6n");
+        assert_eq!(screen.take_queries(), [Query::CursorPosition { row: 1, col: 1, private: false }]);
+    }
+
+    #[test]
+    fn test_reflow_rewraps_soft_wrapped_lines() {
+        let mut screen = apply(3, 10, b"abcdefghijkl");
+        screen.resize(3, 4);
+        assert_eq!(screen.scrollback().line_text(0).as_deref(), Some("abcd"));
+        assert_eq!(screen.text(), "efgh\nijkl\n");
+        assert_eq!(screen.cursor(), Cursor { row: 2, col: 0 });
+
+        // Widening joins the line again, pulling its start back out of scrollback
+        screen.resize(3, 10);
+        assert!(screen.scrollback().is_empty());
+        assert_eq!(screen.text(), "abcdefghij\nkl\n");
+        assert!(screen.row(0).unwrap().wrapped);
+        assert_eq!(screen.cursor(), Cursor { row: 1, col: 2 });
+    }
+
+    #[test]
+    fn test_reflow_keeps_hard_line_breaks() {
+        let mut screen = apply(4, 6, b"abc\r\ndefgh\r\n$ ");
+        screen.resize(4, 3);
+        assert_eq!(screen.text(), "abc\ndef\ngh\n$");
+        assert_eq!(screen.cursor(), Cursor { row: 3, col: 2 });
+
+        screen.resize(4, 6);
+        assert_eq!(screen.text(), "abc\ndefgh\n$\n");
+        assert_eq!(screen.cursor(), Cursor { row: 2, col: 2 });
+    }
+
+    #[test]
+    fn test_reflow_resolves_pending_wrap() {
+        let mut screen = apply(2, 5, b"abcde");
+        screen.resize(2, 10);
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 5 });
+        screen.advance(b"f");
+        assert_eq!(screen.text(), "abcdef\n");
+    }
+
+    #[test]
+    fn test_reflow_moves_straddling_wide_chars() {
+        let mut screen = apply(3, 6, "abあcde".as_bytes());
+        let widths = |screen: &Screen, row: usize| screen.row(row).unwrap().cells.iter().map(|cell| cell.width).collect::<Vec<_>>();
+        assert_eq!(widths(&screen, 0), [1, 1, 2, 0, 1, 1]);
+
+        screen.resize(3, 3);
+        assert_eq!(screen.text(), "ab\nあc\nde");
+        assert_eq!(screen.row(0).unwrap().cells[2], Cell::padding());
+        assert_eq!(widths(&screen, 1), [2, 0, 1]);
+        assert_eq!(screen.cursor(), Cursor { row: 2, col: 2 });
+
+        // The padding disappears when the line has room again
+        screen.resize(3, 6);
+        assert_eq!(screen.text(), "abあcd\ne\n");
+        assert_eq!(widths(&screen, 0), [1, 1, 2, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_reflow_while_alt_screen_active() {
+        let mut screen = apply(3, 4, b"abcdef\x1b[?47h\x1b[Hxy");
+        screen.resize(3, 8);
+        assert_eq!(screen.text(), "xy\n\n");
+        assert_eq!(screen.cursor(), Cursor { row: 0, col: 2 });
+
+        screen.advance(b"\x1b[?47l");
+        assert_eq!(screen.text(), "abcdef\n\n");
+    }
+
+    #[test]
+    fn test_absolute_lines_survive_scrolling() {
+        let mut screen = apply(2, 10, b"one\r\ntwo");
+        let start = (screen.cursor_line(), 0);
+        screen.advance(b"\r\nthree\r\nfour");
+        assert_eq!(screen.cursor_line(), 3);
+        assert_eq!(screen.text_between(start, (screen.cursor_line(), 4)), "two\nthree\nfour");
+
+        // Cleared scrollback is skipped and later lines keep their numbers
+        screen.advance(b"\x1b[3J");
+        assert_eq!(screen.text_between((0, 0), (3, 10)), "three\nfour");
+    }
+
+    #[test]
+    fn test_text_between_joins_wrapped_rows() {
+        let screen = apply(3, 4, b"$ abcdef\r\nok");
+        assert_eq!(screen.text_between((0, 2), (2, 0)), "abcdef\n");
+        assert_eq!(screen.text_between((0, 2), (1, 2)), "abcd");
+        assert_eq!(screen.text_between((1, 2), (2, 2)), "ef\nok");
+    }
+
+    #[test]
+    fn test_reflow_keeps_absolute_lines() {
+        let mut screen = apply(2, 4, b"abcdef\r\nxy\r\nz");
+        assert_eq!(screen.cursor_line(), 3);
+        screen.resize(2, 8);
+        assert_eq!(screen.text(), "xy\nz");
+        assert_eq!(screen.cursor_line(), 2);
+        assert_eq!(screen.text_between((0, 0), (2, 1)), "abcdef\nxy\nz");
+    }
+}
@@ -0,0 +1,211 @@
+// Bounded history of lines scrolled off the top of the primary screen
+use crate::screen::{Cell, Row};
+use std::collections::VecDeque;
+
+pub const DEFAULT_SCROLLBACK_LINES: usize = 10_000;
+pub const DEFAULT_SCROLLBACK_BYTES: usize = 16 * 1024 * 1024;
+
+/// Caps on a scrollback buffer; whichever is hit first evicts the oldest lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrollbackLimits {
+    pub max_lines: usize,
+    /// Approximate heap memory, as reported by `Scrollback::memory_usage`
+    pub max_bytes: usize,
+}
+
+impl Default for ScrollbackLimits {
+    fn default() -> Self {
+        Self { max_lines: DEFAULT_SCROLLBACK_LINES, max_bytes: DEFAULT_SCROLLBACK_BYTES }
+    }
+}
+
+impl ScrollbackLimits {
+    /// Keeps no history at all
+    pub fn disabled() -> Self {
+        Self { max_lines: 0, max_bytes: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scrollback {
+    lines: VecDeque<Row>,
+    limits: ScrollbackLimits,
+    bytes: usize,
+    /// Lines evicted over the buffer's lifetime
+    evicted: u64,
+}
+
+impl Scrollback {
+    pub fn new(limits: ScrollbackLimits) -> Self {
+        Self { lines: VecDeque::new(), limits, bytes: 0, evicted: 0 }
+    }
+
+    pub fn limits(&self) -> ScrollbackLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: ScrollbackLimits) {
+        self.limits = limits;
+        self.enforce_limits();
+    }
+
+    /// Appends a line that scrolled off the screen. Trailing blank cells of
+    /// lines that end there are dropped to save memory; readers treat missing
+    /// cells as blank. Soft-wrapped rows keep them so rewrapping sees the
+    /// spaces that reached the margin.
+    pub fn push(&mut self, mut row: Row) {
+        if self.limits.max_lines == 0 {
+            self.evicted += 1;
+            return;
+        }
+
+        if !row.wrapped {
+            let len = row.cells.iter().rposition(|cell| *cell != Cell::default()).map_or(0, |last| last + 1);
+            row.cells.truncate(len);
+        }
+        row.cells.shrink_to_fit();
+
+        self.bytes += row_bytes(&row);
+        self.lines.push_back(row);
+        self.enforce_limits();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Line `index`, counting from the oldest line still held
+    pub fn line(&self, index: usize) -> Option<&Row> {
+        self.lines.get(index)
+    }
+
+    /// Text of line `index` with trailing blanks removed
+    pub fn line_text(&self, index: usize) -> Option<String> {
+        self.line(index).map(Row::text)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Row> + ExactSizeIterator {
+        self.lines.iter()
+    }
+
+    /// Approximate heap memory held by the stored lines
+    pub fn memory_usage(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of lines dropped because of the limits
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes every line, oldest first, so they can be rewrapped and pushed back
+    pub(crate) fn take_lines(&mut self) -> Vec<Row> {
+        self.bytes = 0;
+        self.lines.drain(..).collect()
+    }
+
+    fn enforce_limits(&mut self) {
+        while self.lines.len() > self.limits.max_lines || (self.bytes > self.limits.max_bytes && !self.lines.is_empty()) {
+            if let Some(row) = self.lines.pop_front() {
+                self.bytes -= row_bytes(&row);
+                self.evicted += 1;
+            }
+        }
+    }
+}
+
+fn row_bytes(row: &Row) -> usize {
+    let combining: usize = row.cells.iter().filter_map(|cell| cell.combining.as_ref()).map(|text| text.len()).sum();
+    std::mem::size_of::<Row>() + row.cells.capacity() * std::mem::size_of::<Cell>() + combining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::Color;
+
+    fn row(text: &str, cols: usize) -> Row {
+        let mut cells = vec![Cell::default(); cols];
+        for (cell, c) in cells.iter_mut().zip(text.chars()) {
+            cell.c = c;
+        }
+        Row { cells, wrapped: false }
+    }
+
+    #[test]
+    fn test_lines_are_indexed_oldest_first() {
+        let mut scrollback = Scrollback::new(ScrollbackLimits::default());
+        for text in ["one", "two", "three"] {
+            scrollback.push(row(text, 10));
+        }
+        assert_eq!(scrollback.len(), 3);
+        assert_eq!(scrollback.line_text(0).as_deref(), Some("one"));
+        assert_eq!(scrollback.line_text(2).as_deref(), Some("three"));
+        assert_eq!(scrollback.line_text(3), None);
+    }
+
+    #[test]
+    fn test_line_cap_evicts_oldest() {
+        let mut scrollback = Scrollback::new(ScrollbackLimits { max_lines: 2, ..ScrollbackLimits::default() });
+        for text in ["a", "b", "c", "d"] {
+            scrollback.push(row(text, 4));
+        }
+        assert_eq!(scrollback.iter().map(Row::text).collect::<Vec<_>>(), ["c", "d"]);
+        assert_eq!(scrollback.evicted(), 2);
+    }
+
+    #[test]
+    fn test_byte_cap_evicts_oldest() {
+        let one_line = row_bytes(&Row { cells: vec![Cell::default(); 3], wrapped: false });
+        let mut scrollback = Scrollback::new(ScrollbackLimits { max_lines: 100, max_bytes: one_line * 2 });
+        for text in ["aaa", "bbb", "ccc"] {
+            scrollback.push(row(text, 80));
+        }
+        assert_eq!(scrollback.len(), 2);
+        assert_eq!(scrollback.memory_usage(), one_line * 2);
+        assert_eq!(scrollback.line_text(0).as_deref(), Some("bbb"));
+    }
+
+    #[test]
+    fn test_trailing_blanks_are_not_stored() {
+        let mut scrollback = Scrollback::new(ScrollbackLimits::default());
+        scrollback.push(row("hi", 200));
+        assert_eq!(scrollback.line(0).unwrap().cells.len(), 2);
+
+        // A colored blank is content, not padding
+        let mut colored = row("", 5);
+        colored.cells[3].bg = Color::Indexed(1);
+        scrollback.push(colored);
+        assert_eq!(scrollback.line(1).unwrap().cells.len(), 4);
+
+        // Spaces that reached the margin of a wrapped row are part of the line
+        scrollback.push(Row { wrapped: true, ..row("x", 6) });
+        assert_eq!(scrollback.line(2).unwrap().cells.len(), 6);
+
+        scrollback.clear();
+        assert_eq!((scrollback.len(), scrollback.memory_usage()), (0, 0));
+    }
+
+    #[test]
+    fn test_disabled_and_shrunk_limits() {
+        let mut scrollback = Scrollback::new(ScrollbackLimits::disabled());
+        scrollback.push(row("gone", 4));
+        assert!(scrollback.is_empty());
+
+        let mut scrollback = Scrollback::new(ScrollbackLimits::default());
+        for index in 0..10 {
+            scrollback.push(row(&index.to_string(), 2));
+        }
+        scrollback.set_limits(ScrollbackLimits { max_lines: 3, ..ScrollbackLimits::default() });
+        assert_eq!(scrollback.iter().map(Row::text).collect::<Vec<_>>(), ["7", "8", "9"]);
+    }
+}
@@ -0,0 +1,240 @@
+// Working directory and titles reported by the shell through OSC 7 and OSC 0/1/2
+use crate::vt::Params;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Longest title kept; longer ones are cut at a character boundary
+pub const MAX_TITLE_LEN: usize = 1024;
+/// Depth of the XTWINOPS title stack, as in xterm
+const MAX_TITLE_STACK: usize = 10;
+
+/// Titles saved by `CSI 22 ; Ps t`. Each is kept only if `Ps` selected it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct SavedTitles {
+    icon: Option<String>,
+    window: Option<String>,
+}
+
+/// What changed since the last `ShellState::take_changes`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShellChanges {
+    pub title: Option<String>,
+    pub cwd: Option<PathBuf>,
+}
+
+/// Follows what the shell reports about itself: its working directory and
+/// the window and icon titles
+#[derive(Debug, Clone, Default)]
+pub struct ShellState {
+    cwd: Option<PathBuf>,
+    title: String,
+    icon_title: String,
+    stack: Vec<SavedTitles>,
+    title_changed: bool,
+    cwd_changed: bool,
+}
+
+impl ShellState {
+    /// Directory from the last OSC 7 naming this host
+    pub fn cwd(&self) -> Option<&PathBuf> {
+        self.cwd.as_ref()
+    }
+
+    /// Window title, empty until one is set
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn icon_title(&self) -> &str {
+        &self.icon_title
+    }
+
+    /// The window title and directory, for each one that changed since the
+    /// last call
+    pub fn take_changes(&mut self) -> ShellChanges {
+        ShellChanges {
+            title: std::mem::take(&mut self.title_changed).then(|| self.title.clone()),
+            cwd: std::mem::take(&mut self.cwd_changed).then(|| self.cwd.clone()).flatten(),
+        }
+    }
+
+    pub(crate) fn osc(&mut self, params: &[&[u8]]) {
+        let [code, rest @ ..] = params else { return };
+        match *code {
+            b"0" | b"1" | b"2" => {
+                // Titles may contain the separator
+                let title = truncate(String::from_utf8_lossy(&rest.join(&b';')).into_owned());
+                if *code != b"2" {
+                    self.icon_title = title.clone();
+                }
+                if *code != b"1" {
+                    self.set_title(title);
+                }
+            }
+            b"7" => {
+                if let Some(path) = rest.first().and_then(|url| local_path(url)) {
+                    self.cwd_changed |= self.cwd.as_ref() != Some(&path);
+                    self.cwd = Some(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the XTWINOPS title stack, `CSI 22 ; Ps t` and `CSI 23 ; Ps t`
+    pub(crate) fn csi(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore || action != 't' || !intermediates.is_empty() {
+            return;
+        }
+        // 0 selects both titles, 1 the icon title and 2 the window title
+        let which = params.get_or(1, 0);
+        let (icon, window) = (which != 2, which != 1);
+        match params.get(0) {
+            Some(22) => {
+                if self.stack.len() == MAX_TITLE_STACK {
+                    self.stack.remove(0);
+                }
+                self.stack.push(SavedTitles {
+                    icon: icon.then(|| self.icon_title.clone()),
+                    window: window.then(|| self.title.clone()),
+                });
+            }
+            Some(23) => {
+                let Some(saved) = self.stack.pop() else { return };
+                if let Some(title) = saved.icon.filter(|_| icon) {
+                    self.icon_title = title;
+                }
+                if let Some(title) = saved.window.filter(|_| window) {
+                    self.set_title(title);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_title(&mut self, title: String) {
+        self.title_changed |= self.title != title;
+        self.title = title;
+    }
+}
+
+fn truncate(mut title: String) -> String {
+    if title.len() > MAX_TITLE_LEN {
+        let mut end = MAX_TITLE_LEN;
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        title.truncate(end);
+    }
+    title
+}
+
+/// Path of a `file://host/path` URL naming this machine. Directories on
+/// other hosts, e.g. from a shell over ssh, are no use locally.
+fn local_path(url: &[u8]) -> Option<PathBuf> {
+    let rest = url.strip_prefix(b"file://")?;
+    let slash = rest.iter().position(|&byte| byte == b'/')?;
+    let (host, path) = rest.split_at(slash);
+    if !is_local_host(host) {
+        return None;
+    }
+    let path = percent_decode(path)?;
+    Some(PathBuf::from(OsString::from_vec(path)))
+}
+
+fn is_local_host(host: &[u8]) -> bool {
+    static HOSTNAME: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+    let hostname = HOSTNAME.get_or_init(|| nix::unistd::gethostname().ok().map(OsString::into_vec));
+    host.is_empty() || host.eq_ignore_ascii_case(b"localhost") || hostname.as_deref() == Some(host)
+}
+
+fn percent_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vt::{Parser, Perform};
+
+    impl Perform for ShellState {
+        fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+            self.csi(params, intermediates, ignore, action);
+        }
+
+        fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+            self.osc(params);
+        }
+    }
+
+    fn apply(state: &mut ShellState, bytes: &[u8]) {
+        Parser::new().advance(state, bytes);
+    }
+
+    #[test]
+    fn test_titles() {
+        let mut state = ShellState::default();
+        apply(&mut state, b"\x1b]0;vim: a;b\x07");
+        assert_eq!((state.title(), state.icon_title()), ("vim: a;b", "vim: a;b"));
+        apply(&mut state, b"\x1b]2;window\x1b\\\x1b]1;icon\x07");
+        assert_eq!((state.title(), state.icon_title()), ("window", "icon"));
+        assert_eq!(state.take_changes().title.as_deref(), Some("window"));
+        assert_eq!(state.take_changes(), ShellChanges::default());
+
+        let long = format!("\x1b]2;{}\x07", "é".repeat(MAX_TITLE_LEN));
+        apply(&mut state, long.as_bytes());
+        assert_eq!(state.title().len(), MAX_TITLE_LEN);
+    }
+
+    #[test]
+    fn test_title_stack() {
+        let mut state = ShellState::default();
+        apply(&mut state, b"\x1b]0;shell\x07\x1b[22;0t\x1b]0;vim\x07");
+        state.take_changes();
+        apply(&mut state, b"\x1b[23;0t");
+        assert_eq!((state.title(), state.icon_title()), ("shell", "shell"));
+        assert_eq!(state.take_changes().title.as_deref(), Some("shell"));
+
+        // Popping the window title leaves the icon title alone
+        apply(&mut state, b"\x1b[22;2t\x1b]0;vim\x07\x1b[23;2t");
+        assert_eq!((state.title(), state.icon_title()), ("shell", "vim"));
+
+        // Popping an empty stack does nothing
+        apply(&mut state, b"\x1b[23t");
+        assert_eq!(state.title(), "shell");
+    }
+
+    #[test]
+    fn test_cwd_from_osc7() {
+        let mut state = ShellState::default();
+        apply(&mut state, b"\x1b]7;file:///home/me/My%20Files\x07");
+        assert_eq!(state.cwd(), Some(&PathBuf::from("/home/me/My Files")));
+        assert_eq!(state.take_changes().cwd, Some(PathBuf::from("/home/me/My Files")));
+
+        // Reporting the same directory again isn't a change
+        apply(&mut state, b"\x1b]7;file://localhost/home/me/My%20Files\x07");
+        assert_eq!(state.take_changes().cwd, None);
+
+        // Other hosts and malformed URLs are ignored
+        apply(&mut state, b"\x1b]7;file://elsewhere.invalid/srv\x07\x1b]7;/tmp\x07\x1b]7;file:///bad%2\x07");
+        assert_eq!(state.cwd(), Some(&PathBuf::from("/home/me/My Files")));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode(b"/a%2Fb%c3%a9"), Some("/a/bé".as_bytes().to_vec()));
+        assert_eq!(percent_decode(b"%zz"), None);
+    }
+}
@@ -0,0 +1,90 @@
+// Built-in color themes selected by `UiConfig::theme`
+use crate::config::UiConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// X11 color spec with 16-bit channels, as xterm reports colors
+    pub fn to_x11(self) -> String {
+        let scale = |channel: u8| u16::from(channel) * 0x101;
+        format!("rgb:{:04x}/{:04x}/{:04x}", scale(self.r), scale(self.g), scale(self.b))
+    }
+
+    /// `#rrggbb`
+    pub fn to_hex(self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// xterm's default 16 ANSI colors
+#[rustfmt::skip]
+const XTERM_PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xcd, 0x00, 0x00), Rgb::new(0x00, 0xcd, 0x00), Rgb::new(0xcd, 0xcd, 0x00),
+    Rgb::new(0x00, 0x00, 0xee), Rgb::new(0xcd, 0x00, 0xcd), Rgb::new(0x00, 0xcd, 0xcd), Rgb::new(0xe5, 0xe5, 0xe5),
+    Rgb::new(0x7f, 0x7f, 0x7f), Rgb::new(0xff, 0x00, 0x00), Rgb::new(0x00, 0xff, 0x00), Rgb::new(0xff, 0xff, 0x00),
+    Rgb::new(0x5c, 0x5c, 0xff), Rgb::new(0xff, 0x00, 0xff), Rgb::new(0x00, 0xff, 0xff), Rgb::new(0xff, 0xff, 0xff),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    /// ANSI colors 0-15
+    pub palette: [Rgb; 16],
+}
+
+impl Theme {
+    pub const DARK: Theme = Theme {
+        foreground: Rgb::new(0xd4, 0xd4, 0xd4),
+        background: Rgb::new(0x1e, 0x1e, 0x1e),
+        palette: XTERM_PALETTE,
+    };
+    pub const LIGHT: Theme = Theme {
+        foreground: Rgb::new(0x24, 0x29, 0x2e),
+        background: Rgb::new(0xff, 0xff, 0xff),
+        palette: XTERM_PALETTE,
+    };
+
+    /// Theme named by `ui.theme`. Unknown names, and `system` until the
+    /// platform appearance is detected, get the dark theme.
+    pub fn from_ui(ui: &UiConfig) -> Theme {
+        match ui.theme.as_str() {
+            "light" => Theme::LIGHT,
+            _ => Theme::DARK,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::DARK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme_from_ui_config() {
+        let ui = |theme: &str| UiConfig { theme: theme.to_string(), ..UiConfig::default() };
+        assert_eq!(Theme::from_ui(&UiConfig::default()), Theme::DARK);
+        assert_eq!(Theme::from_ui(&ui("light")), Theme::LIGHT);
+        assert_eq!(Theme::from_ui(&ui("system")), Theme::DARK);
+    }
+
+    #[test]
+    fn test_color_specs() {
+        assert_eq!(Rgb::new(0xff, 0x80, 0x00).to_x11(), "rgb:ffff/8080/0000");
+        assert_eq!(Rgb::new(0xff, 0x80, 0x00).to_hex(), "#ff8000");
+    }
+}
@@ -1,15 +1,38 @@
 // TTY Engine implementation using direct libc calls for maximum performance
+use bytes::{Bytes, BytesMut};
 use nix::sys::signal::{self, Signal};
+use nix::sys::termios::{self, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg, SpecialCharacterIndices, Termios};
 use nix::sys::wait::{self, WaitStatus};
 use nix::unistd::{self, ForkResult, Pid};
 use std::collections::HashMap;
-use std::os::unix::io::RawFd;
+use std::ffi::{CStr, CString};
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::task::{ready, Context, Poll};
 use std::time::{Duration, Instant};
+use crate::commands::{CommandRecord, CommandTracker, PromptMark};
+use crate::config::{AgentConfig, UiConfig};
+use crate::modes::{ModeTracker, Modes};
+use crate::queries::Query;
+use crate::recording::{self, CastHeader, Recorder, RecordingOptions};
+use crate::replay::{Cast, CastEvent, ReplayError, ReplayOptions, ReplaySpeed, ReplayStatus, Timeline};
+use crate::screen::{Cell, Screen};
+use crate::shell_state::{ShellChanges, ShellState};
+use crate::scrollback::ScrollbackLimits;
+use crate::themes::Theme;
+use crate::width::WidthConfig;
 use thiserror::Error;
-use tokio::sync::broadcast;
-use tokio::time::timeout;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
 #[derive(Error, Debug)]
@@ -24,6 +47,10 @@ pub enum TtyError {
     Signal(String),
     #[error("PTY not found: {id}")]
     PtyNotFound { id: u64 },
+    #[error("Termios error: {0}")]
+    Termios(String),
+    #[error("Invalid PTY config: {0}")]
+    InvalidConfig(String),
     #[error("Invalid terminal mode: {mode}")]
     InvalidMode { mode: String },
     #[error("Buffer overflow: {size} bytes")]
@@ -32,6 +59,58 @@ pub enum TtyError {
     ProcessDied { pid: i32 },
     #[error("Timeout: operation took longer than {timeout_ms}ms")]
     Timeout { timeout_ms: u64 },
+    #[error("Failed to execute {path}: {}", io::Error::from_raw_os_error(*errno))]
+    Exec { path: String, errno: i32 },
+    #[error("Output lagged on PTY {id}: {skipped} chunks were overwritten before being read")]
+    OutputLagged { id: u64, skipped: u64 },
+    #[error("No screen attached to PTY {id}")]
+    ScreenNotAttached { id: u64 },
+    #[error("PTY {id} is already being recorded")]
+    AlreadyRecording { id: u64 },
+    #[error("No recordings directory: the home directory is unknown")]
+    NoRecordingsDir,
+    #[error("Invalid recording: {0}")]
+    Replay(#[from] ReplayError),
+    #[error("PTY {id} is not a replay")]
+    NotAReplay { id: u64 },
+}
+
+/// Default time processes get to exit after a hangup before SIGKILL
+pub const DEFAULT_TEARDOWN_GRACE: Duration = Duration::from_millis(100);
+
+/// Resizes arriving closer together than this are coalesced into one
+pub const RESIZE_DEBOUNCE: Duration = Duration::from_millis(30);
+
+/// Size of a single read from the PTY master
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// How long the output pump waits to write query replies into a PTY whose
+/// input nobody is reading before dropping them
+const QUERY_REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Number of output chunks buffered per session before slow readers lag
+const OUTPUT_CHANNEL_CAPACITY: usize = 256;
+
+/// Value of `TERM` when the config doesn't choose one
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+/// How long a replay waits for the output pump to take in what it wrote
+/// before resizing anyway
+const REPLAY_CATCH_UP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Features advertised to shell integration and agents through `PACHYTERM_CAPS`
+pub const SESSION_CAPS: &[&str] = &["truecolor", "osc7", "osc133"];
+
+/// Which parts of the parent environment a session starts from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EnvPolicy {
+    /// Copy the whole parent environment
+    #[default]
+    Inherit,
+    /// Start from an empty environment
+    Clean,
+    /// Copy only the named parent variables
+    Allowlist(Vec<String>),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -41,14 +120,235 @@ pub enum TerminalMode {
     AltScreen,
 }
 
+/// How a session's child process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled { signal: Signal, core_dumped: bool },
+}
+
+impl ExitStatus {
+    fn from_wait_status(status: WaitStatus) -> Option<Self> {
+        match status {
+            WaitStatus::Exited(_, code) => Some(ExitStatus::Exited(code)),
+            WaitStatus::Signaled(_, signal, core_dumped) => Some(ExitStatus::Signaled { signal, core_dumped }),
+            _ => None,
+        }
+    }
+
+    /// Exit code, or `None` if the process was killed by a signal
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Exited(code) => Some(*code),
+            ExitStatus::Signaled { .. } => None,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code() == Some(0)
+    }
+
+    fn event(&self, id: u64) -> SessionEvent {
+        match *self {
+            ExitStatus::Exited(code) => SessionEvent::Exited { id, code },
+            ExitStatus::Signaled { signal, core_dumped } => SessionEvent::Signaled { id, signal, core_dumped },
+        }
+    }
+}
+
+/// Which processes of a session a signal is delivered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalTarget {
+    /// Only the process the session spawned (usually the shell)
+    Child,
+    /// The terminal's foreground process group, i.e. the running job
+    ForegroundGroup,
+    /// Every process in the session's own process group
+    SessionGroup,
+}
+
+/// Process currently in the foreground of a session's terminal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForegroundProcess {
+    /// Leader of the foreground process group, or another live member once
+    /// the leader has exited, as the first command of a pipeline does
+    pub pid: i32,
+    pub pgid: i32,
+    /// Short command name from `/proc/<pid>/comm`
+    pub name: String,
+    pub cmdline: Vec<String>,
+}
+
+impl ForegroundProcess {
+    fn from_proc(pgid: Pid) -> Result<Self, TtyError> {
+        let pid = group_member(pgid).ok_or(TtyError::ProcessDied { pid: pgid.as_raw() })?;
+        let proc_dir = format!("/proc/{}", pid);
+        let name = std::fs::read_to_string(format!("{}/comm", proc_dir))?;
+        let cmdline = std::fs::read(format!("{}/cmdline", proc_dir))?;
+
+        Ok(Self {
+            pid: pid.as_raw(),
+            pgid: pgid.as_raw(),
+            name: name.trim_end().to_string(),
+            cmdline: cmdline
+                .split(|&b| b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
+        })
+    }
+}
+
+/// Pixel dimensions of one terminal cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellSize {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl CellSize {
+    /// Approximates the cell of a monospace font, treating `font_size` as
+    /// pixels: advance is 0.6em and height is scaled by `line_height`.
+    pub fn from_ui(ui: &UiConfig) -> Self {
+        let font_size = ui.font_size as f32;
+        Self {
+            width: (font_size * 0.6).round().clamp(1.0, u16::MAX as f32) as u16,
+            height: (font_size * ui.line_height).round().clamp(1.0, u16::MAX as f32) as u16,
+        }
+    }
+}
+
+/// Terminal size as reported to programs through TIOCGWINSZ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+}
+
+impl WindowSize {
+    pub fn new(rows: u16, cols: u16, cell: CellSize) -> Self {
+        Self {
+            rows,
+            cols,
+            pixel_width: cols.saturating_mul(cell.width),
+            pixel_height: rows.saturating_mul(cell.height),
+        }
+    }
+
+    fn to_winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: self.pixel_width,
+            ws_ypixel: self.pixel_height,
+        }
+    }
+}
+
+/// Outcome of tearing down a session's processes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TeardownReport {
+    /// Processes that were sent SIGHUP
+    pub hung_up: Vec<i32>,
+    /// Processes still running after the grace period, which were SIGKILLed
+    pub killed: Vec<i32>,
+    /// How the session's child ended; `None` for replays and children that
+    /// could not be reaped
+    pub status: Option<ExitStatus>,
+}
+
+impl TeardownReport {
+    /// True when every process exited on hangup
+    pub fn is_clean(&self) -> bool {
+        self.killed.is_empty()
+    }
+}
+
+/// Lifecycle notifications published by `TtyEngine::subscribe_events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    Created { id: u64, pid: i32 },
+    Exited { id: u64, code: i32 },
+    Signaled { id: u64, signal: Signal, core_dumped: bool },
+    Destroyed { id: u64 },
+    Resized { id: u64, size: WindowSize },
+    /// The application changed private modes, e.g. entered the alternate screen
+    ModesChanged { id: u64, modes: Modes },
+    /// A command marked by OSC 133 shell integration finished
+    CommandFinished { id: u64, seq: u64, exit_code: Option<i32> },
+    /// The window title changed through OSC 0/2 or the title stack
+    TitleChanged { id: u64, title: String },
+    /// The shell reported a new working directory through OSC 7
+    CwdChanged { id: u64, cwd: PathBuf },
+    /// A replay played its last event. It stays open for seeking.
+    ReplayFinished { id: u64 },
+}
+
+impl SessionEvent {
+    pub fn id(&self) -> u64 {
+        match *self {
+            SessionEvent::Created { id, .. }
+            | SessionEvent::Exited { id, .. }
+            | SessionEvent::Signaled { id, .. }
+            | SessionEvent::Destroyed { id }
+            | SessionEvent::Resized { id, .. }
+            | SessionEvent::ModesChanged { id, .. }
+            | SessionEvent::CommandFinished { id, .. }
+            | SessionEvent::TitleChanged { id, .. }
+            | SessionEvent::CwdChanged { id, .. }
+            | SessionEvent::ReplayFinished { id } => id,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PtyConfig {
+    /// Shell to run; bare names are looked up in `$PATH`
     pub shell: String,
     pub args: Vec<String>,
+    /// Extra variables layered over whatever `env_policy` inherits
     pub env: HashMap<String, String>,
+    pub env_policy: EnvPolicy,
+    /// Variables removed after inheriting and applying `env`
+    pub env_unset: Vec<String>,
+    /// Value of `TERM` for the child
+    pub term: String,
     pub working_dir: Option<String>,
+    /// Start in the current directory of this session, e.g. for a new tab.
+    /// `working_dir` takes precedence.
+    pub inherit_cwd_from: Option<u64>,
     pub rows: u16,
     pub cols: u16,
+    /// Cell pixel size; derived from the engine's UI config when unset
+    pub cell_size: Option<CellSize>,
+    /// Start the shell as a login shell (argv[0] prefixed with '-')
+    pub login_shell: bool,
+    /// Explicit argv[0], used verbatim instead of the program name
+    pub argv0: Option<String>,
+    /// Run this program and arguments instead of the interactive shell
+    pub command: Option<Vec<String>>,
+    /// How long `destroy_pty` waits after hanging up before SIGKILL
+    pub teardown_grace: Duration,
+    /// Maintain a screen model from the session's output
+    pub track_screen: bool,
+    /// Caps on the screen model's scrollback
+    pub scrollback: ScrollbackLimits,
+    /// How the screen model measures characters; should match the wcwidth
+    /// the session's programs use
+    pub width: WidthConfig,
+    /// Reply to device attribute, cursor position, mode, version, termcap and
+    /// color queries in the output. Turn off when a front end answers them.
+    pub answer_queries: bool,
+    /// Record the session from the start to a new file in the engine's
+    /// recordings directory, `~/.pachyterm/sessions/<id>/` by default
+    pub auto_record: Option<RecordingOptions>,
+    /// Hold output for `read_from_pty` from the moment the session starts.
+    /// Otherwise the first `read_from_pty` call subscribes and sees output
+    /// from then on. Unread output is held up to the output channel's
+    /// capacity, then reads report `OutputLagged`.
+    pub read_from_start: bool,
 }
 
 impl Default for PtyConfig {
@@ -56,11 +356,83 @@ impl Default for PtyConfig {
         Self {
             shell: std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string()),
             args: vec![],
-            env: std::env::vars().collect(),
+            env: HashMap::new(),
+            env_policy: EnvPolicy::Inherit,
+            env_unset: vec![],
+            term: DEFAULT_TERM.to_string(),
             working_dir: None,
+            inherit_cwd_from: None,
             rows: 24,
             cols: 80,
+            cell_size: None,
+            login_shell: false,
+            argv0: None,
+            command: None,
+            teardown_grace: DEFAULT_TEARDOWN_GRACE,
+            track_screen: true,
+            scrollback: ScrollbackLimits::default(),
+            width: WidthConfig::default(),
+            answer_queries: true,
+            auto_record: None,
+            read_from_start: false,
+        }
+    }
+}
+
+impl PtyConfig {
+    /// Program and arguments to execute: `command` when set, else the shell
+    fn program_and_args(&self) -> Result<(&str, &[String]), TtyError> {
+        let (program, args) = match self.command.as_deref() {
+            Some([program, args @ ..]) => (program.as_str(), args),
+            Some([]) => return Err(TtyError::InvalidConfig("command is empty".to_string())),
+            None => (self.shell.as_str(), self.args.as_slice()),
+        };
+        if program.is_empty() {
+            return Err(TtyError::InvalidConfig("no program to execute".to_string()));
+        }
+        Ok((program, args))
+    }
+
+    /// argv[0] as the child sees it when running `program`
+    fn argv0(&self, program: &str) -> String {
+        if let Some(argv0) = &self.argv0 {
+            return argv0.clone();
+        }
+
+        if self.login_shell && self.command.is_none() {
+            let name = program.rsplit('/').next().unwrap_or(program);
+            format!("-{}", name)
+        } else {
+            program.to_string()
+        }
+    }
+
+    /// Environment for the child of session `session_id`, given the
+    /// variables `env_policy` may inherit. The terminal identity variables are
+    /// set last so they can't be overridden or unset.
+    fn build_env(
+        &self,
+        session_id: u64,
+        inherited: impl Iterator<Item = (String, String)>,
+    ) -> HashMap<String, String> {
+        let mut env: HashMap<String, String> = match &self.env_policy {
+            EnvPolicy::Inherit => inherited.collect(),
+            EnvPolicy::Clean => HashMap::new(),
+            EnvPolicy::Allowlist(names) => inherited.filter(|(key, _)| names.contains(key)).collect(),
+        };
+
+        env.extend(self.env.iter().map(|(key, value)| (key.clone(), value.clone())));
+        for key in &self.env_unset {
+            env.remove(key);
         }
+
+        env.insert("TERM".to_string(), self.term.clone());
+        env.insert("COLORTERM".to_string(), "truecolor".to_string());
+        env.insert("TERM_PROGRAM".to_string(), "pachyterm".to_string());
+        env.insert("TERM_PROGRAM_VERSION".to_string(), env!("CARGO_PKG_VERSION").to_string());
+        env.insert("PACHYTERM_SESSION_ID".to_string(), session_id.to_string());
+        env.insert("PACHYTERM_CAPS".to_string(), SESSION_CAPS.join(","));
+        env
     }
 }
 
@@ -68,46 +440,324 @@ impl Default for PtyConfig {
 pub struct PtySession {
     pub id: u64,
     pub master_fd: RawFd,
+    io: AsyncFd<OwnedFd>,
     pub child_pid: Pid,
     pub mode: Arc<RwLock<TerminalMode>>,
     pub created_at: Instant,
     pub bytes_read: AtomicU64,
     pub bytes_written: AtomicU64,
     pub is_alive: AtomicBool,
+    output_tx: Mutex<Option<broadcast::Sender<Bytes>>>,
+    output: tokio::sync::Mutex<OutputReader>,
+    closed: CancellationToken,
+    exited: CancellationToken,
+    teardown_grace: Duration,
+    cell_size: Mutex<CellSize>,
+    /// Set once the config or `resize_pty_pixels` chose a cell size, so UI
+    /// changes leave it alone
+    pinned_cell_size: AtomicBool,
+    window: Mutex<ResizeState>,
+    output_model: Mutex<OutputModel>,
+    scrollback_limits: ScrollbackLimits,
+    width_config: WidthConfig,
+    answer_queries: bool,
+    /// TERM given to the child, reported by XTGETTCAP
+    term: String,
+    /// Colors reported by OSC 10/11
+    theme: Mutex<Theme>,
+    commands: Mutex<CommandTracker>,
+    /// Program the session runs, for recording headers
+    shell: String,
+    recorder: Mutex<Option<Recorder>>,
+    /// Playback control when the session replays a recording
+    replay: Option<ReplayHandle>,
+}
+
+/// Controls a replay task from the engine
+#[derive(Debug, Clone)]
+struct ReplayHandle {
+    timeline: Arc<Mutex<Timeline>>,
+    commands: mpsc::UnboundedSender<ReplayCommand>,
+}
+
+#[derive(Debug)]
+enum ReplayCommand {
+    /// The timeline was paused, resumed or changed speed
+    Wake,
+    /// Move to a position, answering once its output has been processed
+    Seek(Duration, oneshot::Sender<()>),
+}
+
+/// What the pump maintains from a session's output. Modes are always
+/// followed; a screen, when attached, follows them itself.
+#[derive(Debug)]
+enum OutputModel {
+    Modes(Box<ModeTracker>),
+    Screen(Box<Screen>),
+}
+
+impl OutputModel {
+    fn advance(&mut self, bytes: &[u8]) {
+        match self {
+            OutputModel::Modes(tracker) => tracker.advance(bytes),
+            OutputModel::Screen(screen) => screen.advance(bytes),
+        }
+    }
+
+    fn modes(&self) -> Modes {
+        match self {
+            OutputModel::Modes(tracker) => tracker.modes(),
+            OutputModel::Screen(screen) => screen.modes(),
+        }
+    }
+
+    fn take_queries(&mut self) -> Vec<Query> {
+        match self {
+            OutputModel::Modes(tracker) => tracker.take_queries(),
+            OutputModel::Screen(screen) => screen.take_queries(),
+        }
+    }
+
+    fn take_marks(&mut self) -> Vec<PromptMark> {
+        match self {
+            OutputModel::Modes(tracker) => tracker.take_marks(),
+            OutputModel::Screen(screen) => screen.take_marks(),
+        }
+    }
+
+    fn shell_state(&self) -> &ShellState {
+        match self {
+            OutputModel::Modes(tracker) => tracker.shell_state(),
+            OutputModel::Screen(screen) => screen.shell_state(),
+        }
+    }
+
+    fn take_shell_changes(&mut self) -> ShellChanges {
+        match self {
+            OutputModel::Modes(tracker) => tracker.take_shell_changes(),
+            OutputModel::Screen(screen) => screen.take_shell_changes(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ResizeState {
+    applied: WindowSize,
+    applied_at: Option<Instant>,
+    /// Latest size waiting for the debounce timer
+    pending: Option<WindowSize>,
+}
+
+/// The session's own subscription behind `read_from_pty`. It is created by
+/// the first read, or before the output pump starts with
+/// `PtyConfig::read_from_start`, so sessions nobody reads from hold no output.
+#[derive(Debug, Default)]
+struct OutputReader {
+    rx: Option<broadcast::Receiver<Bytes>>,
+    pending: Bytes,
+}
+
+impl OutputReader {
+    async fn next_chunk(
+        &mut self,
+        id: u64,
+        max: usize,
+        subscribe: impl FnOnce() -> broadcast::Receiver<Bytes>,
+    ) -> Result<Bytes, TtyError> {
+        if self.pending.is_empty() {
+            match self.rx.get_or_insert_with(subscribe).recv().await {
+                Ok(chunk) => self.pending = chunk,
+                Err(RecvError::Closed) => return Ok(Bytes::new()),
+                Err(RecvError::Lagged(skipped)) => return Err(TtyError::OutputLagged { id, skipped }),
+            }
+        }
+
+        let len = max.min(self.pending.len());
+        Ok(self.pending.split_to(len))
+    }
 }
 
 impl PtySession {
-    pub fn new(id: u64, master_fd: RawFd, child_pid: Pid) -> Self {
-        Self {
+    /// Takes ownership of `master_fd`, switches it to non-blocking mode and
+    /// registers it with the tokio reactor. Must be called within a runtime.
+    /// Fails if the fd can't be made non-blocking or registered.
+    pub fn new(id: u64, master_fd: RawFd, child_pid: Pid) -> Result<Self, TtyError> {
+        let owned = unsafe { OwnedFd::from_raw_fd(master_fd) };
+        set_nonblocking(master_fd)?;
+        let (output_tx, _) = broadcast::channel(OUTPUT_CHANNEL_CAPACITY);
+
+        Ok(Self {
             id,
             master_fd,
+            io: AsyncFd::new(owned)?,
             child_pid,
             mode: Arc::new(RwLock::new(TerminalMode::Cooked)),
             created_at: Instant::now(),
             bytes_read: AtomicU64::new(0),
             bytes_written: AtomicU64::new(0),
             is_alive: AtomicBool::new(true),
+            output_tx: Mutex::new(Some(output_tx)),
+            output: tokio::sync::Mutex::new(OutputReader::default()),
+            closed: CancellationToken::new(),
+            exited: CancellationToken::new(),
+            teardown_grace: DEFAULT_TEARDOWN_GRACE,
+            cell_size: Mutex::new(CellSize::from_ui(&UiConfig::default())),
+            pinned_cell_size: AtomicBool::new(false),
+            window: Mutex::new(ResizeState {
+                applied: WindowSize { rows: 0, cols: 0, pixel_width: 0, pixel_height: 0 },
+                applied_at: None,
+                pending: None,
+            }),
+            output_model: Mutex::new(OutputModel::Modes(Box::default())),
+            scrollback_limits: ScrollbackLimits::default(),
+            width_config: WidthConfig::default(),
+            answer_queries: true,
+            term: DEFAULT_TERM.to_string(),
+            theme: Mutex::new(Theme::default()),
+            commands: Mutex::new(CommandTracker::new()),
+            shell: String::new(),
+            recorder: Mutex::new(None),
+            replay: None,
+        })
+    }
+
+    /// Copies the next piece of output into `buffer`, waiting if none is
+    /// buffered. Returns `Ok(0)` once the output stream has ended.
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, TtyError> {
+        let chunk = self.read_chunk(buffer.len()).await?;
+        buffer[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+
+    /// Has `read` hold output from now on, as its first call would
+    fn start_reading(&self) {
+        // A read in progress holds the lock and has subscribed already
+        if let Ok(mut reader) = self.output.try_lock() {
+            if reader.rx.is_none() {
+                reader.rx = Some(self.subscribe_output());
+            }
+        }
+    }
+
+    async fn read_chunk(&self, max: usize) -> Result<Bytes, TtyError> {
+        self.output.lock().await.next_chunk(self.id, max, || self.subscribe_output()).await
+    }
+
+    /// Returns a new receiver for every output chunk read from this session
+    /// from now on. The receiver reports `RecvError::Lagged` if it falls more
+    /// than the channel capacity behind, and `RecvError::Closed` at EOF.
+    pub fn subscribe_output(&self) -> broadcast::Receiver<Bytes> {
+        match &*self.output_tx.lock().unwrap() {
+            Some(tx) => tx.subscribe(),
+            // Output already ended: hand out a receiver that is closed
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    fn publish_output(&self, chunk: Bytes) {
+        if let Some(tx) = &*self.output_tx.lock().unwrap() {
+            let _ = tx.send(chunk);
+        }
+    }
+
+    fn close_output(&self) {
+        self.output_tx.lock().unwrap().take();
+    }
+
+    /// Waits until the master is readable and reads whatever is available.
+    /// Returns `Ok(0)` once the slave side has been closed by the child.
+    async fn read_master(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|fd| read_master(fd.as_raw_fd(), buffer)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Waits until the master is writable and writes as much of `data` as the
+    /// kernel accepts.
+    pub async fn write(&self, data: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|fd| write_master(fd.as_raw_fd(), data)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.io.poll_write_ready(cx))?;
+            match guard.try_io(|fd| write_master(fd.as_raw_fd(), data)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
         }
     }
 
+    /// Applies the line discipline for `mode` to the PTY. On a PTY master,
+    /// tcsetattr/tcgetattr operate on the slave's termios, so this is the same
+    /// state the child sees.
     pub fn set_mode(&self, mode: TerminalMode) -> Result<(), TtyError> {
-        // For now, just store the mode. Full termios implementation would go here
+        let fd = self.borrow_fd();
+        let mut attrs = termios::tcgetattr(fd)
+            .map_err(|e| TtyError::Termios(e.to_string()))?;
+
+        match mode {
+            TerminalMode::Cooked => apply_cooked(&mut attrs),
+            // Full-screen applications run with the same line discipline as raw mode
+            TerminalMode::Raw | TerminalMode::AltScreen => apply_raw(&mut attrs),
+        }
+
+        termios::tcsetattr(fd, SetArg::TCSANOW, &attrs)
+            .map_err(|e| TtyError::Termios(e.to_string()))?;
+
         *self.mode.write().unwrap() = mode;
         Ok(())
     }
 
+    /// Reads the mode back from the kernel, so changes made by the child
+    /// (e.g. `stty raw` or an editor) are visible.
     pub fn get_mode(&self) -> TerminalMode {
-        *self.mode.read().unwrap()
+        if self.modes().alt_screen {
+            return TerminalMode::AltScreen;
+        }
+        let requested = *self.mode.read().unwrap();
+
+        match termios::tcgetattr(self.borrow_fd()) {
+            Ok(attrs) if attrs.local_flags.contains(LocalFlags::ICANON) => TerminalMode::Cooked,
+            Ok(_) if requested == TerminalMode::AltScreen => TerminalMode::AltScreen,
+            Ok(_) => TerminalMode::Raw,
+            Err(e) => {
+                warn!("Failed to read termios for PTY {}: {}", self.id, e);
+                requested
+            }
+        }
+    }
+
+    fn borrow_fd(&self) -> BorrowedFd<'_> {
+        self.io.get_ref().as_fd()
     }
 
+    /// Resizes right away, keeping the current cell size
     pub fn resize(&self, rows: u16, cols: u16) -> Result<(), TtyError> {
-        let winsize = libc::winsize {
-            ws_row: rows,
-            ws_col: cols,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
-        };
-        
+        let cell = self.cell_size();
+        self.set_window_size(WindowSize::new(rows, cols, cell))
+    }
+
+    /// Applies `size` with TIOCSWINSZ. The kernel sends SIGWINCH to the
+    /// foreground process group when the size actually changes.
+    pub fn set_window_size(&self, size: WindowSize) -> Result<(), TtyError> {
+        self.apply_window_size(&mut self.window.lock().unwrap(), size);
+        Ok(())
+    }
+
+    /// Applies under the window lock so concurrent resizes land in order
+    fn apply_window_size(&self, window: &mut ResizeState, size: WindowSize) {
+        let winsize = size.to_winsize();
         unsafe {
             let result = libc::ioctl(self.master_fd, libc::TIOCSWINSZ, &winsize);
             if result == -1 {
@@ -115,16 +765,253 @@ impl PtySession {
                 warn!("Failed to set window size for PTY {}: {}", self.id, std::io::Error::last_os_error());
             }
         }
-        
-        // Send SIGWINCH to child process (ignore errors)
-        let _ = signal::kill(self.child_pid, Signal::SIGWINCH);
+
+        window.applied = size;
+        window.applied_at = Some(Instant::now());
+        self.record(|recorder| recorder.resize(size.cols, size.rows));
+
+        if let OutputModel::Screen(screen) = &mut *self.output_model.lock().unwrap() {
+            screen.resize(size.rows, size.cols);
+        }
+    }
+
+    /// Runs `f` on the screen model, if one is attached
+    pub fn with_screen<R>(&self, f: impl FnOnce(&Screen) -> R) -> Option<R> {
+        match &*self.output_model.lock().unwrap() {
+            OutputModel::Screen(screen) => Some(f(screen)),
+            OutputModel::Modes(_) => None,
+        }
+    }
+
+    /// Starts maintaining a screen model from output read from now on
+    pub fn attach_screen(&self) {
+        let size = self.window_size();
+        let mut model = self.output_model.lock().unwrap();
+        if let OutputModel::Modes(tracker) = &*model {
+            let mut screen = Screen::with_scrollback(size.rows, size.cols, self.scrollback_limits);
+            screen.set_width_config(self.width_config);
+            screen.set_modes(tracker.modes());
+            screen.set_shell_state(tracker.shell_state().clone());
+            *model = OutputModel::Screen(Box::new(screen));
+        }
+    }
+
+    /// Modes the application has selected through its output
+    pub fn modes(&self) -> Modes {
+        self.output_model.lock().unwrap().modes()
+    }
+
+    /// Feeds output to the session's model, returning the new modes if the
+    /// chunk changed any
+    fn process_output(&self, chunk: &[u8]) -> Option<Modes> {
+        let (before, after) = {
+            let mut model = self.output_model.lock().unwrap();
+            let before = model.modes();
+            model.advance(chunk);
+            (before, model.modes())
+        };
+        if before == after {
+            return None;
+        }
+
+        if before.alt_screen != after.alt_screen {
+            let mut mode = self.mode.write().unwrap();
+            if after.alt_screen {
+                *mode = TerminalMode::AltScreen;
+            } else if *mode == TerminalMode::AltScreen {
+                *mode = TerminalMode::Raw;
+            }
+        }
+        Some(after)
+    }
+
+    /// Applies shell integration marks in output processed so far, returning
+    /// the commands they finished. Command text and output are read from the
+    /// screen, so sessions without one only get exit codes and times.
+    fn track_commands(&self) -> Vec<CommandRecord> {
+        let mut model = self.output_model.lock().unwrap();
+        let marks = model.take_marks();
+        if marks.is_empty() {
+            return Vec::new();
+        }
+        let screen = match &*model {
+            OutputModel::Screen(screen) => Some(&**screen),
+            OutputModel::Modes(_) => None,
+        };
+        let reported = model.shell_state().cwd();
+        let mut commands = self.commands.lock().unwrap();
+        marks
+            .into_iter()
+            .filter_map(|mark| commands.apply(mark, screen, || reported.cloned().or_else(|| self.process_cwd())))
+            .collect()
+    }
+
+    /// Title and directory changes in output processed so far
+    fn take_shell_changes(&self) -> ShellChanges {
+        self.output_model.lock().unwrap().take_shell_changes()
+    }
+
+    /// Current directory: the last one the shell reported through OSC 7, or
+    /// else that of the foreground process
+    pub fn cwd(&self) -> Option<PathBuf> {
+        let reported = self.output_model.lock().unwrap().shell_state().cwd().cloned();
+        reported.or_else(|| self.process_cwd())
+    }
+
+    /// Directory of a live process in the foreground group, falling back to
+    /// the session's own child. `None` once the child has been reaped, as
+    /// its pid may already belong to an unrelated process.
+    fn process_cwd(&self) -> Option<PathBuf> {
+        if !self.is_alive() {
+            return None;
+        }
+        let read = |pid: Pid| std::fs::read_link(format!("/proc/{}/cwd", pid)).ok();
+        self.foreground_pgrp().ok().and_then(group_member).and_then(read).or_else(|| read(self.child_pid))
+    }
+
+    /// Window title set by the application, empty until one is set
+    pub fn title(&self) -> String {
+        self.output_model.lock().unwrap().shell_state().title().to_string()
+    }
+
+    /// Commands recorded from shell integration marks, oldest first
+    pub fn command_records(&self) -> Vec<CommandRecord> {
+        self.commands.lock().unwrap().records().cloned().collect()
+    }
+
+    pub fn last_command(&self) -> Option<CommandRecord> {
+        self.commands.lock().unwrap().last().cloned()
+    }
+
+    /// The most recent command that exited with a non-zero code
+    pub fn last_failed_command(&self) -> Option<CommandRecord> {
+        self.commands.lock().unwrap().last_failed().cloned()
+    }
+
+    /// Starts writing the session to an asciicast file at `path`
+    pub fn start_recording(&self, path: &Path, options: RecordingOptions) -> Result<(), TtyError> {
+        let mut recorder = self.recorder.lock().unwrap();
+        if recorder.is_some() {
+            return Err(TtyError::AlreadyRecording { id: self.id });
+        }
+        let size = self.window_size();
+        let header = CastHeader {
+            cols: size.cols,
+            rows: size.rows,
+            shell: self.shell.clone(),
+            term: self.term.clone(),
+            theme: *self.theme.lock().unwrap(),
+        };
+        *recorder = Some(Recorder::create(path, options, &header)?);
         Ok(())
     }
 
+    /// Finishes the recording, returning its path if there was one
+    pub fn stop_recording(&self) -> Result<Option<PathBuf>, TtyError> {
+        let recorder = self.recorder.lock().unwrap().take();
+        Ok(recorder.map(Recorder::finish).transpose()?)
+    }
+
+    pub fn recording_path(&self) -> Option<PathBuf> {
+        self.recorder.lock().unwrap().as_ref().map(|recorder| recorder.path().to_path_buf())
+    }
+
+    /// Records input written by the user, if the recording includes input
+    fn record_input(&self, data: &[u8]) {
+        self.record(|recorder| recorder.input(data));
+    }
+
+    /// When the recording's buffered events are due in its file
+    fn recording_flush_due(&self) -> Option<Instant> {
+        self.recorder.lock().unwrap().as_ref().and_then(Recorder::flush_due)
+    }
+
+    /// Applies `f` to the recorder, if recording. A failed write ends the
+    /// recording rather than the session.
+    fn record(&self, f: impl FnOnce(&mut Recorder) -> io::Result<()>) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if let Some(Err(e)) = recorder.as_mut().map(f) {
+            warn!("Stopped recording PTY {}: {}", self.id, e);
+            *recorder = None;
+        }
+    }
+
+    /// Replies to the queries in output processed so far
+    fn take_replies(&self) -> Vec<u8> {
+        let queries = self.output_model.lock().unwrap().take_queries();
+        if !self.answer_queries {
+            return Vec::new();
+        }
+        let theme = *self.theme.lock().unwrap();
+        queries.iter().flat_map(|query| query.reply(&theme, &self.term)).collect()
+    }
+
+    /// Writes query replies, giving up if the application isn't reading its
+    /// input so the output pump never stalls on it
+    async fn send_replies(&self, replies: &[u8]) {
+        let write = async {
+            let mut written = 0;
+            while written < replies.len() {
+                written += self.write(&replies[written..]).await?;
+            }
+            Ok::<_, io::Error>(())
+        };
+        match tokio::time::timeout(QUERY_REPLY_TIMEOUT, write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to answer queries from PTY {}: {}", self.id, e),
+            Err(_) => warn!("Dropped query replies for PTY {}: input is not being read", self.id),
+        }
+    }
+
+    /// Size most recently applied to the terminal
+    pub fn window_size(&self) -> WindowSize {
+        self.window.lock().unwrap().applied
+    }
+
+    pub fn cell_size(&self) -> CellSize {
+        *self.cell_size.lock().unwrap()
+    }
+
+    /// Process group the terminal currently delivers keyboard signals to
+    pub fn foreground_pgrp(&self) -> Result<Pid, TtyError> {
+        let pgrp = unsafe { libc::tcgetpgrp(self.master_fd) };
+        if pgrp <= 0 {
+            return Err(TtyError::Signal(format!(
+                "Failed to get foreground process group of PTY {}: {}",
+                self.id,
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(Pid::from_raw(pgrp))
+    }
+
+    pub fn signal(&self, signal: Signal, target: SignalTarget) -> Result<(), TtyError> {
+        if self.is_replay() {
+            return Err(TtyError::Signal(format!("PTY {} is a replay with no processes", self.id)));
+        }
+        let result = match target {
+            SignalTarget::Child => signal::kill(self.child_pid, signal),
+            // The child is a session leader, so its pid is also its pgid
+            SignalTarget::SessionGroup => signal::killpg(self.child_pid, signal),
+            SignalTarget::ForegroundGroup => signal::killpg(self.foreground_pgrp()?, signal),
+        };
+        result.map_err(|e| TtyError::Signal(e.to_string()))
+    }
+
+    /// Plays back a recording rather than running a process
+    pub fn is_replay(&self) -> bool {
+        self.replay.is_some()
+    }
+
     pub fn is_alive(&self) -> bool {
         self.is_alive.load(Ordering::Relaxed)
     }
 
+    /// Resolves once the child has been reaped
+    pub async fn wait_exit(&self) {
+        self.exited.cancelled().await
+    }
+
     pub fn mark_dead(&self) {
         self.is_alive.store(false, Ordering::Relaxed);
     }
@@ -138,23 +1025,398 @@ impl PtySession {
     }
 }
 
-impl Drop for PtySession {
-    fn drop(&mut self) {
-        unsafe {
-            libc::close(self.master_fd);
+#[cfg(target_os = "linux")]
+const PTMX_CLOEXEC: libc::c_int = libc::O_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const PTMX_CLOEXEC: libc::c_int = 0;
+
+/// Creates a PTY master using libc directly for better compatibility. The
+/// master is switched to non-blocking mode once a session takes ownership.
+fn open_master() -> Result<OwnedFd, TtyError> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | PTMX_CLOEXEC);
+        if fd == -1 {
+            return Err(TtyError::PtyCreation("Failed to create PTY master".to_string()));
+        }
+        let master = OwnedFd::from_raw_fd(fd);
+
+        // Keep this session's master out of every other session's child
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(TtyError::PtyCreation("Failed to set close-on-exec on PTY".to_string()));
+        }
+
+        if libc::grantpt(fd) == -1 {
+            return Err(TtyError::PtyCreation("Failed to grant PTY".to_string()));
+        }
+
+        if libc::unlockpt(fd) == -1 {
+            return Err(TtyError::PtyCreation("Failed to unlock PTY".to_string()));
         }
+
+        Ok(master)
     }
 }
 
-// Zero-copy buffer implementation is available for future use
-// Currently using direct libc calls for maximum performance
+/// Opens the slave side of `master_fd` in the engine itself, for replays
+/// that write recorded output into it. Raw, so output arrives unchanged.
+fn open_slave(master_fd: RawFd) -> Result<OwnedFd, TtyError> {
+    let path = slave_path(master_fd)?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(TtyError::PtyCreation(format!("Failed to open PTY slave: {}", io::Error::last_os_error())));
+    }
+    let slave = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut attrs = termios::tcgetattr(&slave).map_err(|e| TtyError::Termios(e.to_string()))?;
+    apply_raw(&mut attrs);
+    termios::tcsetattr(&slave, SetArg::TCSANOW, &attrs).map_err(|e| TtyError::Termios(e.to_string()))?;
+    Ok(slave)
+}
+
+/// Path of the slave side of `master_fd`, looked up in the parent because
+/// `ptsname` is not safe to call after fork
+fn slave_path(master_fd: RawFd) -> Result<CString, TtyError> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut name = [0 as libc::c_char; 128];
+        if unsafe { libc::ptsname_r(master_fd, name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(TtyError::PtyCreation("Failed to get slave PTY name".to_string()));
+        }
+        Ok(unsafe { CStr::from_ptr(name.as_ptr()) }.to_owned())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        // ptsname uses a static buffer
+        static PTSNAME_LOCK: Mutex<()> = Mutex::new(());
+        let _guard = PTSNAME_LOCK.lock().unwrap();
+
+        let name = unsafe { libc::ptsname(master_fd) };
+        if name.is_null() {
+            return Err(TtyError::PtyCreation("Failed to get slave PTY name".to_string()));
+        }
+        Ok(unsafe { CStr::from_ptr(name) }.to_owned())
+    }
+}
+
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Looks `program` up in `path` the way `execvp` would. Names containing a
+/// '/' are used as given.
+fn resolve_program(program: &str, path: Option<&str>) -> Result<String, TtyError> {
+    if program.contains('/') {
+        return Ok(program.to_string());
+    }
+
+    let mut errno = libc::ENOENT;
+    for dir in path.unwrap_or(DEFAULT_PATH).split(':') {
+        let dir = if dir.is_empty() { "." } else { dir };
+        let candidate = std::path::Path::new(dir).join(program);
+        if !candidate.is_file() {
+            continue;
+        }
+        if unistd::access(&candidate, unistd::AccessFlags::X_OK).is_ok() {
+            return Ok(candidate.to_string_lossy().into_owned());
+        }
+        errno = libc::EACCES;
+    }
+
+    Err(TtyError::Exec { path: program.to_string(), errno })
+}
+
+fn to_cstring(value: &str, what: &str) -> Result<CString, TtyError> {
+    CString::new(value).map_err(|_| TtyError::InvalidConfig(format!("{} contains a NUL byte: {:?}", what, value)))
+}
+
+/// Arguments, environment and paths for the child, allocated before fork
+struct ChildSpawn {
+    slave_path: CString,
+    working_dir: Option<CString>,
+    program: CString,
+    _argv: Vec<CString>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    _envp: Vec<CString>,
+    envp_ptrs: Vec<*const libc::c_char>,
+}
+
+impl ChildSpawn {
+    fn prepare(config: &PtyConfig, session_id: u64, slave_path: &CStr) -> Result<Self, TtyError> {
+        let env = config.build_env(session_id, std::env::vars());
+
+        let (program, args) = config.program_and_args()?;
+        let argv0 = config.argv0(program);
+        let resolved = resolve_program(program, env.get("PATH").map(String::as_str))?;
+        let program = to_cstring(&resolved, "program")?;
+
+        let mut argv = vec![to_cstring(&argv0, "argv0")?];
+        for arg in args {
+            argv.push(to_cstring(arg, "argument")?);
+        }
+
+        let mut envp = Vec::with_capacity(env.len());
+        for (key, value) in &env {
+            envp.push(to_cstring(&format!("{}={}", key, value), "environment entry")?);
+        }
+
+        let working_dir = match &config.working_dir {
+            Some(dir) => Some(to_cstring(dir, "working_dir")?),
+            None => None,
+        };
+
+        let argv_ptrs = argv.iter().map(|arg| arg.as_ptr()).chain(std::iter::once(std::ptr::null())).collect();
+        let envp_ptrs = envp.iter().map(|entry| entry.as_ptr()).chain(std::iter::once(std::ptr::null())).collect();
+
+        Ok(Self {
+            slave_path: slave_path.to_owned(),
+            working_dir,
+            program,
+            _argv: argv,
+            argv_ptrs,
+            _envp: envp,
+            envp_ptrs,
+        })
+    }
+
+    /// Runs in the forked child. Only async-signal-safe calls are made: no
+    /// allocation, locking or environment mutation.
+    unsafe fn exec(&self, status_fd: RawFd) -> ! {
+        // Create new session
+        if libc::setsid() == -1 {
+            report_child_failure(status_fd, ChildStage::Setup);
+        }
+
+        // Open slave PTY and make it the controlling terminal
+        let slave_fd = libc::open(self.slave_path.as_ptr(), libc::O_RDWR);
+        if slave_fd == -1 {
+            report_child_failure(status_fd, ChildStage::Setup);
+        }
+        if libc::ioctl(slave_fd, libc::TIOCSCTTY as _, 0) == -1 {
+            report_child_failure(status_fd, ChildStage::Setup);
+        }
+
+        // Redirect stdin, stdout, stderr to slave PTY
+        for target in 0..3 {
+            if libc::dup2(slave_fd, target) == -1 {
+                report_child_failure(status_fd, ChildStage::Setup);
+            }
+        }
+        if slave_fd > 2 {
+            libc::close(slave_fd);
+        }
+
+        // Undo signal state inherited from the runtime: Rust ignores SIGPIPE
+        // and exec keeps ignored dispositions and the blocked mask
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        let mut empty_set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut empty_set);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &empty_set, std::ptr::null_mut());
+
+        // Set working directory
+        if let Some(dir) = &self.working_dir {
+            if libc::chdir(dir.as_ptr()) == -1 {
+                report_child_failure(status_fd, ChildStage::Chdir);
+            }
+        }
+
+        libc::execve(self.program.as_ptr(), self.argv_ptrs.as_ptr(), self.envp_ptrs.as_ptr());
+
+        // If we get here, exec failed
+        report_child_failure(status_fd, ChildStage::Exec);
+    }
+}
+
+/// Step of child setup that failed, as reported through the status pipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum ChildStage {
+    Setup = 1,
+    Chdir = 2,
+    Exec = 3,
+}
+
+/// Writes the failed stage and errno to the status pipe and exits. Only
+/// async-signal-safe calls are made here.
+unsafe fn report_child_failure(status_fd: RawFd, stage: ChildStage) -> ! {
+    let errno = nix::errno::Errno::last_raw();
+    let mut report = [0u8; 5];
+    report[0] = stage as u8;
+    report[1..].copy_from_slice(&errno.to_ne_bytes());
+
+    libc::write(status_fd, report.as_ptr() as *const libc::c_void, report.len());
+    libc::_exit(127);
+}
+
+/// Waits off the runtime for the child to exec or report a failure, and
+/// collects a child that failed: it was never registered with the reaper.
+/// Returns `None` once exec succeeded and closed the pipe.
+async fn wait_for_exec(child: Pid, status_fd: OwnedFd) -> Result<Option<(ChildStage, i32)>, TtyError> {
+    tokio::task::spawn_blocking(move || {
+        let failure = read_child_status(status_fd)?;
+        if failure.is_some() {
+            let _ = wait::waitpid(child, None);
+        }
+        Ok(failure)
+    })
+    .await
+    .map_err(|e| TtyError::Fork(format!("Failed to wait for child exec: {}", e)))?
+}
+
+/// Blocks until the child execs or reports a failure. Returns `None` once
+/// exec succeeded and closed the pipe.
+fn read_child_status(status_fd: OwnedFd) -> Result<Option<(ChildStage, i32)>, TtyError> {
+    let mut report = [0u8; 5];
+    let mut filled = 0;
+
+    while filled < report.len() {
+        match unistd::read(status_fd.as_raw_fd(), &mut report[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(TtyError::Fork(format!("Failed to read child status: {}", e))),
+        }
+    }
+
+    if filled < report.len() {
+        return Ok(None);
+    }
+
+    let stage = match report[0] {
+        2 => ChildStage::Chdir,
+        3 => ChildStage::Exec,
+        _ => ChildStage::Setup,
+    };
+    let errno = i32::from_ne_bytes([report[1], report[2], report[3], report[4]]);
+    Ok(Some((stage, errno)))
+}
+
+fn set_nonblocking(fd: RawFd) -> Result<(), TtyError> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            return Err(TtyError::Io(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+fn read_master(fd: RawFd, buffer: &mut [u8]) -> io::Result<usize> {
+    let result = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+    if result >= 0 {
+        return Ok(result as usize);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Linux reports a hung-up slave as EIO rather than end-of-file
+        Some(libc::EIO) => Ok(0),
+        _ => Err(err),
+    }
+}
+
+fn write_master(fd: RawFd, data: &[u8]) -> io::Result<usize> {
+    let result = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+type ChunkFuture = Pin<Box<dyn Future<Output = Result<Bytes, TtyError>> + Send>>;
+
+/// Async byte stream over one PTY session. Reads share the session's own
+/// output subscription with `read_from_pty`; wrap it in
+/// `tokio_util::codec::FramedRead` with `BytesCodec` to get a `Stream` of
+/// output chunks.
+pub struct PtyStream {
+    session: Arc<PtySession>,
+    stats: Arc<Mutex<TtyStats>>,
+    read_future: Option<ChunkFuture>,
+    leftover: Bytes,
+}
+
+impl PtyStream {
+    pub fn id(&self) -> u64 {
+        self.session.id
+    }
+}
+
+impl AsyncRead for PtyStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if this.leftover.is_empty() {
+            let session = Arc::clone(&this.session);
+            let max = buf.remaining();
+            let future = this.read_future.get_or_insert_with(|| {
+                Box::pin(async move { session.read_chunk(max).await })
+            });
+
+            let result = ready!(future.as_mut().poll(cx));
+            this.read_future = None;
+            this.leftover = result.map_err(io::Error::other)?;
+        }
+
+        let len = this.leftover.len().min(buf.remaining());
+        buf.put_slice(&this.leftover.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PtyStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let bytes_written = ready!(self.session.poll_write(cx, data))?;
+        self.session.record_input(&data[..bytes_written]);
+
+        self.session.bytes_written.fetch_add(bytes_written as u64, Ordering::Relaxed);
+        self.stats.lock().unwrap().total_bytes_written += bytes_written as u64;
+        Poll::Ready(Ok(bytes_written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn apply_raw(attrs: &mut Termios) {
+    termios::cfmakeraw(attrs);
+    attrs.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+    attrs.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+}
+
+fn apply_cooked(attrs: &mut Termios) {
+    attrs.input_flags |= InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IXON;
+    attrs.input_flags &= !(InputFlags::INLCR | InputFlags::IGNCR | InputFlags::ISTRIP);
+    attrs.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+    attrs.control_flags |= ControlFlags::CS8 | ControlFlags::CREAD;
+    attrs.local_flags |= LocalFlags::ICANON
+        | LocalFlags::ECHO
+        | LocalFlags::ECHOE
+        | LocalFlags::ECHOK
+        | LocalFlags::ISIG
+        | LocalFlags::IEXTEN;
+    attrs.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+    attrs.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+}
+
+// Zero-copy buffer implementation is available for future use
+// Currently using direct libc calls for maximum performance
 
 pub struct TtyEngine {
     sessions: Arc<RwLock<HashMap<u64, Arc<PtySession>>>>,
     next_id: AtomicU64,
     signal_tx: broadcast::Sender<(Signal, Option<u64>)>,
+    event_tx: broadcast::Sender<SessionEvent>,
+    reaper: Arc<ChildReaper>,
     shutdown: Arc<AtomicBool>,
     stats: Arc<Mutex<TtyStats>>,
+    ui: RwLock<UiConfig>,
+    /// Base directory for `auto_record` and default recordings
+    recordings_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Default)]
@@ -170,177 +1432,297 @@ pub struct TtyStats {
 impl TtyEngine {
     pub fn new() -> Self {
         let (signal_tx, _) = broadcast::channel(1024);
-        
+        let (event_tx, _) = broadcast::channel(1024);
+
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
             next_id: AtomicU64::new(1),
             signal_tx,
+            reaper: Arc::new(ChildReaper::new(event_tx.clone())),
+            event_tx,
             shutdown: Arc::new(AtomicBool::new(false)),
             stats: Arc::new(Mutex::new(TtyStats::default())),
+            ui: RwLock::new(UiConfig::default()),
+            recordings_dir: recording::default_recordings_dir(),
+        }
+    }
+
+    /// Uses `ui` to derive cell pixel sizes for sessions without their own
+    pub fn with_ui_config(self, ui: UiConfig) -> Self {
+        *self.ui.write().unwrap() = ui;
+        self
+    }
+
+    /// Puts recordings under `dir` instead of `~/.pachyterm/sessions`
+    pub fn with_recordings_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.recordings_dir = Some(dir.into());
+        self
+    }
+
+    /// Updates the UI config, e.g. after a live reload. Sessions that derive
+    /// their cell size from it are resized to report the new pixel size, and
+    /// every session reports the new theme's colors.
+    pub fn set_ui_config(&self, ui: UiConfig) {
+        let cell = CellSize::from_ui(&ui);
+        let theme = Theme::from_ui(&ui);
+        *self.ui.write().unwrap() = ui;
+
+        let sessions: Vec<_> = self.sessions.read().unwrap().values().cloned().collect();
+        for session in &sessions {
+            *session.theme.lock().unwrap() = theme;
+        }
+        for session in sessions.into_iter().filter(|session| !session.pinned_cell_size.load(Ordering::Relaxed)) {
+            *session.cell_size.lock().unwrap() = cell;
+            let current = session.window_size();
+            self.request_resize(session, WindowSize::new(current.rows, current.cols, cell));
         }
     }
 
-    pub async fn create_pty(&self, config: PtyConfig) -> Result<u64, TtyError> {
+    pub async fn create_pty(&self, mut config: PtyConfig) -> Result<u64, TtyError> {
         let start = Instant::now();
+        if let (None, Some(parent)) = (&config.working_dir, config.inherit_cwd_from) {
+            config.working_dir = self.session(parent)?.cwd().map(|cwd| cwd.to_string_lossy().into_owned());
+        }
         
-        // Create PTY using libc directly for better compatibility
-        let master_fd = unsafe {
-            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
-            if fd == -1 {
-                return Err(TtyError::PtyCreation("Failed to create PTY master".to_string()));
-            }
-            
-            if libc::grantpt(fd) == -1 {
-                libc::close(fd);
-                return Err(TtyError::PtyCreation("Failed to grant PTY".to_string()));
-            }
-            
-            if libc::unlockpt(fd) == -1 {
-                libc::close(fd);
-                return Err(TtyError::PtyCreation("Failed to unlock PTY".to_string()));
-            }
-            
-            fd
-        };
+        let master = open_master()?;
+        let master_fd = master.as_raw_fd();
 
         // Set initial window size
-        let winsize = libc::winsize {
-            ws_row: config.rows,
-            ws_col: config.cols,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
-        };
-        
+        let cell_size = config.cell_size.unwrap_or_else(|| CellSize::from_ui(&self.ui.read().unwrap()));
+        let window_size = WindowSize::new(config.rows, config.cols, cell_size);
+        let winsize = window_size.to_winsize();
+
         unsafe {
             libc::ioctl(master_fd, libc::TIOCSWINSZ, &winsize);
         }
 
-        // Fork process
-        let fork_result = unsafe { unistd::fork() }
-            .map_err(|e| TtyError::Fork(e.to_string()))?;
-        
-        match fork_result {
-            ForkResult::Parent { child } => {
-                // Parent process - create session
-                let session_id = self.next_id.fetch_add(1, Ordering::Relaxed);
-                let session = Arc::new(PtySession::new(session_id, master_fd, child));
-                
-                self.sessions.write().unwrap().insert(session_id, session.clone());
-                
-                // Start monitoring this session
-                self.start_session_monitor(session.clone()).await;
-                
-                // Update stats
-                {
-                    let mut stats = self.stats.lock().unwrap();
-                    stats.sessions_created += 1;
+        // The child learns its own id through PACHYTERM_SESSION_ID
+        let session_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Everything the child needs is allocated now: after fork only
+        // async-signal-safe calls are allowed in a multithreaded process.
+        // The child's arguments are raw pointers, which must not be held
+        // across an await.
+        let (child, status_read, program_path) = {
+            let spawn = ChildSpawn::prepare(&config, session_id, &slave_path(master_fd)?)?;
+            let program_path = spawn.program.to_string_lossy().into_owned();
+
+            // SIGCHLD must be hooked before the first child can exit
+            self.reaper.ensure_started()?;
+
+            // The child reports setup and exec failures through this pipe; a
+            // successful exec closes it without writing anything
+            let (status_read, status_write) = unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+                .map_err(|e| TtyError::Fork(format!("Failed to create status pipe: {}", e)))?;
+
+            // Fork process
+            let fork_result = unsafe { unistd::fork() }
+                .map_err(|e| TtyError::Fork(e.to_string()))?;
+
+            match fork_result {
+                // The write end closes with this block, so only the child
+                // holds it
+                ForkResult::Parent { child } => (child, status_read, program_path),
+                ForkResult::Child => {
+                    // Child process - setup and exec shell
+                    unsafe { spawn.exec(status_write.as_raw_fd()) }
                 }
-                
-                let creation_time = start.elapsed();
-                debug!("PTY {} created in {:?}", session_id, creation_time);
-                
-                Ok(session_id)
             }
-            ForkResult::Child => {
-                // Child process - setup and exec shell
-                self.setup_child_process(master_fd, &config).await?;
-                unreachable!("Child process should have exec'd");
+        };
+
+        if let Some((stage, errno)) = wait_for_exec(child, status_read).await? {
+            return Err(match stage {
+                ChildStage::Chdir => TtyError::Exec {
+                    path: config.working_dir.clone().unwrap_or_default(),
+                    errno,
+                },
+                ChildStage::Exec => TtyError::Exec { path: program_path, errno },
+                ChildStage::Setup => TtyError::Fork(format!(
+                    "Child setup failed: {}",
+                    io::Error::from_raw_os_error(errno)
+                )),
+            });
+        }
+
+        // Parent process - create session
+        let mut session = match PtySession::new(session_id, master.into_raw_fd(), child) {
+            Ok(session) => session,
+            Err(e) => {
+                // The child is running but was never registered with the
+                // reaper, so end its process group and collect it here
+                let _ = signal::killpg(child, Signal::SIGKILL);
+                let _ = tokio::task::spawn_blocking(move || wait::waitpid(child, None)).await;
+                return Err(e);
+            }
+        };
+        session.teardown_grace = config.teardown_grace;
+        session.cell_size = Mutex::new(cell_size);
+        session.pinned_cell_size = AtomicBool::new(config.cell_size.is_some());
+        session.window.get_mut().unwrap().applied = window_size;
+        session.scrollback_limits = config.scrollback;
+        session.width_config = config.width;
+        session.answer_queries = config.answer_queries;
+        session.term = config.term.clone();
+        session.shell = config.shell.clone();
+        session.theme = Mutex::new(Theme::from_ui(&self.ui.read().unwrap()));
+        if config.read_from_start {
+            session.output.get_mut().rx = Some(session.subscribe_output());
+        }
+        if config.track_screen {
+            let mut screen = Screen::with_scrollback(config.rows, config.cols, config.scrollback);
+            screen.set_width_config(config.width);
+            *session.output_model.get_mut().unwrap() = OutputModel::Screen(Box::new(screen));
+        }
+        // Before the pump starts, so the first prompt is recorded
+        if let Some(options) = config.auto_record {
+            if let Err(e) = self.start_session_recording(&session, None, options) {
+                warn!("Failed to start recording PTY {}: {}", session_id, e);
             }
         }
+        let session = Arc::new(session);
+
+        self.sessions.write().unwrap().insert(session_id, session.clone());
+
+        let _ = self.event_tx.send(SessionEvent::Created { id: session_id, pid: child.as_raw() });
+
+        // Start monitoring this session
+        self.start_output_pump(session.clone());
+        self.reaper.watch(session);
+
+        // Update stats
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.sessions_created += 1;
+        }
+
+        let creation_time = start.elapsed();
+        debug!("PTY {} created in {:?}", session_id, creation_time);
+
+        Ok(session_id)
     }
 
-    async fn setup_child_process(&self, master_fd: RawFd, config: &PtyConfig) -> Result<(), TtyError> {
+    /// Opens an asciicast recording as a session that plays it back. Its
+    /// output, screen, events and commands behave as a live session's; it
+    /// just has no process. Input is accepted and discarded.
+    pub async fn open_replay(&self, path: impl AsRef<Path>, options: ReplayOptions) -> Result<u64, TtyError> {
+        let cast = Cast::parse(&tokio::fs::read_to_string(path.as_ref()).await?)?;
+        let master = open_master()?;
+        let slave = open_slave(master.as_raw_fd())?;
+
+        let cell_size = CellSize::from_ui(&self.ui.read().unwrap());
+        let window_size = WindowSize::new(cast.rows, cast.cols, cell_size);
         unsafe {
-            // Create new session
-            if libc::setsid() == -1 {
-                return Err(TtyError::Fork("Failed to create new session".to_string()));
-            }
-            
-            // Get slave PTY name
-            let slave_name = libc::ptsname(master_fd);
-            if slave_name.is_null() {
-                return Err(TtyError::Fork("Failed to get slave PTY name".to_string()));
-            }
-            
-            // Open slave PTY
-            let slave_fd = libc::open(slave_name, libc::O_RDWR);
-            if slave_fd == -1 {
-                return Err(TtyError::Fork("Failed to open slave PTY".to_string()));
-            }
-            
-            // Redirect stdin, stdout, stderr to slave PTY
-            libc::dup2(slave_fd, 0); // stdin
-            libc::dup2(slave_fd, 1); // stdout
-            libc::dup2(slave_fd, 2); // stderr
-            
-            // Close the slave fd and master fd as they're now duplicated
-            libc::close(slave_fd);
-            libc::close(master_fd);
-            
-            // Set working directory
-            if let Some(ref dir) = config.working_dir {
-                let c_dir = std::ffi::CString::new(dir.as_str()).unwrap();
-                libc::chdir(c_dir.as_ptr());
-            }
-            
-            // Set environment variables
-            for (key, value) in &config.env {
-                let c_key = std::ffi::CString::new(key.as_str()).unwrap();
-                let c_value = std::ffi::CString::new(value.as_str()).unwrap();
-                libc::setenv(c_key.as_ptr(), c_value.as_ptr(), 1);
-            }
-            
-            // Prepare arguments
-            let mut args = vec![config.shell.clone()];
-            args.extend(config.args.iter().cloned());
-            
-            let c_args: Vec<std::ffi::CString> = args
-                .iter()
-                .map(|s| std::ffi::CString::new(s.as_str()).unwrap())
-                .collect();
-            
-            let mut c_argv: Vec<*const libc::c_char> = c_args
-                .iter()
-                .map(|s| s.as_ptr())
-                .collect();
-            c_argv.push(std::ptr::null());
-            
-            // Execute shell
-            let c_shell = std::ffi::CString::new(config.shell.as_str()).unwrap();
-            libc::execv(c_shell.as_ptr(), c_argv.as_ptr());
-            
-            // If we get here, exec failed
-            libc::exit(1);
+            libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &window_size.to_winsize());
         }
+
+        let session_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut session = PtySession::new(session_id, master.into_raw_fd(), Pid::from_raw(0))?;
+        session.cell_size = Mutex::new(cell_size);
+        session.window.get_mut().unwrap().applied = window_size;
+        // Replies would only be discarded with the rest of the input
+        session.answer_queries = false;
+        session.theme = Mutex::new(Theme::from_ui(&self.ui.read().unwrap()));
+        if options.track_screen {
+            let screen = Screen::with_scrollback(cast.rows, cast.cols, session.scrollback_limits);
+            *session.output_model.get_mut().unwrap() = OutputModel::Screen(Box::new(screen));
+        }
+        let timeline = Arc::new(Mutex::new(Timeline::new(cast, &options)));
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        session.replay = Some(ReplayHandle { timeline: Arc::clone(&timeline), commands });
+        let session = Arc::new(session);
+
+        self.sessions.write().unwrap().insert(session_id, session.clone());
+        let _ = self.event_tx.send(SessionEvent::Created { id: session_id, pid: 0 });
+
+        self.start_output_pump(session.clone());
+        let player = ReplayPlayer {
+            session,
+            slave: AsyncFd::new(slave)?,
+            timeline,
+            event_tx: self.event_tx.clone(),
+            written: 0,
+            finished_sent: false,
+        };
+        tokio::spawn(player.run(command_rx));
+
+        self.stats.lock().unwrap().sessions_created += 1;
+        debug!("PTY {} replaying {}", session_id, path.as_ref().display());
+        Ok(session_id)
     }
 
-    async fn start_session_monitor(&self, session: Arc<PtySession>) {
-        let sessions = Arc::clone(&self.sessions);
+    fn start_output_pump(&self, session: Arc<PtySession>) {
         let stats = Arc::clone(&self.stats);
-        let shutdown = Arc::clone(&self.shutdown);
-        
+        let event_tx = self.event_tx.clone();
+
         tokio::spawn(async move {
-            let mut check_interval = tokio::time::interval(Duration::from_millis(100));
-            
-            while !shutdown.load(Ordering::Relaxed) && session.is_alive() {
-                check_interval.tick().await;
-                
-                // Check if child process is still alive
-                match wait::waitpid(session.child_pid, Some(wait::WaitPidFlag::WNOHANG)) {
-                    Ok(WaitStatus::StillAlive) => continue,
-                    Ok(_) | Err(_) => {
-                        // Process has died
-                        session.mark_dead();
-                        sessions.write().unwrap().remove(&session.id);
-                        
-                        let mut stats_guard = stats.lock().unwrap();
-                        stats_guard.sessions_destroyed += 1;
-                        
-                        info!("PTY session {} terminated", session.id);
+            let mut buffer = BytesMut::with_capacity(READ_CHUNK_SIZE);
+
+            loop {
+                buffer.resize(READ_CHUNK_SIZE, 0);
+
+                // Recorded output reaches the file even if the session goes quiet
+                let flush_due = session.recording_flush_due();
+                let flush = async {
+                    match flush_due {
+                        Some(due) => tokio::time::sleep_until(due.into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+
+                let result = tokio::select! {
+                    _ = session.closed.cancelled() => break,
+                    result = session.read_master(&mut buffer) => result,
+                    _ = flush => {
+                        session.record(Recorder::flush);
+                        continue;
+                    }
+                };
+
+                match result {
+                    Ok(0) => break,
+                    Ok(bytes_read) => {
+                        buffer.truncate(bytes_read);
+
+                        // Update the screen first so readers never see output it hasn't applied
+                        let chunk = buffer.split().freeze();
+                        let modes = session.process_output(&chunk);
+                        // Counted once applied, which replays wait for before resizing
+                        session.bytes_read.fetch_add(bytes_read as u64, Ordering::Relaxed);
+                        stats.lock().unwrap().total_bytes_read += bytes_read as u64;
+                        if let Some(modes) = modes {
+                            let _ = event_tx.send(SessionEvent::ModesChanged { id: session.id, modes });
+                        }
+                        session.record(|recorder| recorder.output(&chunk));
+                        let changes = session.take_shell_changes();
+                        if let Some(title) = changes.title {
+                            let _ = event_tx.send(SessionEvent::TitleChanged { id: session.id, title });
+                        }
+                        if let Some(cwd) = changes.cwd {
+                            let _ = event_tx.send(SessionEvent::CwdChanged { id: session.id, cwd });
+                        }
+                        for record in session.track_commands() {
+                            let _ = event_tx.send(SessionEvent::CommandFinished {
+                                id: session.id,
+                                seq: record.seq,
+                                exit_code: record.exit_code,
+                            });
+                        }
+                        let replies = session.take_replies();
+                        if !replies.is_empty() {
+                            session.send_replies(&replies).await;
+                        }
+                        session.publish_output(chunk);
+                    }
+                    Err(e) => {
+                        warn!("Read from PTY {} failed: {}", session.id, e);
+                        stats.lock().unwrap().errors += 1;
                         break;
                     }
                 }
             }
+
+            session.record(Recorder::flush);
+            session.close_output();
+            debug!("Output pump for PTY {} stopped", session.id);
         });
     }
 
@@ -356,38 +1738,33 @@ impl TtyEngine {
             return Err(TtyError::ProcessDied { pid: session.child_pid.as_raw() });
         }
 
-        let master_fd = session.master_fd;
-        let data_vec = data.to_vec();
-        
-        // Use non-blocking write with timeout
-        let write_result = timeout(Duration::from_millis(100), async {
-            tokio::task::spawn_blocking(move || {
-                unsafe {
-                    libc::write(master_fd, data_vec.as_ptr() as *const libc::c_void, data_vec.len())
+        let mut total = 0;
+        while total < data.len() {
+            match session.write(&data[total..]).await {
+                Ok(bytes_written) => {
+                    session.record_input(&data[total..total + bytes_written]);
+                    total += bytes_written;
                 }
-            }).await.unwrap()
-        }).await;
-
-        match write_result {
-            Ok(bytes_written) => {
-                if bytes_written < 0 {
+                Err(e) => {
                     let mut stats = self.stats.lock().unwrap();
                     stats.errors += 1;
-                    Err(TtyError::Io(std::io::Error::last_os_error()))
-                } else {
-                    let bytes_written = bytes_written as usize;
-                    session.bytes_written.fetch_add(bytes_written as u64, Ordering::Relaxed);
-                    
-                    let mut stats = self.stats.lock().unwrap();
-                    stats.total_bytes_written += bytes_written as u64;
-                    
-                    Ok(bytes_written)
+                    return Err(TtyError::Io(e));
                 }
             }
-            Err(_) => Err(TtyError::Timeout { timeout_ms: 100 }),
         }
+
+        session.bytes_written.fetch_add(total as u64, Ordering::Relaxed);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.total_bytes_written += total as u64;
+
+        Ok(total)
     }
 
+    /// Waits for output and copies it into `buffer`. Returns `Ok(0)` only at
+    /// end-of-file, never because the shell is quiet. The first call
+    /// subscribes to the output unless the session was created with
+    /// `PtyConfig::read_from_start`.
     pub async fn read_from_pty(&self, pty_id: u64, buffer: &mut [u8]) -> Result<usize, TtyError> {
         let session = {
             let sessions = self.sessions.read().unwrap();
@@ -396,52 +1773,49 @@ impl TtyEngine {
                 .clone()
         };
 
-        if !session.is_alive() {
-            return Err(TtyError::ProcessDied { pid: session.child_pid.as_raw() });
-        }
+        session.read(buffer).await.inspect_err(|_| {
+            self.stats.lock().unwrap().errors += 1;
+        })
+    }
 
-        let master_fd = session.master_fd;
-        let buffer_len = buffer.len();
-        
-        // Use non-blocking read with timeout
-        let read_result = timeout(Duration::from_millis(100), async {
-            tokio::task::spawn_blocking(move || {
-                let mut temp_buffer = vec![0u8; buffer_len];
-                unsafe {
-                    let result = libc::read(master_fd, temp_buffer.as_mut_ptr() as *mut libc::c_void, buffer_len);
-                    (result, temp_buffer)
-                }
-            }).await.unwrap()
-        }).await;
-
-        match read_result {
-            Ok((bytes_read, temp_buffer)) => {
-                if bytes_read < 0 {
-                    let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
-                    if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
-                        Ok(0) // No data available
-                    } else {
-                        let mut stats = self.stats.lock().unwrap();
-                        stats.errors += 1;
-                        Err(TtyError::Io(std::io::Error::last_os_error()))
-                    }
-                } else {
-                    let bytes_read = bytes_read as usize;
-                    buffer[..bytes_read].copy_from_slice(&temp_buffer[..bytes_read]);
-                    
-                    session.bytes_read.fetch_add(bytes_read as u64, Ordering::Relaxed);
-                    
-                    let mut stats = self.stats.lock().unwrap();
-                    stats.total_bytes_read += bytes_read as u64;
-                    
-                    Ok(bytes_read)
-                }
-            }
-            Err(_) => Err(TtyError::Timeout { timeout_ms: 100 }),
-        }
+    /// Has `read_from_pty` hold the session's output from now on, without
+    /// waiting for its first call
+    pub fn start_reading(&self, pty_id: u64) -> Result<(), TtyError> {
+        self.session(pty_id)?.start_reading();
+        Ok(())
+    }
+
+    /// Returns a receiver that observes every output chunk of the session,
+    /// alongside `read_from_pty` and any other subscribers.
+    pub fn subscribe_output(&self, pty_id: u64) -> Result<broadcast::Receiver<Bytes>, TtyError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(&pty_id)
+            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
+
+        Ok(session.subscribe_output())
+    }
+
+    /// Returns an `AsyncRead`/`AsyncWrite` handle for the session.
+    pub fn pty_stream(&self, pty_id: u64) -> Result<PtyStream, TtyError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(&pty_id)
+            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
+
+        Ok(PtyStream {
+            session: Arc::clone(session),
+            stats: Arc::clone(&self.stats),
+            read_future: None,
+            leftover: Bytes::new(),
+        })
     }
 
     pub async fn send_signal(&self, pty_id: Option<u64>, signal: Signal) -> Result<(), TtyError> {
+        self.send_signal_with(pty_id, signal, SignalTarget::Child).await
+    }
+
+    /// Like `send_signal`, choosing which processes of the session receive it.
+    /// Use `SignalTarget::ForegroundGroup` to interrupt the running job.
+    pub async fn send_signal_with(&self, pty_id: Option<u64>, signal: Signal, target: SignalTarget) -> Result<(), TtyError> {
         match pty_id {
             Some(id) => {
                 let session = {
@@ -451,198 +1825,2025 @@ impl TtyEngine {
                         .clone()
                 };
                 
-                signal::kill(session.child_pid, signal)
-                    .map_err(|e| TtyError::Signal(e.to_string()))?;
+                session.signal(signal, target)?;
             }
             None => {
                 // Broadcast to all sessions
                 let sessions = self.sessions.read().unwrap();
-                for session in sessions.values() {
-                    if let Err(e) = signal::kill(session.child_pid, signal) {
+                for session in sessions.values().filter(|session| !session.is_replay()) {
+                    if let Err(e) = session.signal(signal, target) {
                         warn!("Failed to send signal to PTY {}: {}", session.id, e);
                     }
                 }
             }
         }
 
-        // Broadcast signal event
-        let _ = self.signal_tx.send((signal, pty_id));
-        
-        let mut stats = self.stats.lock().unwrap();
-        stats.signal_count += 1;
-        
-        Ok(())
+        // Broadcast signal event
+        let _ = self.signal_tx.send((signal, pty_id));
+        
+        let mut stats = self.stats.lock().unwrap();
+        stats.signal_count += 1;
+        
+        Ok(())
+    }
+
+    pub fn set_pty_mode(&self, pty_id: u64, mode: TerminalMode) -> Result<(), TtyError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(&pty_id)
+            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
+        
+        session.set_mode(mode)
+    }
+
+    pub fn get_pty_mode(&self, pty_id: u64) -> Result<TerminalMode, TtyError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(&pty_id)
+            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
+
+        Ok(session.get_mode())
+    }
+
+    /// Resizes the session keeping its cell size. Bursts are debounced:
+    /// the first resize applies at once, later ones within `RESIZE_DEBOUNCE`
+    /// collapse into a single trailing resize.
+    pub fn resize_pty(&self, pty_id: u64, rows: u16, cols: u16) -> Result<(), TtyError> {
+        let session = self.session(pty_id)?;
+        let cell = session.cell_size();
+        self.request_resize(session, WindowSize::new(rows, cols, cell));
+        Ok(())
+    }
+
+    /// Like `resize_pty`, also changing the cell pixel size for this session.
+    /// Later UI config changes no longer touch its cell size.
+    pub fn resize_pty_pixels(&self, pty_id: u64, rows: u16, cols: u16, cell: CellSize) -> Result<(), TtyError> {
+        let session = self.session(pty_id)?;
+        session.pinned_cell_size.store(true, Ordering::Relaxed);
+        *session.cell_size.lock().unwrap() = cell;
+        self.request_resize(session, WindowSize::new(rows, cols, cell));
+        Ok(())
+    }
+
+    /// Private modes the session's application has selected
+    pub fn get_modes(&self, pty_id: u64) -> Result<Modes, TtyError> {
+        Ok(self.session(pty_id)?.modes())
+    }
+
+    /// Starts recording the session as asciicast v2, to `path` or else a new
+    /// file in the recordings directory. Returns the file's path.
+    pub fn start_recording(
+        &self,
+        pty_id: u64,
+        path: Option<PathBuf>,
+        options: RecordingOptions,
+    ) -> Result<PathBuf, TtyError> {
+        let session = self.session(pty_id)?;
+        self.start_session_recording(&session, path, options)
+    }
+
+    fn start_session_recording(
+        &self,
+        session: &PtySession,
+        path: Option<PathBuf>,
+        options: RecordingOptions,
+    ) -> Result<PathBuf, TtyError> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let dir = self.recordings_dir.as_ref().ok_or(TtyError::NoRecordingsDir)?;
+                recording::recording_path(dir, session.id)
+            }
+        };
+        session.start_recording(&path, options)?;
+        Ok(path)
+    }
+
+    /// Finishes the session's recording, returning the file's path if it was
+    /// being recorded
+    pub fn stop_recording(&self, pty_id: u64) -> Result<Option<PathBuf>, TtyError> {
+        self.session(pty_id)?.stop_recording()
+    }
+
+    pub fn recording_path(&self, pty_id: u64) -> Result<Option<PathBuf>, TtyError> {
+        Ok(self.session(pty_id)?.recording_path())
+    }
+
+    /// Playback handle of a replay session
+    fn replay(&self, pty_id: u64) -> Result<ReplayHandle, TtyError> {
+        self.session(pty_id)?.replay.clone().ok_or(TtyError::NotAReplay { id: pty_id })
+    }
+
+    /// Changes a replay's timeline and lets its task pick the change up
+    fn update_replay(&self, pty_id: u64, f: impl FnOnce(&mut Timeline)) -> Result<(), TtyError> {
+        let replay = self.replay(pty_id)?;
+        f(&mut replay.timeline.lock().unwrap());
+        let _ = replay.commands.send(ReplayCommand::Wake);
+        Ok(())
+    }
+
+    pub fn pause_replay(&self, pty_id: u64) -> Result<(), TtyError> {
+        self.update_replay(pty_id, |timeline| timeline.set_paused(true))
+    }
+
+    pub fn resume_replay(&self, pty_id: u64) -> Result<(), TtyError> {
+        self.update_replay(pty_id, |timeline| timeline.set_paused(false))
+    }
+
+    pub fn set_replay_speed(&self, pty_id: u64, speed: ReplaySpeed) -> Result<(), TtyError> {
+        self.update_replay(pty_id, |timeline| timeline.set_speed(speed))
+    }
+
+    /// Moves a replay to `position` in recorded time. Returns once the
+    /// screen shows that point; seeking backwards replays from the start.
+    pub async fn seek_replay(&self, pty_id: u64, position: Duration) -> Result<(), TtyError> {
+        let replay = self.replay(pty_id)?;
+        let (done, seeked) = oneshot::channel();
+        if replay.commands.send(ReplayCommand::Seek(position, done)).is_ok() {
+            // Fails only if the session closed meanwhile
+            let _ = seeked.await;
+        }
+        Ok(())
+    }
+
+    pub fn replay_status(&self, pty_id: u64) -> Result<ReplayStatus, TtyError> {
+        let status = self.replay(pty_id)?.timeline.lock().unwrap().status();
+        Ok(status)
+    }
+
+    /// The session's current directory, from OSC 7 or else its foreground
+    /// process
+    pub fn session_cwd(&self, pty_id: u64) -> Result<Option<PathBuf>, TtyError> {
+        Ok(self.session(pty_id)?.cwd())
+    }
+
+    /// Window title set through OSC 0/2
+    pub fn session_title(&self, pty_id: u64) -> Result<String, TtyError> {
+        Ok(self.session(pty_id)?.title())
+    }
+
+    /// Commands the session's shell marked with OSC 133, oldest first
+    pub fn command_records(&self, pty_id: u64) -> Result<Vec<CommandRecord>, TtyError> {
+        Ok(self.session(pty_id)?.command_records())
+    }
+
+    pub fn last_command(&self, pty_id: u64) -> Result<Option<CommandRecord>, TtyError> {
+        Ok(self.session(pty_id)?.last_command())
+    }
+
+    /// The most recent command that exited with a non-zero code, with its
+    /// output
+    pub fn last_failed_command(&self, pty_id: u64) -> Result<Option<CommandRecord>, TtyError> {
+        Ok(self.session(pty_id)?.last_failed_command())
+    }
+
+    /// Starts a screen model for a session created with `track_screen` off.
+    /// It only reflects output from this point on.
+    pub fn attach_screen(&self, pty_id: u64) -> Result<(), TtyError> {
+        self.session(pty_id)?.attach_screen();
+        Ok(())
+    }
+
+    /// Runs `f` on the session's screen model
+    pub fn with_screen<R>(&self, pty_id: u64, f: impl FnOnce(&Screen) -> R) -> Result<R, TtyError> {
+        self.session(pty_id)?
+            .with_screen(f)
+            .ok_or(TtyError::ScreenNotAttached { id: pty_id })
+    }
+
+    /// Visible screen text, one line per row
+    pub fn screen_text(&self, pty_id: u64) -> Result<String, TtyError> {
+        self.with_screen(pty_id, Screen::text)
+    }
+
+    pub fn screen_cells(&self, pty_id: u64) -> Result<Vec<Vec<Cell>>, TtyError> {
+        self.with_screen(pty_id, Screen::cells)
+    }
+
+    /// Number of lines in the session's scrollback
+    pub fn scrollback_len(&self, pty_id: u64) -> Result<usize, TtyError> {
+        self.with_screen(pty_id, |screen| screen.scrollback().len())
+    }
+
+    /// Scrollback lines in `range`, counting from the oldest line held.
+    /// Indices past the end are skipped.
+    pub fn scrollback_lines(&self, pty_id: u64, range: std::ops::Range<usize>) -> Result<Vec<String>, TtyError> {
+        self.with_screen(pty_id, |screen| range.filter_map(|index| screen.scrollback().line_text(index)).collect())
+    }
+
+    /// The last `count` lines of output, continuing from scrollback into
+    /// the visible screen
+    pub fn recent_lines(&self, pty_id: u64, count: usize) -> Result<Vec<String>, TtyError> {
+        self.with_screen(pty_id, |screen| screen.recent_lines(count))
+    }
+
+    /// The lines an agent request for this session should include as context
+    pub fn agent_context(&self, pty_id: u64, agent: &AgentConfig) -> Result<Vec<String>, TtyError> {
+        self.recent_lines(pty_id, agent.context_lines as usize)
+    }
+
+    /// Approximate memory held by the session's scrollback
+    pub fn scrollback_memory(&self, pty_id: u64) -> Result<usize, TtyError> {
+        self.with_screen(pty_id, |screen| screen.scrollback().memory_usage())
+    }
+
+    /// Approximate scrollback memory across all sessions
+    pub fn total_scrollback_memory(&self) -> usize {
+        let sessions: Vec<_> = self.sessions.read().unwrap().values().cloned().collect();
+        sessions
+            .iter()
+            .filter_map(|session| session.with_screen(|screen| screen.scrollback().memory_usage()))
+            .sum()
+    }
+
+    pub fn get_window_size(&self, pty_id: u64) -> Result<WindowSize, TtyError> {
+        Ok(self.session(pty_id)?.window_size())
+    }
+
+    fn session(&self, pty_id: u64) -> Result<Arc<PtySession>, TtyError> {
+        self.sessions.read().unwrap()
+            .get(&pty_id)
+            .cloned()
+            .ok_or(TtyError::PtyNotFound { id: pty_id })
+    }
+
+    fn request_resize(&self, session: Arc<PtySession>, size: WindowSize) {
+        let mut window = session.window.lock().unwrap();
+        if window.pending.is_some() {
+            // A timer is already armed and will pick this size up
+            window.pending = Some(size);
+            return;
+        }
+        if window.applied == size {
+            return;
+        }
+
+        let wait_until = match (window.applied_at, tokio::runtime::Handle::try_current()) {
+            (Some(applied_at), Ok(_)) if applied_at.elapsed() < RESIZE_DEBOUNCE => applied_at + RESIZE_DEBOUNCE,
+            _ => {
+                session.apply_window_size(&mut window, size);
+                let _ = self.event_tx.send(SessionEvent::Resized { id: session.id, size });
+                return;
+            }
+        };
+        window.pending = Some(size);
+        drop(window);
+
+        let event_tx = self.event_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(wait_until.into()).await;
+            if session.closed.is_cancelled() {
+                return;
+            }
+
+            let mut window = session.window.lock().unwrap();
+            if let Some(size) = window.pending.take() {
+                session.apply_window_size(&mut window, size);
+                let _ = event_tx.send(SessionEvent::Resized { id: session.id, size });
+            }
+        });
+    }
+
+    pub fn get_pty_stats(&self, pty_id: u64) -> Result<(u64, u64, Duration), TtyError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(&pty_id)
+            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
+        
+        Ok(session.get_stats())
+    }
+
+    /// Foreground job of a session's terminal; the shell itself when idle
+    pub fn foreground_process(&self, pty_id: u64) -> Result<ForegroundProcess, TtyError> {
+        let session = {
+            let sessions = self.sessions.read().unwrap();
+            sessions.get(&pty_id)
+                .ok_or(TtyError::PtyNotFound { id: pty_id })?
+                .clone()
+        };
+
+        if !session.is_alive() {
+            return Err(TtyError::ProcessDied { pid: session.child_pid.as_raw() });
+        }
+
+        ForegroundProcess::from_proc(session.foreground_pgrp()?)
+    }
+
+    /// Foreground process of every live session, for tab titles
+    pub fn foreground_processes(&self) -> HashMap<u64, ForegroundProcess> {
+        self.list_sessions()
+            .into_iter()
+            .filter_map(|id| self.foreground_process(id).ok().map(|process| (id, process)))
+            .collect()
+    }
+
+    pub fn list_sessions(&self) -> Vec<u64> {
+        self.sessions.read().unwrap().keys().copied().collect()
+    }
+
+    pub fn get_session_count(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    /// Hangs up the session like a closing terminal: its whole process tree
+    /// gets SIGHUP, and anything still running after the session's grace
+    /// period is SIGKILLed and listed in the report.
+    ///
+    /// Sessions stay registered after their child exits, so their output,
+    /// screen and exit status can still be read; whoever created one is
+    /// responsible for destroying it. The exit status goes with the session
+    /// and is returned in the report.
+    pub async fn destroy_pty(&self, pty_id: u64) -> Result<TeardownReport, TtyError> {
+        let session = {
+            let mut sessions = self.sessions.write().unwrap();
+            sessions.remove(&pty_id)
+                .ok_or(TtyError::PtyNotFound { id: pty_id })?
+        };
+
+        let targets = hang_up(&session);
+        let mut report = TeardownReport {
+            hung_up: targets.iter().map(|pid| pid.as_raw()).collect(),
+            killed: vec![],
+            status: None,
+        };
+
+        let deadline = Instant::now() + session.teardown_grace;
+        let mut remaining = survivors(&targets);
+        while !remaining.is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(TEARDOWN_POLL_INTERVAL).await;
+            remaining = survivors(&remaining);
+        }
+
+        if !remaining.is_empty() {
+            warn!("Force killing {} processes of PTY {}", remaining.len(), pty_id);
+            force_kill(&remaining);
+            report.killed = remaining.iter().map(|pid| pid.as_raw()).collect();
+        }
+
+        if session.is_replay() {
+            // Nothing to reap
+            session.exited.cancel();
+        } else if tokio::time::timeout(FORCE_KILL_TIMEOUT, session.wait_exit()).await.is_err() {
+            error!("PTY {} process {} did not exit after SIGKILL", pty_id, session.child_pid);
+        }
+        report.status = self.reaper.forget(pty_id);
+
+        session.mark_dead();
+        session.closed.cancel();
+        if let Err(e) = session.stop_recording() {
+            warn!("Failed to finish recording PTY {}: {}", pty_id, e);
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.sessions_destroyed += 1;
+
+        let _ = self.event_tx.send(SessionEvent::Destroyed { id: pty_id });
+        info!("PTY session {} destroyed", pty_id);
+        Ok(report)
+    }
+
+    pub async fn shutdown(&self) -> Result<(), TtyError> {
+        info!("Shutting down TTY engine");
+        
+        self.shutdown.store(true, Ordering::Relaxed);
+        
+        // Get all session IDs
+        let session_ids: Vec<u64> = self.sessions.read().unwrap().keys().copied().collect();
+        
+        // Destroy all sessions
+        for id in session_ids {
+            if let Err(e) = self.destroy_pty(id).await {
+                error!("Failed to destroy PTY {}: {}", id, e);
+            }
+        }
+        
+        info!("TTY engine shutdown complete");
+        Ok(())
+    }
+
+    pub fn get_stats(&self) -> TtyStats {
+        let stats = self.stats.lock().unwrap();
+        TtyStats {
+            sessions_created: stats.sessions_created,
+            sessions_destroyed: stats.sessions_destroyed,
+            total_bytes_read: stats.total_bytes_read,
+            total_bytes_written: stats.total_bytes_written,
+            signal_count: stats.signal_count,
+            errors: stats.errors,
+        }
+    }
+
+    pub fn subscribe_signals(&self) -> broadcast::Receiver<(Signal, Option<u64>)> {
+        self.signal_tx.subscribe()
+    }
+
+    /// Lifecycle events for all sessions: creation, child exit and teardown
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SessionEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Exit status of a session's child, `None` while it is still running.
+    /// Available until the session is destroyed.
+    pub fn exit_status(&self, pty_id: u64) -> Result<Option<ExitStatus>, TtyError> {
+        if let Some(status) = self.reaper.exit_status(pty_id) {
+            return Ok(Some(status));
+        }
+
+        if self.sessions.read().unwrap().contains_key(&pty_id) {
+            Ok(None)
+        } else {
+            Err(TtyError::PtyNotFound { id: pty_id })
+        }
+    }
+}
+
+/// Writes a recording's output into the slave side of a replay session, so
+/// it reaches the session through the same pump as a live program's
+struct ReplayPlayer {
+    session: Arc<PtySession>,
+    slave: AsyncFd<OwnedFd>,
+    timeline: Arc<Mutex<Timeline>>,
+    event_tx: broadcast::Sender<SessionEvent>,
+    /// Output bytes written so far, to compare with what the pump read
+    written: u64,
+    finished_sent: bool,
+}
+
+impl ReplayPlayer {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<ReplayCommand>) {
+        let closed = self.session.closed.clone();
+        loop {
+            if self.timeline.lock().unwrap().is_finished() && !self.finished_sent {
+                self.finished_sent = true;
+                let _ = self.event_tx.send(SessionEvent::ReplayFinished { id: self.session.id });
+            }
+            tokio::select! {
+                _ = closed.cancelled() => break,
+                open = self.step(&mut commands) => if !open { break },
+            }
+        }
+        debug!("Replay for PTY {} stopped", self.session.id);
+    }
+
+    /// Waits for the next event, command or input. Returns false once the
+    /// engine dropped the session.
+    async fn step(&mut self, commands: &mut mpsc::UnboundedReceiver<ReplayCommand>) -> bool {
+        let delay = self.timeline.lock().unwrap().next_delay();
+        let due = async {
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(ReplayCommand::Seek(position, done)) => {
+                    self.seek(position).await;
+                    let _ = done.send(());
+                }
+                Some(ReplayCommand::Wake) => {}
+                None => return false,
+            },
+            _ = due => {
+                let event = self.timeline.lock().unwrap().advance();
+                if let Some(event) = event {
+                    self.play(event).await;
+                }
+            }
+            guard = self.slave.readable() => {
+                // Input, and anything else written to the master, goes nowhere
+                let mut buffer = [0; 1024];
+                if let Ok(mut guard) = guard {
+                    let _ = guard.try_io(|fd| read_master(fd.as_raw_fd(), &mut buffer));
+                }
+            }
+        }
+        true
+    }
+
+    async fn seek(&mut self, position: Duration) {
+        let (restart, events) = self.timeline.lock().unwrap().seek(position);
+        if restart {
+            // Marks in output already written belong to the old pass
+            self.catch_up().await;
+            *self.session.commands.lock().unwrap() = CommandTracker::new();
+            self.write(b"\x1bc\x1b[3J").await;
+            let (cols, rows) = {
+                let timeline = self.timeline.lock().unwrap();
+                (timeline.cast().cols, timeline.cast().rows)
+            };
+            self.resize(cols, rows).await;
+            self.finished_sent = false;
+        }
+        for event in events {
+            self.play(event).await;
+        }
+        self.catch_up().await;
+    }
+
+    async fn play(&mut self, event: CastEvent) {
+        match event {
+            CastEvent::Output(text) => self.write(text.as_bytes()).await,
+            CastEvent::Resize { cols, rows } => self.resize(cols, rows).await,
+            // The recorded program already echoed anything it showed
+            CastEvent::Input(_) => {}
+        }
+    }
+
+    async fn write(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let Ok(mut guard) = self.slave.writable().await else { return };
+            match guard.try_io(|fd| write_master(fd.as_raw_fd(), data)) {
+                Ok(Ok(written)) => {
+                    data = &data[written..];
+                    self.written += written as u64;
+                }
+                Ok(Err(e)) => {
+                    warn!("Replay write to PTY {} failed: {}", self.session.id, e);
+                    return;
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Resizes once the output before it has been applied, so the screen
+    /// reflows the same text the recording did
+    async fn resize(&mut self, cols: u16, rows: u16) {
+        self.catch_up().await;
+        let size = WindowSize::new(rows, cols, self.session.cell_size());
+        if size != self.session.window_size() {
+            let _ = self.session.set_window_size(size);
+            let _ = self.event_tx.send(SessionEvent::Resized { id: self.session.id, size });
+        }
+    }
+
+    /// Waits until the output pump has processed everything written
+    async fn catch_up(&self) {
+        let caught_up = async {
+            while self.session.bytes_read.load(Ordering::Relaxed) < self.written {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        if tokio::time::timeout(REPLAY_CATCH_UP_TIMEOUT, caught_up).await.is_err() {
+            warn!("Output pump for replay PTY {} fell behind", self.session.id);
+        }
+    }
+}
+
+/// How long to wait for a SIGKILLed child to be reaped before giving up
+const FORCE_KILL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often teardown checks whether hung-up processes have exited
+const TEARDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sends SIGHUP and SIGCONT to every process belonging to the session, the
+/// way the kernel does when a terminal closes, and returns them.
+fn hang_up(session: &PtySession) -> Vec<Pid> {
+    if session.is_replay() {
+        return Vec::new();
+    }
+    let targets = session_processes(session.child_pid);
+
+    // The whole group first, so nothing forked meanwhile is missed
+    let _ = signal::killpg(session.child_pid, Signal::SIGHUP);
+    if let Ok(pgrp) = session.foreground_pgrp() {
+        let _ = signal::killpg(pgrp, Signal::SIGHUP);
+    }
+    for &pid in &targets {
+        let _ = signal::kill(pid, Signal::SIGHUP);
+        // Stopped jobs only act on the hangup once continued
+        let _ = signal::kill(pid, Signal::SIGCONT);
+    }
+
+    targets
+}
+
+/// The pids in `pids` that are still running
+fn survivors(pids: &[Pid]) -> Vec<Pid> {
+    pids.iter().copied().filter(|&pid| process_running(pid)).collect()
+}
+
+fn force_kill(pids: &[Pid]) {
+    for &pid in pids {
+        let _ = signal::kill(pid, Signal::SIGKILL);
+    }
+}
+
+/// Running, non-zombie process
+fn process_running(pid: Pid) -> bool {
+    if cfg!(target_os = "linux") {
+        matches!(read_proc_stat(pid), Some(stat) if stat.state != 'Z')
+    } else {
+        signal::kill(pid, None).is_ok()
+    }
+}
+
+/// The fields of `/proc/<pid>/stat` the engine needs
+struct ProcStat {
+    state: char,
+    ppid: i32,
+    pgrp: i32,
+    session: i32,
+}
+
+fn read_proc_stat(pid: Pid) -> Option<ProcStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces and parentheses, so split after
+    // the last ')'
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    let pgrp = fields.next()?.parse().ok()?;
+    let session = fields.next()?.parse().ok()?;
+    Some(ProcStat { state, ppid, pgrp, session })
+}
+
+/// A live process of group `pgid`: its leader while that is running, else
+/// the oldest remaining member
+fn group_member(pgid: Pid) -> Option<Pid> {
+    if process_running(pgid) {
+        return Some(pgid);
+    }
+
+    std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|&pid| {
+            matches!(read_proc_stat(Pid::from_raw(pid)), Some(stat) if stat.pgrp == pgid.as_raw() && stat.state != 'Z')
+        })
+        .min()
+        .map(Pid::from_raw)
+}
+
+/// Live processes of the session led by `leader`: its descendants, plus
+/// anything still in its session after being orphaned. Falls back to the
+/// leader alone where `/proc` isn't available.
+fn session_processes(leader: Pid) -> Vec<Pid> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![leader];
+    };
+
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    let mut in_session = Vec::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else {
+            continue;
+        };
+        let Some(stat) = read_proc_stat(Pid::from_raw(pid)) else {
+            continue;
+        };
+        if stat.state == 'Z' {
+            continue;
+        }
+        children.entry(stat.ppid).or_default().push(pid);
+        if stat.session == leader.as_raw() {
+            in_session.push(pid);
+        }
+    }
+
+    let mut found = vec![leader.as_raw()];
+    let mut index = 0;
+    while index < found.len() {
+        if let Some(kids) = children.get(&found[index]) {
+            found.extend(kids.iter().filter(|pid| !found.contains(pid)).copied().collect::<Vec<_>>());
+        }
+        index += 1;
+    }
+    for pid in in_session {
+        if !found.contains(&pid) {
+            found.push(pid);
+        }
+    }
+
+    found.into_iter().map(Pid::from_raw).collect()
+}
+
+/// Owns every `waitpid` call for the engine's children. A single task woken
+/// by SIGCHLD reaps whichever children have exited, so idle sessions cost
+/// nothing and `destroy_pty` never races another waiter for a status.
+struct ChildReaper {
+    children: Mutex<HashMap<Pid, Arc<PtySession>>>,
+    exit_statuses: Mutex<HashMap<u64, ExitStatus>>,
+    event_tx: broadcast::Sender<SessionEvent>,
+    started: AtomicBool,
+    stop: CancellationToken,
+}
+
+impl ChildReaper {
+    fn new(event_tx: broadcast::Sender<SessionEvent>) -> Self {
+        Self {
+            children: Mutex::new(HashMap::new()),
+            exit_statuses: Mutex::new(HashMap::new()),
+            event_tx,
+            started: AtomicBool::new(false),
+            stop: CancellationToken::new(),
+        }
+    }
+
+    fn ensure_started(self: &Arc<Self>) -> Result<(), TtyError> {
+        if self.started.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let mut sigchld = match unix_signal(SignalKind::child()) {
+            Ok(sigchld) => sigchld,
+            Err(e) => {
+                self.started.store(false, Ordering::Release);
+                return Err(TtyError::Signal(format!("Failed to install SIGCHLD handler: {}", e)));
+            }
+        };
+
+        let reaper = Arc::clone(self);
+        tokio::spawn(async move {
+            // Keep running after the engine is dropped until the children it
+            // signalled on the way out have been collected
+            let mut stopping = false;
+            loop {
+                tokio::select! {
+                    _ = sigchld.recv() => {}
+                    _ = reaper.stop.cancelled(), if !stopping => stopping = true,
+                }
+
+                reaper.reap();
+                if stopping && reaper.children.lock().unwrap().is_empty() {
+                    break;
+                }
+            }
+            debug!("Child reaper stopped");
+        });
+
+        Ok(())
+    }
+
+    fn watch(&self, session: Arc<PtySession>) {
+        self.children.lock().unwrap().insert(session.child_pid, session);
+        // The child may have exited before it was registered
+        self.reap();
+    }
+
+    fn reap(&self) {
+        let mut children = self.children.lock().unwrap();
+
+        children.retain(|pid, session| {
+            match wait::waitpid(*pid, Some(wait::WaitPidFlag::WNOHANG)) {
+                Ok(status) => match ExitStatus::from_wait_status(status) {
+                    Some(exit_status) => {
+                        self.record_exit(session, exit_status);
+                        false
+                    }
+                    None => true,
+                },
+                Err(e) => {
+                    // Not our child any more; nothing left to wait for
+                    warn!("waitpid for PTY {} failed: {}", session.id, e);
+                    session.mark_dead();
+                    session.exited.cancel();
+                    false
+                }
+            }
+        });
+    }
+
+    fn record_exit(&self, session: &PtySession, status: ExitStatus) {
+        // A child outliving its session's teardown has no one to collect it
+        if !session.closed.is_cancelled() {
+            self.exit_statuses.lock().unwrap().insert(session.id, status);
+        }
+
+        session.mark_dead();
+        session.exited.cancel();
+        let _ = self.event_tx.send(status.event(session.id));
+        info!("PTY session {} terminated: {:?}", session.id, status);
+    }
+
+    fn exit_status(&self, id: u64) -> Option<ExitStatus> {
+        self.exit_statuses.lock().unwrap().get(&id).copied()
+    }
+
+    /// Drops the status of a destroyed session
+    fn forget(&self, id: u64) -> Option<ExitStatus> {
+        self.exit_statuses.lock().unwrap().remove(&id)
+    }
+}
+
+impl Default for TtyEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TtyEngine {
+    fn drop(&mut self) {
+        // Hang up every session now; there is no runtime to wait on here, so
+        // a detached thread SIGKILLs whatever outlives the grace period
+        let mut pending = Vec::new();
+        for session in self.sessions.read().unwrap().values() {
+            pending.push((Instant::now() + session.teardown_grace, hang_up(session)));
+            session.mark_dead();
+            session.closed.cancel();
+        }
+
+        if !pending.is_empty() {
+            let spawned = std::thread::Builder::new()
+                .name("pty-teardown".to_string())
+                .spawn(move || {
+                    for (deadline, targets) in pending {
+                        let mut remaining = survivors(&targets);
+                        while !remaining.is_empty() && Instant::now() < deadline {
+                            std::thread::sleep(TEARDOWN_POLL_INTERVAL);
+                            remaining = survivors(&remaining);
+                        }
+                        force_kill(&remaining);
+                    }
+                });
+            if let Err(e) = spawned {
+                error!("Failed to start PTY teardown thread: {}", e);
+            }
+        }
+
+        self.reaper.stop.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_pty_creation() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig::default();
+        
+        let pty_id = engine.create_pty(config).await.unwrap();
+        assert!(pty_id > 0);
+        
+        let sessions = engine.list_sessions();
+        assert!(sessions.contains(&pty_id));
+        
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pty_io() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { read_from_start: true, ..PtyConfig::default() };
+        
+        let pty_id = engine.create_pty(config).await.unwrap();
+        
+        // Write to PTY
+        let test_data = b"echo hello\n";
+        let written = engine.write_to_pty(pty_id, test_data).await.unwrap();
+        assert_eq!(written, test_data.len());
+        
+        // Give some time for command to execute
+        sleep(Duration::from_millis(100)).await;
+        
+        // Read from PTY
+        let mut buffer = [0u8; 1024];
+        let read_bytes = engine.read_from_pty(pty_id, &mut buffer).await.unwrap();
+        assert!(read_bytes > 0);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_waits_for_output() {
+        let engine = Arc::new(TtyEngine::new());
+        let config = PtyConfig {
+            shell: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), "sleep 0.3; echo late; exec sleep 5".to_string()],
+            ..PtyConfig::default()
+        };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        // A quiet shell must not produce Ok(0) or a timeout error
+        let start = Instant::now();
+        let mut buffer = [0u8; 1024];
+        let read_bytes = tokio::time::timeout(Duration::from_secs(5), engine.read_from_pty(pty_id, &mut buffer))
+            .await
+            .expect("read did not wake up on output")
+            .unwrap();
+        assert!(read_bytes > 0);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reader_subscribes_on_first_read() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { read_from_start: false, ..command_config("echo early; sleep 0.5; echo late") };
+        let pty_id = engine.create_pty(config).await.unwrap();
+        let mut output = engine.subscribe_output(pty_id).unwrap();
+        recv_until(&mut output, "early").await;
+
+        // Output from before the first read was never held for it
+        let read = read_for(&engine, pty_id, Duration::from_secs(5)).await;
+        assert_eq!(read.trim(), "late");
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pty_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+        let mut stream = engine.pty_stream(pty_id).unwrap();
+        assert_eq!(stream.id(), pty_id);
+
+        stream.write_all(b"echo stream_$((40 + 2))\n").await.unwrap();
+
+        let mut output = Vec::new();
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let read_bytes = stream.read(&mut buffer).await.unwrap();
+                assert!(read_bytes > 0, "unexpected end of stream");
+                output.extend_from_slice(&buffer[..read_bytes]);
+                if String::from_utf8_lossy(&output).contains("stream_42") {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(found.is_ok(), "output never arrived: {:?}", String::from_utf8_lossy(&output));
+
+        let (bytes_read, bytes_written, _) = engine.get_pty_stats(pty_id).unwrap();
+        assert!(bytes_read >= output.len() as u64);
+        assert!(bytes_written > 0);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    async fn recv_until(rx: &mut broadcast::Receiver<Bytes>, needle: &str) -> String {
+        let mut output = Vec::new();
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            while !String::from_utf8_lossy(&output).contains(needle) {
+                output.extend_from_slice(&rx.recv().await.unwrap());
+            }
+        })
+        .await;
+        assert!(found.is_ok(), "{:?} never arrived: {:?}", needle, String::from_utf8_lossy(&output));
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_output_subscribers_see_same_stream() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+
+        let mut renderer = engine.subscribe_output(pty_id).unwrap();
+        let mut recorder = engine.subscribe_output(pty_id).unwrap();
+
+        engine.write_to_pty(pty_id, b"echo fanout_$((6 * 7))\n").await.unwrap();
+
+        recv_until(&mut renderer, "fanout_42").await;
+        recv_until(&mut recorder, "fanout_42").await;
+
+        // The pull-based reader still gets its own copy
+        let mut buffer = [0u8; 1024];
+        assert!(engine.read_from_pty(pty_id, &mut buffer).await.unwrap() > 0);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_told_it_lagged() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+
+        let mut slow = engine.subscribe_output(pty_id).unwrap();
+
+        engine.write_to_pty(pty_id, b"seq 1 300000\n").await.unwrap();
+
+        // Wait for the output to stop growing without consuming any of it
+        let mut last_read = 0;
+        for _ in 0..50 {
+            sleep(Duration::from_millis(200)).await;
+            let (bytes_read, _, _) = engine.get_pty_stats(pty_id).unwrap();
+            if bytes_read > 1_000_000 && bytes_read == last_read {
+                break;
+            }
+            last_read = bytes_read;
+        }
+
+        assert!(matches!(slow.recv().await, Err(RecvError::Lagged(skipped)) if skipped > 0));
+
+        let mut buffer = [0u8; 1024];
+        let result = engine.read_from_pty(pty_id, &mut buffer).await;
+        assert!(matches!(result, Err(TtyError::OutputLagged { id, .. }) if id == pty_id));
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    fn command_config(script: &str) -> PtyConfig {
+        PtyConfig {
+            shell: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            read_from_start: true,
+            ..PtyConfig::default()
+        }
+    }
+
+    async fn next_event(events: &mut broadcast::Receiver<SessionEvent>) -> SessionEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no session event within 5s")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events_report_exit_code() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+
+        let pty_id = engine.create_pty(command_config("echo bye; exit 3")).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, SessionEvent::Created { id, .. } if id == pty_id));
+        assert_eq!(next_event(&mut events).await, SessionEvent::Exited { id: pty_id, code: 3 });
+        assert_eq!(engine.exit_status(pty_id).unwrap(), Some(ExitStatus::Exited(3)));
+
+        // Output written before the exit can still be drained
+        let output = read_for(&engine, pty_id, Duration::from_secs(1)).await;
+        assert!(output.contains("bye"), "lost output: {:?}", output);
+
+        let report = engine.destroy_pty(pty_id).await.unwrap();
+        assert_eq!(next_event(&mut events).await, SessionEvent::Destroyed { id: pty_id });
+        assert_eq!(report.status, Some(ExitStatus::Exited(3)));
+        assert!(matches!(engine.exit_status(pty_id), Err(TtyError::PtyNotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events_report_signal() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+
+        let pty_id = engine.create_pty(command_config("kill -TERM $$")).await.unwrap();
+        next_event(&mut events).await;
+        assert_eq!(
+            next_event(&mut events).await,
+            SessionEvent::Signaled { id: pty_id, signal: Signal::SIGTERM, core_dumped: false }
+        );
+
+        let status = engine.exit_status(pty_id).unwrap().unwrap();
+        assert_eq!(status.code(), None);
+        assert!(!status.success());
+
+        engine.destroy_pty(pty_id).await.unwrap();
+        assert!(matches!(engine.exit_status(999), Err(TtyError::PtyNotFound { id: 999 })));
+    }
+
+    #[tokio::test]
+    async fn test_reaper_collects_simultaneous_exits() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+
+        let mut expected = HashMap::new();
+        for code in 0..10 {
+            let pty_id = engine.create_pty(command_config(&format!("exit {}", code))).await.unwrap();
+            expected.insert(pty_id, code);
+        }
+
+        // SIGCHLDs coalesce, so every exit must be found by the sweep
+        let mut exited = HashMap::new();
+        while exited.len() < expected.len() {
+            if let SessionEvent::Exited { id, code } = next_event(&mut events).await {
+                exited.insert(id, code);
+            }
+        }
+        assert_eq!(exited, expected);
+
+        for (pty_id, code) in expected {
+            assert_eq!(engine.exit_status(pty_id).unwrap(), Some(ExitStatus::Exited(code)));
+            engine.destroy_pty(pty_id).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_destroy_reports_exit_status() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+        next_lifecycle_event(&mut events).await;
+
+        let report = engine.destroy_pty(pty_id).await.unwrap();
+
+        let status = report.status.expect("exit status lost on destroy");
+        assert!(matches!(engine.exit_status(pty_id), Err(TtyError::PtyNotFound { .. })));
+        assert!(matches!(status, ExitStatus::Signaled { .. }));
+        // The shell's prompt also announces modes, which aren't of interest here
+        assert_eq!(next_lifecycle_event(&mut events).await, status.event(pty_id));
+        assert_eq!(next_lifecycle_event(&mut events).await, SessionEvent::Destroyed { id: pty_id });
+    }
+
+    #[tokio::test]
+    async fn test_exec_failure_is_reported() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            shell: "/nonexistent/shell".to_string(),
+            ..PtyConfig::default()
+        };
+
+        let result = engine.create_pty(config).await;
+        assert!(
+            matches!(&result, Err(TtyError::Exec { path, errno }) if path == "/nonexistent/shell" && *errno == libc::ENOENT),
+            "unexpected result: {:?}",
+            result
+        );
+        assert_eq!(engine.get_session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chdir_failure_is_reported() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            working_dir: Some("/nonexistent/dir".to_string()),
+            ..plain_shell_config()
+        };
+
+        let result = engine.create_pty(config).await;
+        assert!(
+            matches!(&result, Err(TtyError::Exec { path, errno }) if path == "/nonexistent/dir" && *errno == libc::ENOENT),
+            "unexpected result: {:?}",
+            result
+        );
+        assert_eq!(engine.get_stats().sessions_created, 0);
+    }
+
+    #[tokio::test]
+    async fn test_bare_program_name_is_resolved_from_path() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            shell: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo \"argv0=$0\"".to_string()],
+            read_from_start: true,
+            ..PtyConfig::default()
+        };
+
+        let id = engine.create_pty(config).await.unwrap();
+        let output = read_for(&engine, id, Duration::from_secs(2)).await;
+        assert!(output.contains("argv0=sh"), "unexpected output: {:?}", output);
+
+        engine.destroy_pty(id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_shell_and_argv0_override() {
+        let engine = TtyEngine::new();
+        let print_argv0 = vec!["-c".to_string(), "echo \"argv0=$0\"".to_string()];
+
+        let login = PtyConfig {
+            shell: "/bin/sh".to_string(),
+            args: print_argv0.clone(),
+            login_shell: true,
+            read_from_start: true,
+            ..PtyConfig::default()
+        };
+        let id = engine.create_pty(login).await.unwrap();
+        let output = read_for(&engine, id, Duration::from_secs(2)).await;
+        assert!(output.contains("argv0=-sh"), "unexpected output: {:?}", output);
+        engine.destroy_pty(id).await.unwrap();
+
+        let renamed = PtyConfig {
+            shell: "/bin/sh".to_string(),
+            args: print_argv0,
+            login_shell: true,
+            argv0: Some("custom".to_string()),
+            read_from_start: true,
+            ..PtyConfig::default()
+        };
+        let id = engine.create_pty(renamed).await.unwrap();
+        let output = read_for(&engine, id, Duration::from_secs(2)).await;
+        assert!(output.contains("argv0=custom"), "unexpected output: {:?}", output);
+        engine.destroy_pty(id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_mode_runs_program_instead_of_shell() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+        let config = PtyConfig {
+            shell: "/nonexistent/shell".to_string(),
+            command: Some(vec!["echo".to_string(), "from-command".to_string()]),
+            read_from_start: true,
+            ..PtyConfig::default()
+        };
+
+        let id = engine.create_pty(config).await.unwrap();
+        let output = read_for(&engine, id, Duration::from_secs(2)).await;
+        assert!(output.contains("from-command"), "unexpected output: {:?}", output);
+
+        assert!(matches!(next_event(&mut events).await, SessionEvent::Created { .. }));
+        assert!(matches!(next_event(&mut events).await, SessionEvent::Exited { code: 0, .. }));
+        engine.destroy_pty(id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_command_is_rejected() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { command: Some(vec![]), ..PtyConfig::default() };
+
+        let result = engine.create_pty(config).await;
+        assert!(matches!(result, Err(TtyError::InvalidConfig(_))), "unexpected result: {:?}", result);
+        assert_eq!(engine.get_session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_program_name_fails_lookup() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            command: Some(vec!["pachyterm-no-such-program".to_string()]),
+            ..PtyConfig::default()
+        };
+
+        let result = engine.create_pty(config).await;
+        assert!(
+            matches!(&result, Err(TtyError::Exec { path, errno }) if path == "pachyterm-no-such-program" && *errno == libc::ENOENT),
+            "unexpected result: {:?}",
+            result
+        );
+        assert_eq!(engine.get_stats().sessions_created, 0);
+    }
+
+    #[test]
+    fn test_env_policies() {
+        let parent = || {
+            [("PACHYTERM_TEST_INHERITED", "1"), ("PATH", "/usr/bin:/bin")]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        };
+
+        let inherit = PtyConfig {
+            env_unset: vec!["PACHYTERM_TEST_INHERITED".to_string()],
+            ..PtyConfig::default()
+        }
+        .build_env(1, parent());
+        assert!(!inherit.contains_key("PACHYTERM_TEST_INHERITED"));
+        assert_eq!(inherit.get("PATH").map(String::as_str), Some("/usr/bin:/bin"));
+
+        let clean = PtyConfig {
+            env_policy: EnvPolicy::Clean,
+            env: HashMap::from([("EXTRA".to_string(), "x".to_string())]),
+            ..PtyConfig::default()
+        }
+        .build_env(2, parent());
+        let mut keys: Vec<_> = clean.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            ["COLORTERM", "EXTRA", "PACHYTERM_CAPS", "PACHYTERM_SESSION_ID", "TERM", "TERM_PROGRAM", "TERM_PROGRAM_VERSION"]
+        );
+
+        let allowlist = PtyConfig {
+            env_policy: EnvPolicy::Allowlist(vec!["PACHYTERM_TEST_INHERITED".to_string()]),
+            ..PtyConfig::default()
+        }
+        .build_env(3, parent());
+        assert_eq!(allowlist.get("PACHYTERM_TEST_INHERITED").map(String::as_str), Some("1"));
+        assert!(!allowlist.contains_key("PATH"));
+    }
+
+    #[test]
+    fn test_identity_variables_cannot_be_overridden() {
+        let config = PtyConfig {
+            term: "xterm-kitty".to_string(),
+            env: HashMap::from([
+                ("TERM".to_string(), "dumb".to_string()),
+                ("PACHYTERM_SESSION_ID".to_string(), "999".to_string()),
+            ]),
+            env_unset: vec!["TERM_PROGRAM".to_string()],
+            ..PtyConfig::default()
+        };
+
+        let env = config.build_env(7, std::env::vars());
+        assert_eq!(env["TERM"], "xterm-kitty");
+        assert_eq!(env["COLORTERM"], "truecolor");
+        assert_eq!(env["TERM_PROGRAM"], "pachyterm");
+        assert_eq!(env["PACHYTERM_SESSION_ID"], "7");
+        assert_eq!(env["PACHYTERM_CAPS"], SESSION_CAPS.join(","));
+    }
+
+    #[tokio::test]
+    async fn test_child_sees_its_session_id() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            env_policy: EnvPolicy::Clean,
+            ..command_config("echo \"id=$PACHYTERM_SESSION_ID term=$TERM\"")
+        };
+
+        let id = engine.create_pty(config).await.unwrap();
+        let output = read_for(&engine, id, Duration::from_secs(2)).await;
+        assert!(output.contains(&format!("id={} term={}", id, DEFAULT_TERM)), "unexpected output: {:?}", output);
+
+        engine.destroy_pty(id).await.unwrap();
+    }
+
+    async fn wait_for_foreground(engine: &TtyEngine, pty_id: u64, name: &str) -> ForegroundProcess {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(process) = engine.foreground_process(pty_id) {
+                    if process.name == name {
+                        return process;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} never became the foreground process", name))
+    }
+
+    #[tokio::test]
+    async fn test_foreground_process_tracks_running_job() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+
+        let shell = wait_for_foreground(&engine, pty_id, "bash").await;
+        assert_eq!(shell.cmdline, ["/bin/bash", "--norc", "--noprofile"]);
+
+        engine.write_to_pty(pty_id, b"sleep 100\n").await.unwrap();
+        let job = wait_for_foreground(&engine, pty_id, "sleep").await;
+        assert_eq!(job.cmdline, ["sleep", "100"]);
+        assert_ne!(job.pgid, shell.pgid);
+        assert_eq!(engine.foreground_processes()[&pty_id], job);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_foreground_process_outlives_group_leader() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+        wait_for_foreground(&engine, pty_id, "bash").await;
+
+        // The pipeline's group is led by `true`, which exits right away
+        engine.write_to_pty(pty_id, b"true | sleep 100\n").await.unwrap();
+        let job = wait_for_foreground(&engine, pty_id, "sleep").await;
+        assert_ne!(job.pid, job.pgid);
+        assert_eq!(job.cmdline, ["sleep", "100"]);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_foreground_signal_interrupts_job_not_shell() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(plain_shell_config()).await.unwrap();
+
+        wait_for_foreground(&engine, pty_id, "bash").await;
+        engine.write_to_pty(pty_id, b"sleep 100\n").await.unwrap();
+        wait_for_foreground(&engine, pty_id, "sleep").await;
+
+        engine.send_signal_with(Some(pty_id), Signal::SIGINT, SignalTarget::ForegroundGroup).await.unwrap();
+
+        // The shell survives the interrupt and keeps taking commands
+        wait_for_foreground(&engine, pty_id, "bash").await;
+        engine.write_to_pty(pty_id, b"echo \"status=$?\"\n").await.unwrap();
+        let output = read_for(&engine, pty_id, Duration::from_millis(500)).await;
+        assert!(output.contains("status=130"), "unexpected output: {:?}", output);
+        assert_eq!(engine.exit_status(pty_id).unwrap(), None);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    /// Starts `script` and returns the pid it prints as `pid=<n>`
+    async fn spawn_reporting_pid(engine: &TtyEngine, script: &str, grace: Duration) -> (u64, Pid) {
+        let config = PtyConfig {
+            teardown_grace: grace,
+            ..command_config(script)
+        };
+        let id = engine.create_pty(config).await.unwrap();
+        let output = read_for(engine, id, Duration::from_millis(500)).await;
+        let pid = output
+            .split("pid=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|pid| pid.parse().ok())
+            .unwrap_or_else(|| panic!("no pid in output: {:?}", output));
+        (id, Pid::from_raw(pid))
+    }
+
+    #[tokio::test]
+    async fn test_teardown_hangs_up_background_jobs() {
+        let engine = TtyEngine::new();
+        let (id, job) = spawn_reporting_pid(&engine, "sleep 300 & echo pid=$!; wait", Duration::from_secs(2)).await;
+        assert!(process_running(job));
+
+        let report = engine.destroy_pty(id).await.unwrap();
+        assert!(report.is_clean(), "unexpected report: {:?}", report);
+        assert!(report.hung_up.contains(&job.as_raw()));
+        assert!(!process_running(job));
+    }
+
+    #[tokio::test]
+    async fn test_teardown_reaches_orphaned_session_members() {
+        let engine = TtyEngine::new();
+        // The subshell exits at once, leaving its sleep reparented outside the tree
+        let (id, orphan) =
+            spawn_reporting_pid(&engine, "(sleep 300 & echo pid=$!); exec sleep 300", Duration::from_secs(2)).await;
+        assert!(process_running(orphan));
+
+        let report = engine.destroy_pty(id).await.unwrap();
+        assert!(report.hung_up.contains(&orphan.as_raw()), "unexpected report: {:?}", report);
+        assert!(!process_running(orphan));
+    }
+
+    #[tokio::test]
+    async fn test_teardown_kills_processes_ignoring_hangup() {
+        let engine = TtyEngine::new();
+        let (id, job) =
+            spawn_reporting_pid(&engine, "trap '' HUP; sleep 300 & echo pid=$!; wait", Duration::from_millis(200)).await;
+
+        let start = Instant::now();
+        let report = engine.destroy_pty(id).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(report.killed.contains(&job.as_raw()), "unexpected report: {:?}", report);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!process_running(job));
+    }
+
+    #[tokio::test]
+    async fn test_drop_tears_down_process_tree() {
+        let engine = TtyEngine::new();
+        let (_, stubborn) =
+            spawn_reporting_pid(&engine, "trap '' HUP; sleep 300 & echo pid=$!; wait", Duration::from_millis(50)).await;
+        let (_, polite) = spawn_reporting_pid(&engine, "sleep 300 & echo pid=$!; wait", Duration::from_millis(50)).await;
+
+        drop(engine);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!process_running(polite));
+        assert!(!process_running(stubborn));
+    }
+
+    #[tokio::test]
+    async fn test_pty_resize() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig::default();
+        
+        let pty_id = engine.create_pty(config).await.unwrap();
+        
+        // Test resize
+        engine.resize_pty(pty_id, 50, 120).unwrap();
+        
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    /// Window size as a program on the slave side sees it
+    fn kernel_window_size(engine: &TtyEngine, pty_id: u64) -> libc::winsize {
+        let session = engine.session(pty_id).unwrap();
+        let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+        assert_ne!(unsafe { libc::ioctl(session.master_fd, libc::TIOCGWINSZ, &mut winsize) }, -1);
+        winsize
+    }
+
+    #[test]
+    fn test_cell_size_from_ui_config() {
+        assert_eq!(CellSize::from_ui(&UiConfig::default()), CellSize { width: 7, height: 14 });
+
+        let ui = UiConfig { font_size: 0, ..UiConfig::default() };
+        assert_eq!(CellSize::from_ui(&ui), CellSize { width: 1, height: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_pixel_sizes_reach_the_kernel() {
+        let ui = UiConfig { font_size: 20, line_height: 1.5, ..UiConfig::default() };
+        let engine = TtyEngine::new().with_ui_config(ui);
+
+        let derived = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        let winsize = kernel_window_size(&engine, derived);
+        assert_eq!((winsize.ws_row, winsize.ws_col), (24, 80));
+        assert_eq!((winsize.ws_xpixel, winsize.ws_ypixel), (80 * 12, 24 * 30));
+
+        let explicit = engine
+            .create_pty(PtyConfig {
+                cell_size: Some(CellSize { width: 9, height: 18 }),
+                ..single_byte_reader_config()
+            })
+            .await
+            .unwrap();
+        let winsize = kernel_window_size(&engine, explicit);
+        assert_eq!((winsize.ws_xpixel, winsize.ws_ypixel), (80 * 9, 24 * 18));
+
+        engine.resize_pty_pixels(explicit, 10, 20, CellSize { width: 5, height: 10 }).unwrap();
+        let winsize = kernel_window_size(&engine, explicit);
+        assert_eq!((winsize.ws_row, winsize.ws_col, winsize.ws_xpixel, winsize.ws_ypixel), (10, 20, 100, 100));
+
+        engine.destroy_pty(derived).await.unwrap();
+        engine.destroy_pty(explicit).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resize_bursts_are_debounced() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        let mut events = engine.subscribe_events();
+
+        for cols in 81..=90 {
+            engine.resize_pty(pty_id, 30, cols).unwrap();
+        }
+
+        let cell = CellSize::from_ui(&UiConfig::default());
+        let first = next_event(&mut events).await;
+        assert_eq!(first, SessionEvent::Resized { id: pty_id, size: WindowSize::new(30, 81, cell) });
+        let last = next_event(&mut events).await;
+        assert_eq!(last, SessionEvent::Resized { id: pty_id, size: WindowSize::new(30, 90, cell) });
+
+        tokio::time::sleep(RESIZE_DEBOUNCE * 2).await;
+        assert!(events.try_recv().is_err());
+        assert_eq!(kernel_window_size(&engine, pty_id).ws_col, 90);
+        assert_eq!(engine.get_window_size(pty_id).unwrap(), WindowSize::new(30, 90, cell));
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ui_config_change_updates_unpinned_sessions() {
+        let engine = TtyEngine::new();
+        let derived = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        let pinned = engine
+            .create_pty(PtyConfig {
+                cell_size: Some(CellSize { width: 9, height: 18 }),
+                ..single_byte_reader_config()
+            })
+            .await
+            .unwrap();
+        let resized = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        engine.resize_pty_pixels(resized, 24, 80, CellSize { width: 7, height: 15 }).unwrap();
+
+        engine.set_ui_config(UiConfig { font_size: 10, line_height: 2.0, ..UiConfig::default() });
+
+        assert_eq!(engine.get_window_size(derived).unwrap(), WindowSize::new(24, 80, CellSize { width: 6, height: 20 }));
+        assert_eq!(engine.get_window_size(pinned).unwrap(), WindowSize::new(24, 80, CellSize { width: 9, height: 18 }));
+        assert_eq!(engine.get_window_size(resized).unwrap(), WindowSize::new(24, 80, CellSize { width: 7, height: 15 }));
+
+        engine.destroy_pty(derived).await.unwrap();
+        engine.destroy_pty(pinned).await.unwrap();
+        engine.destroy_pty(resized).await.unwrap();
+    }
+
+    async fn wait_for_screen(engine: &TtyEngine, pty_id: u64, needle: &str) -> String {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let text = engine.screen_text(pty_id).unwrap();
+                if text.contains(needle) {
+                    return text;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{:?} never appeared on screen", needle))
+    }
+
+    #[tokio::test]
+    async fn test_resize_rewraps_screen() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { rows: 5, cols: 20, ..command_config("printf '%030d\\n' 7; exec sleep 5") };
+        let pty_id = engine.create_pty(config).await.unwrap();
+        let line = format!("{:030}", 7);
+
+        wait_for_screen(&engine, pty_id, "7").await;
+        assert_eq!(engine.screen_text(pty_id).unwrap(), format!("{}\n{}\n\n\n", &line[..20], &line[20..]));
+
+        engine.resize_pty(pty_id, 5, 40).unwrap();
+        assert_eq!(engine.screen_text(pty_id).unwrap(), format!("{}\n\n\n\n", line));
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_screen_measures_wide_output() {
+        let engine = TtyEngine::new();
+        let width = WidthConfig { grapheme_clusters: true, ..WidthConfig::default() };
+        let config = PtyConfig { rows: 5, cols: 20, width, ..command_config("printf '日本 👍🏽 ok\\n'; exec sleep 5") };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        wait_for_screen(&engine, pty_id, "ok").await;
+        let cells = engine.screen_cells(pty_id).unwrap();
+        let widths: Vec<u8> = cells[0][..10].iter().map(|cell| cell.width).collect();
+        assert_eq!(widths, [2, 0, 2, 0, 1, 2, 0, 1, 1, 1]);
+        assert_eq!(cells[0][5].text(), "👍🏽");
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queries_are_answered() {
+        let ui = UiConfig { theme: "light".to_string(), ..UiConfig::default() };
+        let engine = TtyEngine::new().with_ui_config(ui);
+        // Raw mode hands the replies to head without waiting for a newline
+        let script = "stty raw -echo; printf 'ab\\033[6n'; head -c 6 | tr '\\033' E; \
+                      printf '\\033]11;?\\007'; head -c 24 | tr '\\033\\007' EB; stty sane";
+        let pty_id = engine.create_pty(command_config(script)).await.unwrap();
+
+        let output = read_for(&engine, pty_id, Duration::from_secs(5)).await;
+        // The cursor was after "ab"
+        assert!(output.contains("E[1;3R"), "unexpected output: {:?}", output);
+        assert!(output.contains("E]11;rgb:ffff/ffff/ffffB"), "unexpected output: {:?}", output);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shell_integration_records_commands() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+        let prompt = |command: &str| format!("printf '\\033]133;A\\007$ \\033]133;B\\007{}\\r\\n\\033]133;C\\007'", command);
+        let script = format!(
+            "{}; ls /nonexistent-dir; printf '\\033]133;D;2\\007'; {}; echo fine; printf '\\033]133;D;0\\007'; exec sleep 5",
+            prompt("ls /nonexistent-dir"),
+            prompt("echo fine"),
+        );
+        let config = PtyConfig { working_dir: Some("/tmp".to_string()), ..command_config(&script) };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        let mut finished = Vec::new();
+        while finished.len() < 2 {
+            if let SessionEvent::CommandFinished { id, seq, exit_code } = next_event(&mut events).await {
+                assert_eq!(id, pty_id);
+                finished.push((seq, exit_code));
+            }
+        }
+        assert_eq!(finished, [(0, Some(2)), (1, Some(0))]);
+
+        let failed = engine.last_failed_command(pty_id).unwrap().unwrap();
+        assert_eq!(failed.command, "ls /nonexistent-dir");
+        assert!(failed.output.contains("nonexistent-dir"), "unexpected output: {:?}", failed.output);
+        assert_eq!(failed.cwd, std::fs::canonicalize("/tmp").ok());
+        assert_eq!(engine.last_command(pty_id).unwrap().unwrap().output, "fine");
+        assert_eq!(engine.command_records(pty_id).unwrap().len(), 2);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_title_and_cwd_reported_by_shell() {
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+        let script = "printf '\\033]7;file:///srv/My%%20Dir\\007\\033]0;build\\007\\033[22t\\033]2;vim\\007'; \
+                      sleep 0.2; printf '\\033[23t'; exec sleep 5";
+        let config = PtyConfig { track_screen: false, ..command_config(script) };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        let mut titles = Vec::new();
+        let mut cwd = None;
+        // Changes within one chunk of output are reported once
+        while !titles.ends_with(&["vim".to_string(), "build".to_string()]) || cwd.is_none() {
+            match next_event(&mut events).await {
+                SessionEvent::TitleChanged { title, .. } => titles.push(title),
+                SessionEvent::CwdChanged { cwd: path, .. } => cwd = Some(path),
+                _ => {}
+            }
+        }
+        assert_eq!(cwd, Some(PathBuf::from("/srv/My Dir")));
+        assert_eq!(engine.session_title(pty_id).unwrap(), "build");
+        assert_eq!(engine.session_cwd(pty_id).unwrap(), Some(PathBuf::from("/srv/My Dir")));
+
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub fn set_pty_mode(&self, pty_id: u64, mode: TerminalMode) -> Result<(), TtyError> {
-        let sessions = self.sessions.read().unwrap();
-        let session = sessions.get(&pty_id)
-            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
-        
-        session.set_mode(mode)
+    #[tokio::test]
+    async fn test_new_session_inherits_process_cwd() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { working_dir: Some("/tmp".to_string()), ..command_config("exec sleep 5") };
+        let parent = engine.create_pty(config).await.unwrap();
+        let tmp = std::fs::canonicalize("/tmp").unwrap();
+        // Without OSC 7 the directory comes from the foreground process
+        assert_eq!(engine.session_cwd(parent).unwrap(), Some(tmp.clone()));
+
+        let config = PtyConfig { inherit_cwd_from: Some(parent), ..command_config("pwd -P; exec sleep 5") };
+        let child = engine.create_pty(config).await.unwrap();
+        wait_for_screen(&engine, child, &tmp.to_string_lossy()).await;
+
+        let config = PtyConfig { inherit_cwd_from: Some(9999), ..command_config("true") };
+        assert!(matches!(engine.create_pty(config).await, Err(TtyError::PtyNotFound { id: 9999 })));
+
+        engine.destroy_pty(child).await.unwrap();
+        engine.destroy_pty(parent).await.unwrap();
     }
 
-    pub fn resize_pty(&self, pty_id: u64, rows: u16, cols: u16) -> Result<(), TtyError> {
-        let sessions = self.sessions.read().unwrap();
-        let session = sessions.get(&pty_id)
-            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
-        
-        session.resize(rows, cols)
+    #[tokio::test]
+    async fn test_process_cwd_follows_live_foreground_process() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig { working_dir: Some("/tmp".to_string()), ..plain_shell_config() };
+        let pty_id = engine.create_pty(config).await.unwrap();
+        wait_for_foreground(&engine, pty_id, "bash").await;
+
+        // The pipeline's leader exits at once; its other member is elsewhere
+        engine.write_to_pty(pty_id, b"true | (cd / && exec sleep 100)\n").await.unwrap();
+        wait_for_foreground(&engine, pty_id, "sleep").await;
+        assert_eq!(engine.session_cwd(pty_id).unwrap(), Some(PathBuf::from("/")));
+        engine.destroy_pty(pty_id).await.unwrap();
+
+        let mut events = engine.subscribe_events();
+        let pty_id = engine.create_pty(command_config("exit 0")).await.unwrap();
+        while !matches!(next_event(&mut events).await, SessionEvent::Exited { id, .. } if id == pty_id) {}
+        assert_eq!(engine.session_cwd(pty_id).unwrap(), None);
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub fn get_pty_stats(&self, pty_id: u64) -> Result<(u64, u64, Duration), TtyError> {
-        let sessions = self.sessions.read().unwrap();
-        let session = sessions.get(&pty_id)
-            .ok_or(TtyError::PtyNotFound { id: pty_id })?;
-        
-        Ok(session.get_stats())
+    fn read_cast(path: &Path) -> Vec<serde_json::Value> {
+        let text = std::fs::read_to_string(path).unwrap();
+        text.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
     }
 
-    pub fn list_sessions(&self) -> Vec<u64> {
-        self.sessions.read().unwrap().keys().copied().collect()
+    #[tokio::test]
+    async fn test_auto_record_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = TtyEngine::new().with_recordings_dir(dir.path());
+        let mut events = engine.subscribe_events();
+        let config = PtyConfig {
+            auto_record: Some(RecordingOptions { record_input: true }),
+            ..command_config("read line; echo \"got $line\"; exec sleep 5")
+        };
+        let pty_id = engine.create_pty(config).await.unwrap();
+        let path = engine.recording_path(pty_id).unwrap().unwrap();
+        assert!(path.starts_with(dir.path().join(pty_id.to_string())));
+
+        engine.write_to_pty(pty_id, b"hi\n").await.unwrap();
+        wait_for_screen(&engine, pty_id, "got hi").await;
+        engine.resize_pty(pty_id, 30, 100).unwrap();
+        while !matches!(next_event(&mut events).await, SessionEvent::Resized { .. }) {}
+        engine.destroy_pty(pty_id).await.unwrap();
+
+        let cast = read_cast(&path);
+        assert_eq!((cast[0]["width"].as_u64(), cast[0]["height"].as_u64()), (Some(80), Some(24)));
+        assert_eq!(cast[0]["env"]["SHELL"], "/bin/sh");
+        let events: Vec<(&str, &str)> =
+            cast[1..].iter().map(|event| (event[1].as_str().unwrap(), event[2].as_str().unwrap())).collect();
+        let output: String = events.iter().filter(|(code, _)| *code == "o").map(|(_, data)| *data).collect();
+        assert!(output.contains("got hi"), "unexpected output: {:?}", output);
+        assert!(events.contains(&("i", "hi\n")));
+        assert!(events.contains(&("r", "100x30")));
     }
 
-    pub fn get_session_count(&self) -> usize {
-        self.sessions.read().unwrap().len()
+    #[tokio::test]
+    async fn test_recording_started_and_stopped_at_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(command_config("read line; echo \"got $line\"; exec sleep 5")).await.unwrap();
+        assert_eq!(engine.recording_path(pty_id).unwrap(), None);
+
+        let path = dir.path().join("session.cast");
+        let options = RecordingOptions::default();
+        assert_eq!(engine.start_recording(pty_id, Some(path.clone()), options).unwrap(), path);
+        assert!(matches!(engine.start_recording(pty_id, None, options), Err(TtyError::AlreadyRecording { .. })));
+
+        engine.write_to_pty(pty_id, b"hi\n").await.unwrap();
+        wait_for_screen(&engine, pty_id, "got hi").await;
+        assert_eq!(engine.stop_recording(pty_id).unwrap(), Some(path.clone()));
+        assert_eq!(engine.stop_recording(pty_id).unwrap(), None);
+
+        // Input is left out unless asked for
+        let cast = read_cast(&path);
+        assert!(cast[1..].iter().all(|event| event[1] == "o"));
+        assert!(cast[1..].iter().any(|event| event[2].as_str().unwrap().contains("got hi")));
+
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub async fn destroy_pty(&self, pty_id: u64) -> Result<(), TtyError> {
-        let session = {
-            let mut sessions = self.sessions.write().unwrap();
-            sessions.remove(&pty_id)
-                .ok_or(TtyError::PtyNotFound { id: pty_id })?
-        };
+    #[tokio::test]
+    async fn test_recording_flushed_while_session_is_quiet() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(command_config("echo ready; exec sleep 5")).await.unwrap();
+        let path = dir.path().join("session.cast");
+        engine.start_recording(pty_id, Some(path.clone()), RecordingOptions::default()).unwrap();
+        engine.write_to_pty(pty_id, b"x").await.unwrap();
+        wait_for_screen(&engine, pty_id, "x").await;
 
-        // Send SIGTERM to child process
-        if let Err(e) = signal::kill(session.child_pid, Signal::SIGTERM) {
-            warn!("Failed to send SIGTERM to PTY {}: {}", pty_id, e);
-        }
+        // No further output arrives, yet the echo reaches the file
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let cast = read_cast(&path);
+        assert!(cast[1..].iter().any(|event| event[2].as_str().unwrap().contains('x')), "unexpected cast: {:?}", cast);
 
-        // Wait for process to exit gracefully
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    fn write_replay_cast(dir: &Path) -> PathBuf {
+        let path = dir.join("failure.cast");
+        let cast = concat!(
+            "{\"version\": 2, \"width\": 20, \"height\": 5}\n",
+            "[0.1, \"o\", \"$ make\\r\\n\"]\n",
+            "[0.2, \"r\", \"30x6\"]\n",
+            "[0.3, \"o\", \"error: oops\\r\\n\"]\n",
+        );
+        std::fs::write(&path, cast).unwrap();
+        path
+    }
 
-        // Force kill if still alive
-        match wait::waitpid(session.child_pid, Some(wait::WaitPidFlag::WNOHANG)) {
-            Ok(WaitStatus::StillAlive) => {
-                warn!("Force killing PTY {} process", pty_id);
-                let _ = signal::kill(session.child_pid, Signal::SIGKILL);
-                let _ = wait::waitpid(session.child_pid, None);
+    #[tokio::test]
+    async fn test_replay_plays_recording_like_a_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = TtyEngine::new();
+        let mut events = engine.subscribe_events();
+        let options = ReplayOptions { speed: ReplaySpeed::Max, ..ReplayOptions::default() };
+        let pty_id = engine.open_replay(write_replay_cast(dir.path()), options).await.unwrap();
+        let mut output = engine.subscribe_output(pty_id).unwrap();
+
+        loop {
+            if let SessionEvent::ReplayFinished { id } = next_event(&mut events).await {
+                assert_eq!(id, pty_id);
+                break;
             }
-            _ => {}
         }
+        let text = wait_for_screen(&engine, pty_id, "error: oops").await;
+        assert!(text.contains("$ make"));
+        let size = engine.get_window_size(pty_id).unwrap();
+        assert_eq!((size.cols, size.rows), (30, 6));
+        assert!(recv_until(&mut output, "error: oops").await.contains("$ make"));
 
-        session.mark_dead();
-        
-        let mut stats = self.stats.lock().unwrap();
-        stats.sessions_destroyed += 1;
-        
-        info!("PTY session {} destroyed", pty_id);
-        Ok(())
+        // Seeking back starts over from the recorded size
+        engine.seek_replay(pty_id, Duration::from_millis(150)).await.unwrap();
+        let text = engine.screen_text(pty_id).unwrap();
+        assert!(text.contains("$ make") && !text.contains("error"), "{:?}", text);
+        assert_eq!(engine.get_window_size(pty_id).unwrap().cols, 20);
+
+        // Input is accepted and goes nowhere; there's no process to signal
+        engine.write_to_pty(pty_id, b"ignored\r").await.unwrap();
+        assert!(engine.send_signal(Some(pty_id), Signal::SIGINT).await.is_err());
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub async fn shutdown(&self) -> Result<(), TtyError> {
-        info!("Shutting down TTY engine");
-        
-        self.shutdown.store(true, Ordering::Relaxed);
-        
-        // Get all session IDs
-        let session_ids: Vec<u64> = self.sessions.read().unwrap().keys().copied().collect();
-        
-        // Destroy all sessions
-        for id in session_ids {
-            if let Err(e) = self.destroy_pty(id).await {
-                error!("Failed to destroy PTY {}: {}", id, e);
-            }
-        }
-        
-        info!("TTY engine shutdown complete");
-        Ok(())
+    #[tokio::test]
+    async fn test_replay_pause_and_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = TtyEngine::new();
+        let options = ReplayOptions { start_paused: true, ..ReplayOptions::default() };
+        let pty_id = engine.open_replay(write_replay_cast(dir.path()), options).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let status = engine.replay_status(pty_id).unwrap();
+        assert!(status.paused && !status.finished);
+        assert_eq!((status.position, status.duration), (Duration::ZERO, Duration::from_millis(300)));
+        assert!(!engine.screen_text(pty_id).unwrap().contains("make"));
+
+        engine.seek_replay(pty_id, Duration::from_millis(100)).await.unwrap();
+        assert!(engine.screen_text(pty_id).unwrap().contains("$ make"));
+        assert!(engine.replay_status(pty_id).unwrap().paused);
+
+        engine.set_replay_speed(pty_id, ReplaySpeed::Scaled(2.0)).unwrap();
+        engine.resume_replay(pty_id).unwrap();
+        wait_for_screen(&engine, pty_id, "error: oops").await;
+        assert!(engine.replay_status(pty_id).unwrap().finished);
+
+        // Live sessions aren't replays
+        let live = engine.create_pty(command_config("exec sleep 5")).await.unwrap();
+        assert!(matches!(engine.pause_replay(live), Err(TtyError::NotAReplay { .. })));
+        assert!(matches!(
+            engine.open_replay(dir.path().join("missing.cast"), ReplayOptions::default()).await,
+            Err(TtyError::Io(_))
+        ));
+        engine.destroy_pty(live).await.unwrap();
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub fn get_stats(&self) -> TtyStats {
-        let stats = self.stats.lock().unwrap();
-        TtyStats {
-            sessions_created: stats.sessions_created,
-            sessions_destroyed: stats.sessions_destroyed,
-            total_bytes_read: stats.total_bytes_read,
-            total_bytes_written: stats.total_bytes_written,
-            signal_count: stats.signal_count,
-            errors: stats.errors,
-        }
+    #[tokio::test]
+    async fn test_queries_left_unanswered_when_disabled() {
+        let engine = TtyEngine::new();
+        let script = "stty raw -echo; printf '\\033[c'; \
+                      if timeout 0.5 head -c 1 >/dev/null; then echo answered; else echo unanswered; fi; stty sane";
+        let config = PtyConfig { answer_queries: false, ..command_config(script) };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        let output = read_for(&engine, pty_id, Duration::from_secs(5)).await;
+        assert!(output.contains("unanswered"), "unexpected output: {:?}", output);
+
+        engine.destroy_pty(pty_id).await.unwrap();
     }
 
-    pub fn subscribe_signals(&self) -> broadcast::Receiver<(Signal, Option<u64>)> {
-        self.signal_tx.subscribe()
+    #[tokio::test]
+    async fn test_screen_tracks_session_output() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            rows: 5,
+            cols: 20,
+            ..command_config("printf 'plain\\n\\033[1;32mgreen\\033[0m\\n'; exec sleep 5")
+        };
+        let pty_id = engine.create_pty(config).await.unwrap();
+
+        let text = wait_for_screen(&engine, pty_id, "green").await;
+        assert_eq!(text, "plain\ngreen\n\n\n");
+
+        let cells = engine.screen_cells(pty_id).unwrap();
+        assert_eq!(cells[1][0].fg, crate::screen::Color::Indexed(2));
+        assert!(cells[1][0].attrs.bold);
+        assert_eq!(engine.with_screen(pty_id, |screen| screen.cursor().row).unwrap(), 2);
+
+        engine.resize_pty(pty_id, 3, 10).unwrap();
+        assert_eq!(engine.with_screen(pty_id, |screen| (screen.rows(), screen.cols())).unwrap(), (3, 10));
+
+        engine.destroy_pty(pty_id).await.unwrap();
     }
-}
 
-impl Drop for TtyEngine {
-    fn drop(&mut self) {
-        // Ensure all sessions are cleaned up
-        let session_ids: Vec<u64> = self.sessions.read().unwrap().keys().copied().collect();
-        
-        for id in session_ids {
-            if let Some(session) = self.sessions.read().unwrap().get(&id) {
-                let _ = signal::kill(session.child_pid, Signal::SIGTERM);
-                session.mark_dead();
+    #[tokio::test]
+    async fn test_screen_can_be_attached_later() {
+        let engine = TtyEngine::new();
+        let config = PtyConfig {
+            track_screen: false,
+            ..single_byte_reader_config()
+        };
+        let pty_id = engine.create_pty(config).await.unwrap();
+        assert!(matches!(engine.screen_text(pty_id), Err(TtyError::ScreenNotAttached { id }) if id == pty_id));
+
+        engine.attach_screen(pty_id).unwrap();
+        engine.set_pty_mode(pty_id, TerminalMode::Raw).unwrap();
+        engine.write_to_pty(pty_id, b"x").await.unwrap();
+        wait_for_screen(&engine, pty_id, "GOT").await;
+        assert_eq!(engine.with_screen(pty_id, |screen| screen.rows()).unwrap(), 24);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    async fn next_lifecycle_event(events: &mut broadcast::Receiver<SessionEvent>) -> SessionEvent {
+        loop {
+            match next_event(events).await {
+                SessionEvent::ModesChanged { .. }
+                | SessionEvent::Resized { .. }
+                | SessionEvent::CommandFinished { .. }
+                | SessionEvent::TitleChanged { .. }
+                | SessionEvent::CwdChanged { .. } => continue,
+                event => return event,
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
-    use tokio::time::sleep;
+    async fn next_modes(events: &mut broadcast::Receiver<SessionEvent>) -> Modes {
+        loop {
+            if let SessionEvent::ModesChanged { modes, .. } = next_event(events).await {
+                return modes;
+            }
+        }
+    }
 
     #[tokio::test]
-    async fn test_pty_creation() {
+    async fn test_alt_screen_detected_from_output() {
         let engine = TtyEngine::new();
-        let config = PtyConfig::default();
-        
+        let mut events = engine.subscribe_events();
+        let config = PtyConfig {
+            track_screen: false,
+            ..command_config("printf '\\033[?1049h\\033[?2004h'; head -c 1 >/dev/null; printf '\\033[?1049l'; exec sleep 5")
+        };
         let pty_id = engine.create_pty(config).await.unwrap();
-        assert!(pty_id > 0);
-        
-        let sessions = engine.list_sessions();
-        assert!(sessions.contains(&pty_id));
-        
+
+        let modes = next_modes(&mut events).await;
+        assert!(modes.alt_screen && modes.bracketed_paste, "unexpected modes: {:?}", modes);
+        assert_eq!(engine.get_modes(pty_id).unwrap(), modes);
+        assert_eq!(engine.get_pty_mode(pty_id).unwrap(), TerminalMode::AltScreen);
+
+        engine.write_to_pty(pty_id, b"q\n").await.unwrap();
+        let modes = next_modes(&mut events).await;
+        assert!(!modes.alt_screen && modes.bracketed_paste, "unexpected modes: {:?}", modes);
+        assert_ne!(engine.get_pty_mode(pty_id).unwrap(), TerminalMode::AltScreen);
+
         engine.destroy_pty(pty_id).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_pty_io() {
+    async fn test_attached_screen_inherits_tracked_modes() {
         let engine = TtyEngine::new();
-        let config = PtyConfig::default();
-        
+        let mut events = engine.subscribe_events();
+        let config = PtyConfig {
+            track_screen: false,
+            ..command_config("printf '\\033[?1h'; exec sleep 5")
+        };
         let pty_id = engine.create_pty(config).await.unwrap();
-        
-        // Write to PTY
-        let test_data = b"echo hello\n";
-        let written = engine.write_to_pty(pty_id, test_data).await.unwrap();
-        assert_eq!(written, test_data.len());
-        
-        // Give some time for command to execute
-        sleep(Duration::from_millis(100)).await;
-        
-        // Read from PTY
-        let mut buffer = [0u8; 1024];
-        let read_bytes = engine.read_from_pty(pty_id, &mut buffer).await.unwrap();
-        assert!(read_bytes > 0);
-        
+        assert!(next_modes(&mut events).await.application_cursor);
+
+        engine.attach_screen(pty_id).unwrap();
+        assert!(engine.with_screen(pty_id, |screen| screen.modes().application_cursor).unwrap());
+        assert!(engine.get_modes(pty_id).unwrap().application_cursor);
+
         engine.destroy_pty(pty_id).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_pty_resize() {
+    async fn test_scrollback_keeps_scrolled_output() {
         let engine = TtyEngine::new();
-        let config = PtyConfig::default();
-        
+        let config = PtyConfig {
+            rows: 5,
+            cols: 20,
+            scrollback: ScrollbackLimits { max_lines: 20, ..ScrollbackLimits::default() },
+            ..command_config("seq 1 50; exec sleep 5")
+        };
         let pty_id = engine.create_pty(config).await.unwrap();
-        
-        // Test resize
-        engine.resize_pty(pty_id, 50, 120).unwrap();
-        
+        wait_for_screen(&engine, pty_id, "50").await;
+
+        // 50 lines plus the cursor row through a 5-row screen leave 46 scrolled
+        // off, of which the newest 20 are kept
+        assert_eq!(engine.scrollback_len(pty_id).unwrap(), 20);
+        assert_eq!(engine.scrollback_lines(pty_id, 0..2).unwrap(), ["27", "28"]);
+        assert_eq!(engine.recent_lines(pty_id, 3).unwrap(), ["49", "50", ""]);
+        assert!(engine.scrollback_memory(pty_id).unwrap() > 0);
+        assert_eq!(engine.total_scrollback_memory(), engine.scrollback_memory(pty_id).unwrap());
+
+        let agent = AgentConfig { context_lines: 30, ..AgentConfig::default() };
+        assert_eq!(engine.agent_context(pty_id, &agent).unwrap().len(), 25);
+
         engine.destroy_pty(pty_id).await.unwrap();
     }
 
@@ -670,7 +3871,88 @@ mod tests {
         engine.set_pty_mode(pty_id, TerminalMode::Raw).unwrap();
         engine.set_pty_mode(pty_id, TerminalMode::Cooked).unwrap();
         engine.set_pty_mode(pty_id, TerminalMode::AltScreen).unwrap();
-        
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    /// Interactive bash without startup files, so tests don't depend on the
+    /// speed of the user's rc scripts
+    fn plain_shell_config() -> PtyConfig {
+        PtyConfig {
+            shell: "/bin/bash".to_string(),
+            args: vec!["--norc".to_string(), "--noprofile".to_string()],
+            read_from_start: true,
+            ..PtyConfig::default()
+        }
+    }
+
+    fn single_byte_reader_config() -> PtyConfig {
+        command_config("head -c 1 >/dev/null; echo GOT; exec sleep 5")
+    }
+
+    /// Collects output for `duration`; reads are cancel-safe, so nothing is
+    /// lost when the deadline interrupts one.
+    async fn read_for(engine: &TtyEngine, pty_id: u64, duration: Duration) -> String {
+        let mut output = Vec::new();
+        let _ = tokio::time::timeout(duration, async {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let read_bytes = engine.read_from_pty(pty_id, &mut buffer).await.unwrap();
+                if read_bytes == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buffer[..read_bytes]);
+            }
+        })
+        .await;
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_mode_read_back_from_termios() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(single_byte_reader_config()).await.unwrap();
+
+        engine.set_pty_mode(pty_id, TerminalMode::Raw).unwrap();
+        assert_eq!(engine.get_pty_mode(pty_id).unwrap(), TerminalMode::Raw);
+
+        engine.set_pty_mode(pty_id, TerminalMode::Cooked).unwrap();
+        assert_eq!(engine.get_pty_mode(pty_id).unwrap(), TerminalMode::Cooked);
+
+        engine.set_pty_mode(pty_id, TerminalMode::AltScreen).unwrap();
+        assert_eq!(engine.get_pty_mode(pty_id).unwrap(), TerminalMode::AltScreen);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_raw_mode_delivers_single_bytes() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        engine.set_pty_mode(pty_id, TerminalMode::Raw).unwrap();
+
+        engine.write_to_pty(pty_id, b"x").await.unwrap();
+        let output = read_for(&engine, pty_id, Duration::from_millis(300)).await;
+        assert!(output.contains("GOT"), "raw read did not complete: {:?}", output);
+
+        engine.destroy_pty(pty_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cooked_mode_buffers_until_newline() {
+        let engine = TtyEngine::new();
+        let pty_id = engine.create_pty(single_byte_reader_config()).await.unwrap();
+        engine.set_pty_mode(pty_id, TerminalMode::Cooked).unwrap();
+
+        // Only the echo comes back while the line is incomplete
+        engine.write_to_pty(pty_id, b"x").await.unwrap();
+        let output = read_for(&engine, pty_id, Duration::from_millis(300)).await;
+        assert!(!output.contains("GOT"), "cooked read completed early: {:?}", output);
+
+        engine.write_to_pty(pty_id, b"\n").await.unwrap();
+        let output = read_for(&engine, pty_id, Duration::from_millis(300)).await;
+        assert!(output.contains("GOT"), "cooked read did not complete: {:?}", output);
+
         engine.destroy_pty(pty_id).await.unwrap();
     }
 
@@ -717,6 +3999,8 @@ mod tests {
         // Write some data
         let test_data = b"test data";
         engine.write_to_pty(pty_id, test_data).await.unwrap();
+        // The write alone can take under a millisecond
+        tokio::time::sleep(Duration::from_millis(5)).await;
         
         let (_bytes_read, bytes_written, uptime) = engine.get_pty_stats(pty_id).unwrap();
         assert_eq!(bytes_written, test_data.len() as u64);
@@ -763,6 +4047,36 @@ mod tests {
         engine.destroy_pty(pty_id).await.unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_spawns_exec_cleanly() {
+        let engine = Arc::new(TtyEngine::new());
+        let mut events = engine.subscribe_events();
+
+        let mut handles = Vec::new();
+        for _ in 0..32 {
+            let engine = Arc::clone(&engine);
+            handles.push(tokio::spawn(async move { engine.create_pty(command_config("exit 0")).await }));
+        }
+
+        let mut ids = Vec::new();
+        for handle in handles {
+            ids.push(handle.await.unwrap().unwrap());
+        }
+
+        let mut exited = 0;
+        while exited < ids.len() {
+            if let SessionEvent::Exited { code, .. } = next_event(&mut events).await {
+                assert_eq!(code, 0);
+                exited += 1;
+            }
+        }
+
+        for id in ids {
+            assert_eq!(engine.exit_status(id).unwrap(), Some(ExitStatus::Exited(0)));
+            engine.destroy_pty(id).await.unwrap();
+        }
+    }
+
     #[tokio::test]
     async fn test_concurrent_operations() {
         let engine = Arc::new(TtyEngine::new());
@@ -770,8 +4084,7 @@ mod tests {
         
         let mut handles = Vec::new();
         
-        // Create fewer concurrent operations to prevent resource exhaustion
-        for i in 0..3 { // Reduced from 10 to 3
+        for i in 0..10 {
             let engine_clone = Arc::clone(&engine);
             let config_clone = config.clone();
             
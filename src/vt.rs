@@ -0,0 +1,683 @@
+// VT/ANSI escape sequence parser following Paul Williams' DEC state machine
+// (https://vt100.net/emu/dec_ansi_parser), with UTF-8 decoding in the ground
+// state. 8-bit C1 controls are not recognised since 0x80-0x9F are UTF-8
+// continuation bytes.
+
+/// Maximum number of CSI/DCS parameters, counting subparameters
+pub const MAX_PARAMS: usize = 32;
+
+/// Maximum number of intermediate bytes kept for a sequence
+pub const MAX_INTERMEDIATES: usize = 2;
+
+/// OSC payloads longer than this are truncated
+pub const MAX_OSC_LEN: usize = 64 * 1024;
+
+const REPLACEMENT: char = '\u{FFFD}';
+
+/// Receives the actions recognised by `Parser::advance`. Every method has a
+/// no-op default so implementors only handle what they care about.
+pub trait Perform {
+    /// A printable character
+    fn print(&mut self, _c: char) {}
+
+    /// A C0 control such as BEL, BS, HT, LF or CR
+    fn execute(&mut self, _byte: u8) {}
+
+    /// A complete CSI sequence. `ignore` is set when parameters or
+    /// intermediates overflowed and the sequence should not be acted on.
+    fn csi_dispatch(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _action: char) {}
+
+    /// An escape sequence other than CSI, OSC, DCS and SOS/PM/APC
+    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {}
+
+    /// An OSC string split on ';'
+    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {}
+
+    /// Start of a DCS string; its data follows through `put`
+    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _action: char) {}
+
+    /// One byte of DCS data
+    fn put(&mut self, _byte: u8) {}
+
+    /// End of a DCS string
+    fn unhook(&mut self) {}
+}
+
+/// CSI/DCS parameters. Each parameter is a group of one or more values:
+/// `38:2:255:0:0` is a single parameter with five subparameters. Omitted
+/// values are 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    groups: Vec<Vec<u16>>,
+}
+
+impl Params {
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u16]> {
+        self.groups.iter().map(Vec::as_slice)
+    }
+
+    /// First value of parameter `index`
+    pub fn get(&self, index: usize) -> Option<u16> {
+        self.groups.get(index).and_then(|group| group.first().copied())
+    }
+
+    /// First value of parameter `index`, with missing or zero values
+    /// replaced by `default` as most control functions expect
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.get(index) {
+            Some(0) | None => default,
+            Some(value) => value,
+        }
+    }
+
+    fn value_count(&self) -> usize {
+        self.groups.iter().map(Vec::len).sum()
+    }
+}
+
+impl<const N: usize> From<[&[u16]; N]> for Params {
+    fn from(groups: [&[u16]; N]) -> Self {
+        Self { groups: groups.iter().map(|group| group.to_vec()).collect() }
+    }
+}
+
+/// Owned form of one `Perform` callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    Execute(u8),
+    Csi { params: Params, intermediates: Vec<u8>, ignore: bool, action: char },
+    Esc { intermediates: Vec<u8>, ignore: bool, byte: u8 },
+    Osc { params: Vec<Vec<u8>>, bell_terminated: bool },
+    Hook { params: Params, intermediates: Vec<u8>, ignore: bool, action: char },
+    Put(u8),
+    Unhook,
+}
+
+/// Collects every action in order
+impl Perform for Vec<Action> {
+    fn print(&mut self, c: char) {
+        self.push(Action::Print(c));
+    }
+
+    fn execute(&mut self, byte: u8) {
+        self.push(Action::Execute(byte));
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        self.push(Action::Csi { params: params.clone(), intermediates: intermediates.to_vec(), ignore, action });
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        self.push(Action::Esc { intermediates: intermediates.to_vec(), ignore, byte });
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        self.push(Action::Osc { params: params.iter().map(|param| param.to_vec()).collect(), bell_terminated });
+    }
+
+    fn hook(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        self.push(Action::Hook { params: params.clone(), intermediates: intermediates.to_vec(), ignore, action });
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.push(Action::Put(byte));
+    }
+
+    fn unhook(&mut self) {
+        self.push(Action::Unhook);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
+}
+
+/// Incremental parser. State, including partial UTF-8 sequences, carries
+/// over between `advance` calls, so output can be fed chunk by chunk.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    intermediates: Vec<u8>,
+    params: Params,
+    current: u16,
+    param_started: bool,
+    /// The last parameter group is still taking subparameters
+    open_group: bool,
+    ignore: bool,
+    osc: Vec<u8>,
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_needed: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            intermediates: Vec::with_capacity(MAX_INTERMEDIATES),
+            params: Params::default(),
+            current: 0,
+            param_started: false,
+            open_group: false,
+            ignore: false,
+            osc: Vec::new(),
+            utf8: [0; 4],
+            utf8_len: 0,
+            utf8_needed: 0,
+        }
+    }
+
+    /// Feeds `bytes` through the state machine, reporting actions to `performer`
+    pub fn advance<P: Perform>(&mut self, performer: &mut P, bytes: &[u8]) {
+        for &byte in bytes {
+            self.advance_byte(performer, byte);
+        }
+    }
+
+    /// Convenience wrapper collecting the actions for `bytes`
+    pub fn actions(&mut self, bytes: &[u8]) -> Vec<Action> {
+        let mut actions = Vec::new();
+        self.advance(&mut actions, bytes);
+        actions
+    }
+
+    /// True when no sequence or UTF-8 character is partially parsed
+    pub fn is_ground(&self) -> bool {
+        self.state == State::Ground && self.utf8_needed == 0
+    }
+
+    fn advance_byte<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        if self.utf8_needed > 0 {
+            if (0x80..=0xBF).contains(&byte) {
+                self.push_utf8(performer, byte);
+                return;
+            }
+            // Truncated character; the interrupting byte is processed normally
+            self.utf8_needed = 0;
+            self.utf8_len = 0;
+            performer.print(REPLACEMENT);
+        }
+
+        // Transitions from anywhere
+        match byte {
+            0x18 | 0x1A => {
+                self.leave_string(performer, false);
+                performer.execute(byte);
+                self.state = State::Ground;
+                return;
+            }
+            0x1B => {
+                self.leave_string(performer, true);
+                self.enter(State::Escape);
+                return;
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x7E => performer.print(byte as char),
+                0x7F => {}
+                _ => self.start_utf8(performer, byte),
+            },
+            State::Escape => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x2F => {
+                    self.collect(byte);
+                    self.state = State::EscapeIntermediate;
+                }
+                b'[' => self.enter(State::CsiEntry),
+                b']' => self.enter(State::OscString),
+                b'P' => self.enter(State::DcsEntry),
+                b'X' | b'^' | b'_' => self.state = State::SosPmApcString,
+                0x30..=0x7E => {
+                    performer.esc_dispatch(&self.intermediates, self.ignore, byte);
+                    self.state = State::Ground;
+                }
+                _ => {}
+            },
+            State::EscapeIntermediate => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x2F => self.collect(byte),
+                0x30..=0x7E => {
+                    performer.esc_dispatch(&self.intermediates, self.ignore, byte);
+                    self.state = State::Ground;
+                }
+                _ => {}
+            },
+            State::CsiEntry => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x2F => {
+                    self.collect(byte);
+                    self.state = State::CsiIntermediate;
+                }
+                0x30..=0x3B => {
+                    self.param(byte);
+                    self.state = State::CsiParam;
+                }
+                0x3C..=0x3F => {
+                    self.collect(byte);
+                    self.state = State::CsiParam;
+                }
+                0x40..=0x7E => self.csi_dispatch(performer, byte),
+                _ => {}
+            },
+            State::CsiParam => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x2F => {
+                    self.collect(byte);
+                    self.state = State::CsiIntermediate;
+                }
+                0x30..=0x3B => self.param(byte),
+                0x3C..=0x3F => self.state = State::CsiIgnore,
+                0x40..=0x7E => self.csi_dispatch(performer, byte),
+                _ => {}
+            },
+            State::CsiIntermediate => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x20..=0x2F => self.collect(byte),
+                0x30..=0x3F => self.state = State::CsiIgnore,
+                0x40..=0x7E => self.csi_dispatch(performer, byte),
+                _ => {}
+            },
+            State::CsiIgnore => match byte {
+                0x00..=0x1F => performer.execute(byte),
+                0x40..=0x7E => self.state = State::Ground,
+                _ => {}
+            },
+            State::DcsEntry => match byte {
+                0x20..=0x2F => {
+                    self.collect(byte);
+                    self.state = State::DcsIntermediate;
+                }
+                0x30..=0x3B => {
+                    self.param(byte);
+                    self.state = State::DcsParam;
+                }
+                0x3C..=0x3F => {
+                    self.collect(byte);
+                    self.state = State::DcsParam;
+                }
+                0x40..=0x7E => self.hook(performer, byte),
+                _ => {}
+            },
+            State::DcsParam => match byte {
+                0x20..=0x2F => {
+                    self.collect(byte);
+                    self.state = State::DcsIntermediate;
+                }
+                0x30..=0x3B => self.param(byte),
+                0x3C..=0x3F => self.state = State::DcsIgnore,
+                0x40..=0x7E => self.hook(performer, byte),
+                _ => {}
+            },
+            State::DcsIntermediate => match byte {
+                0x20..=0x2F => self.collect(byte),
+                0x30..=0x3F => self.state = State::DcsIgnore,
+                0x40..=0x7E => self.hook(performer, byte),
+                _ => {}
+            },
+            State::DcsPassthrough => match byte {
+                0x7F => {}
+                _ => performer.put(byte),
+            },
+            State::DcsIgnore | State::SosPmApcString => {}
+            State::OscString => match byte {
+                0x07 => {
+                    self.osc_dispatch(performer, true);
+                    self.state = State::Ground;
+                }
+                0x00..=0x1F => {}
+                _ => {
+                    if self.osc.len() < MAX_OSC_LEN {
+                        self.osc.push(byte);
+                    }
+                }
+            },
+        }
+    }
+
+    /// Clears per-sequence state on entering `state`
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.intermediates.clear();
+        self.params.groups.clear();
+        self.current = 0;
+        self.param_started = false;
+        self.open_group = false;
+        self.ignore = false;
+        self.osc.clear();
+    }
+
+    /// Finishes a DCS or OSC string interrupted by ESC (`terminated`, the
+    /// start of ST) or cancelled by CAN/SUB
+    fn leave_string<P: Perform>(&mut self, performer: &mut P, terminated: bool) {
+        match self.state {
+            State::DcsPassthrough => performer.unhook(),
+            State::OscString if terminated => self.osc_dispatch(performer, false),
+            _ => {}
+        }
+    }
+
+    fn collect(&mut self, byte: u8) {
+        if self.intermediates.len() < MAX_INTERMEDIATES {
+            self.intermediates.push(byte);
+        } else {
+            self.ignore = true;
+        }
+    }
+
+    fn param(&mut self, byte: u8) {
+        match byte {
+            b';' => {
+                self.finish_value(false);
+                self.param_started = true;
+            }
+            b':' => {
+                self.finish_value(true);
+                self.param_started = true;
+            }
+            _ => {
+                self.param_started = true;
+                self.current = self.current.saturating_mul(10).saturating_add((byte - b'0') as u16);
+            }
+        }
+    }
+
+    /// Ends the value being accumulated, starting a new parameter unless it
+    /// is followed by a subparameter
+    fn finish_value(&mut self, subparameter_follows: bool) {
+        if self.params.value_count() >= MAX_PARAMS {
+            self.ignore = true;
+        } else {
+            match self.params.groups.last_mut() {
+                Some(group) if self.open_group => group.push(self.current),
+                _ => self.params.groups.push(vec![self.current]),
+            }
+        }
+        self.open_group = subparameter_follows;
+        self.current = 0;
+    }
+
+    fn finish_params(&mut self) {
+        if self.param_started {
+            self.finish_value(false);
+            self.param_started = false;
+        }
+    }
+
+    fn csi_dispatch<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        self.finish_params();
+        performer.csi_dispatch(&self.params, &self.intermediates, self.ignore, byte as char);
+        self.state = State::Ground;
+    }
+
+    fn hook<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        self.finish_params();
+        performer.hook(&self.params, &self.intermediates, self.ignore, byte as char);
+        self.state = State::DcsPassthrough;
+    }
+
+    fn osc_dispatch<P: Perform>(&mut self, performer: &mut P, bell_terminated: bool) {
+        let params: Vec<&[u8]> = self.osc.split(|&b| b == b';').collect();
+        performer.osc_dispatch(&params, bell_terminated);
+        self.osc.clear();
+    }
+
+    fn start_utf8<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        let needed = match byte {
+            0xC2..=0xDF => 1,
+            0xE0..=0xEF => 2,
+            0xF0..=0xF4 => 3,
+            _ => {
+                performer.print(REPLACEMENT);
+                return;
+            }
+        };
+        self.utf8[0] = byte;
+        self.utf8_len = 1;
+        self.utf8_needed = needed;
+    }
+
+    fn push_utf8<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+        self.utf8[self.utf8_len] = byte;
+        self.utf8_len += 1;
+        self.utf8_needed -= 1;
+        if self.utf8_needed > 0 {
+            return;
+        }
+
+        // Rejects overlong forms and surrogates the lead byte alone can't rule out
+        let c = std::str::from_utf8(&self.utf8[..self.utf8_len])
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(REPLACEMENT);
+        self.utf8_len = 0;
+        performer.print(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Action> {
+        Parser::new().actions(bytes)
+    }
+
+    fn csi(params: Params, intermediates: &[u8], action: char) -> Action {
+        Action::Csi { params, intermediates: intermediates.to_vec(), ignore: false, action }
+    }
+
+    /// Deterministic xorshift64 so failures reproduce
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        /// Random bytes biased towards the interesting parts of the table
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            const PIECES: &[&[u8]] = &[
+                b"\x1b", b"\x1b[", b"\x1b]", b"\x1bP", b"\x1b\\", b"\x07", b"\x18", b";", b":", b"?", b"0", b"9",
+                b"m", b"H", "é".as_bytes(), "漢".as_bytes(), "🦀".as_bytes(), b"\xff", b"\x80", b"\xe2\x82",
+            ];
+            let mut out = Vec::with_capacity(len);
+            while out.len() < len {
+                if self.below(3) == 0 {
+                    out.extend_from_slice(PIECES[self.below(PIECES.len())]);
+                } else {
+                    out.push(self.next() as u8);
+                }
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn test_text_and_controls() {
+        assert_eq!(
+            parse(b"hi\r\n\x07\x7f"),
+            [Action::Print('h'), Action::Print('i'), Action::Execute(b'\r'), Action::Execute(b'\n'), Action::Execute(0x07)]
+        );
+    }
+
+    #[test]
+    fn test_csi_params_and_private_markers() {
+        assert_eq!(parse(b"\x1b[1;31m"), [csi(Params::from([&[1][..], &[31]]), b"", 'm')]);
+        assert_eq!(parse(b"\x1b[?1049h"), [csi(Params::from([&[1049][..]]), b"?", 'h')]);
+        assert_eq!(parse(b"\x1b[2 q"), [csi(Params::from([&[2][..]]), b" ", 'q')]);
+        assert_eq!(parse(b"\x1b[H"), [csi(Params::default(), b"", 'H')]);
+        assert_eq!(parse(b"\x1b[;5H"), [csi(Params::from([&[0][..], &[5]]), b"", 'H')]);
+    }
+
+    #[test]
+    fn test_csi_subparameters() {
+        let actions = parse(b"\x1b[38:2::255:128:0;1m");
+        let Action::Csi { params, .. } = &actions[0] else { panic!("expected CSI: {:?}", actions) };
+        assert_eq!(params.iter().collect::<Vec<_>>(), [&[38, 2, 0, 255, 128, 0][..], &[1]]);
+        assert_eq!(params.get(1), Some(1));
+        assert_eq!(params.get_or(2, 7), 7);
+    }
+
+    #[test]
+    fn test_param_overflow_sets_ignore() {
+        let mut bytes = b"\x1b[".to_vec();
+        for _ in 0..40 {
+            bytes.extend_from_slice(b"1;");
+        }
+        bytes.push(b'm');
+        let actions = parse(&bytes);
+        assert!(matches!(&actions[..], [Action::Csi { ignore: true, params, .. }] if params.len() == MAX_PARAMS));
+
+        assert!(matches!(&parse(b"\x1b[99999999m")[..], [Action::Csi { params, .. }] if params.get(0) == Some(u16::MAX)));
+    }
+
+    #[test]
+    fn test_osc_terminators() {
+        // Only the first ';' matters to most OSC handlers, but all are split
+        assert_eq!(
+            parse(b"\x1b]0;title; with semicolon\x07"),
+            [Action::Osc { params: vec![b"0".to_vec(), b"title".to_vec(), b" with semicolon".to_vec()], bell_terminated: true }]
+        );
+        assert_eq!(
+            parse(b"\x1b]2;x\x1b\\"),
+            [
+                Action::Osc { params: vec![b"2".to_vec(), b"x".to_vec()], bell_terminated: false },
+                Action::Esc { intermediates: vec![], ignore: false, byte: b'\\' },
+            ]
+        );
+        assert_eq!(
+            parse("\x1b]2;caf\u{e9}\x07".as_bytes()),
+            [Action::Osc { params: vec![b"2".to_vec(), "caf\u{e9}".as_bytes().to_vec()], bell_terminated: true }]
+        );
+    }
+
+    #[test]
+    fn test_dcs_passthrough() {
+        assert_eq!(
+            parse(b"\x1bP1$qm\x1b\\"),
+            [
+                Action::Hook { params: Params::from([&[1][..]]), intermediates: b"$".to_vec(), ignore: false, action: 'q' },
+                Action::Put(b'm'),
+                Action::Unhook,
+                Action::Esc { intermediates: vec![], ignore: false, byte: b'\\' },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape_dispatch_and_ignored_strings() {
+        assert_eq!(parse(b"\x1b(B"), [Action::Esc { intermediates: b"(".to_vec(), ignore: false, byte: b'B' }]);
+        assert_eq!(parse(b"\x1b7"), [Action::Esc { intermediates: vec![], ignore: false, byte: b'7' }]);
+        // SOS/PM/APC payloads are swallowed up to the ST
+        assert_eq!(
+            parse(b"\x1b_Gf=100;data\x1b\\ok"),
+            [Action::Esc { intermediates: vec![], ignore: false, byte: b'\\' }, Action::Print('o'), Action::Print('k')]
+        );
+    }
+
+    #[test]
+    fn test_cancel_aborts_sequence() {
+        assert_eq!(parse(b"\x1b[12\x18A"), [Action::Execute(0x18), Action::Print('A')]);
+        assert_eq!(parse(b"\x1b]0;never\x1aB"), [Action::Execute(0x1A), Action::Print('B')]);
+    }
+
+    #[test]
+    fn test_utf8_split_across_chunks() {
+        let text = "aé漢🦀";
+        let mut parser = Parser::new();
+        let mut actions = Vec::new();
+        for byte in text.as_bytes() {
+            parser.advance(&mut actions, std::slice::from_ref(byte));
+        }
+        assert!(parser.is_ground());
+        assert_eq!(actions, text.chars().map(Action::Print).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_replaced() {
+        assert_eq!(parse(b"\xffA"), [Action::Print(REPLACEMENT), Action::Print('A')]);
+        // Truncated by an escape sequence
+        assert_eq!(
+            parse(b"\xe2\x82\x1b[m"),
+            [Action::Print(REPLACEMENT), csi(Params::default(), b"", 'm')]
+        );
+        // Encoded surrogate
+        assert_eq!(parse(b"\xed\xa0\x80"), [Action::Print(REPLACEMENT)]);
+    }
+
+    #[test]
+    fn test_fuzz_random_input_does_not_panic() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut parser = Parser::new();
+        for _ in 0..2000 {
+            let len = rng.below(512);
+            let bytes = rng.bytes(len);
+            for action in parser.actions(&bytes) {
+                if let Action::Csi { params, intermediates, .. } | Action::Hook { params, intermediates, .. } = action {
+                    assert!(params.iter().map(<[u16]>::len).sum::<usize>() <= MAX_PARAMS);
+                    assert!(intermediates.len() <= MAX_INTERMEDIATES);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_chunking_does_not_change_actions() {
+        let mut rng = Rng(0xDEAD_BEEF_CAFE_F00D);
+        for _ in 0..500 {
+            let len = rng.below(1024);
+            let bytes = rng.bytes(len);
+            let whole = parse(&bytes);
+
+            let mut parser = Parser::new();
+            let mut chunked = Vec::new();
+            let mut rest = &bytes[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(1 + rng.below(rest.len().min(16)));
+                parser.advance(&mut chunked, chunk);
+                rest = tail;
+            }
+            assert_eq!(chunked, whole, "input: {:?}", bytes);
+        }
+    }
+}
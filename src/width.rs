@@ -0,0 +1,233 @@
+// Cell widths of characters and grapheme clusters in session output
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Unicode version whose width rules to follow. Programs measure text with
+/// their libc's wcwidth, so the grid has to agree with the version it was
+/// built from or cursor positions drift.
+///
+/// Pinning covers the emoji added since Unicode 8 and Unicode 9's switch of
+/// emoji to wide, which is where wcwidth implementations disagree in
+/// practice. Everything else follows the bundled tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum UnicodeVersion {
+    /// Emoji are narrow, as in glibc before 2.26
+    V8,
+    V9,
+    V10,
+    V11,
+    V12,
+    V13,
+    V14,
+    /// Also 15.1, which only added sequences
+    V15,
+    V16,
+    /// The bundled tables
+    #[default]
+    Latest,
+}
+
+/// How session output is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WidthConfig {
+    /// Measure grapheme clusters as a whole, so a ZWJ emoji sequence takes 2
+    /// cells instead of the sum wcwidth gives its parts. Applications can
+    /// also turn this on with private mode 2027.
+    pub grapheme_clusters: bool,
+    /// Emoji take 2 cells; off, they take 1 like older terminals
+    pub emoji_wide: bool,
+    pub unicode_version: UnicodeVersion,
+}
+
+impl Default for WidthConfig {
+    fn default() -> Self {
+        Self { grapheme_clusters: false, emoji_wide: true, unicode_version: UnicodeVersion::Latest }
+    }
+}
+
+impl WidthConfig {
+    /// Cells taken by `c` on its own: 0 for combining marks, joiners,
+    /// variation selectors and controls, otherwise 1 or 2
+    pub fn char_width(&self, c: char) -> usize {
+        match c.width().unwrap_or(0) {
+            2 if self.narrow_emoji(c) => 1,
+            width => width,
+        }
+    }
+
+    /// Cells taken by one grapheme cluster, at most 2
+    pub fn cluster_width(&self, cluster: &str) -> usize {
+        let Some(first) = cluster.chars().next() else {
+            return 0;
+        };
+        let width = cluster.width().min(2);
+        let emoji = is_emoji(first) || cluster.contains(VS16);
+        if width == 2 && emoji && (!self.emoji_wide || self.unicode_version < UnicodeVersion::V9 || newer_emoji(first, self.unicode_version)) {
+            1
+        } else {
+            width
+        }
+    }
+
+    /// Cells taken by `text`, measured the way the screen will lay it out
+    pub fn str_width(&self, text: &str) -> usize {
+        if self.grapheme_clusters {
+            text.graphemes(true).map(|cluster| self.cluster_width(cluster)).sum()
+        } else {
+            text.chars().map(|c| self.char_width(c)).sum()
+        }
+    }
+
+    fn narrow_emoji(&self, c: char) -> bool {
+        is_emoji(c) && (!self.emoji_wide || self.unicode_version < UnicodeVersion::V9 || newer_emoji(c, self.unicode_version))
+    }
+}
+
+/// VARIATION SELECTOR-16, requesting emoji presentation
+const VS16: char = '\u{FE0F}';
+
+/// Wide pictographs whose width depends on the emoji settings. The Enclosed
+/// Ideographic Supplement is wide as CJK and not included.
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x231A..=0x231B
+            | 0x23E9..=0x23FA
+            | 0x25FD..=0x25FE
+            | 0x2600..=0x27BF
+            | 0x2B1B..=0x2B1C
+            | 0x2B50
+            | 0x2B55
+            | 0x1F000..=0x1F1FF
+            | 0x1F300..=0x1FAFF
+    )
+}
+
+/// True if `c` is an emoji added after `version`. Programs built against an
+/// older version don't know it and count it as a single cell.
+fn newer_emoji(c: char, version: UnicodeVersion) -> bool {
+    let c = c as u32;
+    EMOJI_ADDITIONS
+        .iter()
+        .any(|&(added, start, end)| added > version && (start..=end).contains(&c))
+}
+
+/// Wide emoji by the Unicode version that added them
+#[rustfmt::skip]
+const EMOJI_ADDITIONS: &[(UnicodeVersion, u32, u32)] = {
+    use UnicodeVersion::*;
+    &[
+        (V9, 0x1F57A, 0x1F57A), (V9, 0x1F5A4, 0x1F5A4), (V9, 0x1F6D1, 0x1F6D2), (V9, 0x1F6F4, 0x1F6F6),
+        (V9, 0x1F919, 0x1F91E), (V9, 0x1F920, 0x1F927), (V9, 0x1F930, 0x1F930), (V9, 0x1F933, 0x1F93A),
+        (V9, 0x1F93C, 0x1F93E), (V9, 0x1F940, 0x1F945), (V9, 0x1F947, 0x1F94B), (V9, 0x1F950, 0x1F95E),
+        (V9, 0x1F985, 0x1F991),
+        (V10, 0x1F6F7, 0x1F6F8), (V10, 0x1F91F, 0x1F91F), (V10, 0x1F928, 0x1F92F), (V10, 0x1F931, 0x1F932),
+        (V10, 0x1F94C, 0x1F94C), (V10, 0x1F95F, 0x1F96B), (V10, 0x1F992, 0x1F997), (V10, 0x1F9D0, 0x1F9E6),
+        (V11, 0x1F6F9, 0x1F6F9), (V11, 0x1F94D, 0x1F94F), (V11, 0x1F96C, 0x1F970), (V11, 0x1F973, 0x1F976),
+        (V11, 0x1F97A, 0x1F97A), (V11, 0x1F97C, 0x1F97F), (V11, 0x1F998, 0x1F9A2), (V11, 0x1F9B0, 0x1F9B9),
+        (V11, 0x1F9C1, 0x1F9C2), (V11, 0x1F9E7, 0x1F9FF),
+        (V12, 0x1F6D5, 0x1F6D5), (V12, 0x1F6FA, 0x1F6FA), (V12, 0x1F7E0, 0x1F7EB), (V12, 0x1F90D, 0x1F90F),
+        (V12, 0x1F93F, 0x1F93F), (V12, 0x1F971, 0x1F971), (V12, 0x1F97B, 0x1F97B), (V12, 0x1F9A5, 0x1F9AA),
+        (V12, 0x1F9AE, 0x1F9AF), (V12, 0x1F9BA, 0x1F9BF), (V12, 0x1F9C3, 0x1F9CA), (V12, 0x1F9CD, 0x1F9CF),
+        (V12, 0x1FA70, 0x1FA73), (V12, 0x1FA78, 0x1FA7A), (V12, 0x1FA80, 0x1FA82), (V12, 0x1FA90, 0x1FA95),
+        (V13, 0x1F6D6, 0x1F6D7), (V13, 0x1F6FB, 0x1F6FC), (V13, 0x1F90C, 0x1F90C), (V13, 0x1F972, 0x1F972),
+        (V13, 0x1F977, 0x1F978), (V13, 0x1F9A3, 0x1F9A4), (V13, 0x1F9AB, 0x1F9AD), (V13, 0x1F9CB, 0x1F9CB),
+        (V13, 0x1FA74, 0x1FA74), (V13, 0x1FA83, 0x1FA86), (V13, 0x1FA96, 0x1FAA8), (V13, 0x1FAB0, 0x1FAB6),
+        (V13, 0x1FAC0, 0x1FAC2), (V13, 0x1FAD0, 0x1FAD6),
+        (V14, 0x1F6DD, 0x1F6DF), (V14, 0x1F7F0, 0x1F7F0), (V14, 0x1F979, 0x1F979), (V14, 0x1F9CC, 0x1F9CC),
+        (V14, 0x1FA7B, 0x1FA7C), (V14, 0x1FAA9, 0x1FAAC), (V14, 0x1FAB7, 0x1FABA), (V14, 0x1FAC3, 0x1FAC5),
+        (V14, 0x1FAD7, 0x1FAD9), (V14, 0x1FAE0, 0x1FAE7), (V14, 0x1FAF0, 0x1FAF6),
+        (V15, 0x1F6DC, 0x1F6DC), (V15, 0x1FA75, 0x1FA77), (V15, 0x1FA87, 0x1FA88), (V15, 0x1FAAD, 0x1FAAF),
+        (V15, 0x1FABB, 0x1FABD), (V15, 0x1FABF, 0x1FABF), (V15, 0x1FACE, 0x1FACF), (V15, 0x1FADA, 0x1FADB),
+        (V15, 0x1FAE8, 0x1FAE8), (V15, 0x1FAF7, 0x1FAF8),
+        (V16, 0x1FA89, 0x1FA89), (V16, 0x1FA8F, 0x1FA8F), (V16, 0x1FABE, 0x1FABE), (V16, 0x1FAC6, 0x1FAC6),
+        (V16, 0x1FADC, 0x1FADC), (V16, 0x1FADF, 0x1FADF), (V16, 0x1FAE9, 0x1FAE9),
+    ]
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTERS: WidthConfig =
+        WidthConfig { grapheme_clusters: true, emoji_wide: true, unicode_version: UnicodeVersion::Latest };
+
+    #[test]
+    fn test_east_asian_text() {
+        let config = WidthConfig::default();
+        assert_eq!(config.str_width("日本語のログ"), 12);
+        assert_eq!(config.str_width("한국어"), 6);
+        assert_eq!(config.str_width("ｶﾀｶﾅ"), 4);
+        assert_eq!(config.str_width("ＡＢＣ"), 6);
+        assert_eq!(config.str_width("error: 文件未找到"), 17);
+    }
+
+    #[test]
+    fn test_combining_marks_take_no_cells() {
+        let config = WidthConfig::default();
+        assert_eq!(config.str_width("cafe\u{301}"), 4);
+        assert_eq!(config.str_width("Z\u{351}\u{36b}\u{343}a\u{300}"), 2);
+        // Conjoining jamo and Devanagari signs attach to the preceding letter
+        assert_eq!(config.str_width("\u{1100}\u{1161}"), 2);
+        assert_eq!(config.str_width("नमस्ते"), 4);
+        assert_eq!(config.char_width('\u{200D}'), 0);
+        assert_eq!(config.char_width(VS16), 0);
+    }
+
+    #[test]
+    fn test_emoji_sequences_match_wcwidth_by_default() {
+        let config = WidthConfig::default();
+        assert_eq!(config.str_width("👍"), 2);
+        // wcwidth adds up the parts of joined and modified sequences
+        assert_eq!(config.str_width("👨\u{200D}👩\u{200D}👧"), 6);
+        assert_eq!(config.str_width("👍🏽"), 4);
+        assert_eq!(config.str_width("☀\u{FE0F}"), 1);
+        assert_eq!(config.str_width("🇯🇵"), 2);
+    }
+
+    #[test]
+    fn test_emoji_sequences_as_clusters() {
+        assert_eq!(CLUSTERS.str_width("👨\u{200D}👩\u{200D}👧"), 2);
+        assert_eq!(CLUSTERS.str_width("🏳\u{FE0F}\u{200D}🌈"), 2);
+        assert_eq!(CLUSTERS.str_width("👍🏽 ok"), 5);
+        assert_eq!(CLUSTERS.str_width("☀\u{FE0F}"), 2);
+        assert_eq!(CLUSTERS.str_width("1\u{FE0F}\u{20E3}"), 2);
+        assert_eq!(CLUSTERS.str_width("🇯🇵🇫🇷"), 4);
+        // Text presentation keeps the narrow default
+        assert_eq!(CLUSTERS.str_width("☀\u{FE0E}"), 1);
+    }
+
+    #[test]
+    fn test_narrow_emoji_switch() {
+        let narrow = WidthConfig { emoji_wide: false, ..WidthConfig::default() };
+        assert_eq!(narrow.str_width("🔥 build failed"), 14);
+        assert_eq!(narrow.str_width("漢字"), 4);
+        let narrow = WidthConfig { emoji_wide: false, ..CLUSTERS };
+        assert_eq!(narrow.str_width("👨\u{200D}👩\u{200D}👧"), 1);
+        assert_eq!(narrow.str_width("❤\u{FE0F}"), 1);
+    }
+
+    #[test]
+    fn test_unicode_version_pinning() {
+        let pinned = |unicode_version| WidthConfig { unicode_version, ..WidthConfig::default() };
+        // Hugging face (8), partying face (11), melting face (14), harp (16)
+        let emoji = ['🤗', '🥳', '🫠', '🪉'];
+        let widths = |config: WidthConfig| emoji.map(|c| config.char_width(c));
+        assert_eq!(widths(pinned(UnicodeVersion::Latest)), [2, 2, 2, 2]);
+        assert_eq!(widths(pinned(UnicodeVersion::V14)), [2, 2, 2, 1]);
+        assert_eq!(widths(pinned(UnicodeVersion::V11)), [2, 2, 1, 1]);
+        assert_eq!(widths(pinned(UnicodeVersion::V8)), [1, 1, 1, 1]);
+        // Only emoji change; CJK is wide in every version
+        assert_eq!(pinned(UnicodeVersion::V8).str_width("中文〈〉"), 8);
+    }
+
+    #[test]
+    fn test_emoji_additions_are_wide() {
+        for &(_, start, end) in EMOJI_ADDITIONS {
+            for c in (start..=end).filter_map(char::from_u32) {
+                assert_eq!(c.width(), Some(2), "U+{:X}", c as u32);
+                assert!(is_emoji(c));
+            }
+        }
+    }
+}